@@ -0,0 +1,148 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest payload of a frame, longer frames end the connection.
+pub const MAX_PAYLOAD: usize = 16 * 1024 * 1024;
+
+/// Kind, upload id and payload length, big-endian.
+const HEADER_SIZE: usize = 9;
+
+/// Frames of the framed ingest protocol. Uploads are told apart by their id, so several
+/// of them can be in progress on one connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Starts an upload, the payload is its target like the one of an HTTP PUT.
+    Open = 1,
+    /// Chunk of an open upload.
+    Data = 2,
+    /// Completes an upload.
+    Close = 3,
+    /// Deletes a key, the payload is the target like the one of an HTTP DELETE.
+    Delete = 4,
+    /// Sent by the server once an upload or delete is done, the payload is the HTTP
+    /// status code as `u16`.
+    Status = 5,
+}
+
+impl TryFrom<u8> for Kind {
+    type Error = io::Error;
+
+    fn try_from(kind: u8) -> Result<Self, Self::Error> {
+        match kind {
+            1 => Ok(Kind::Open),
+            2 => Ok(Kind::Data),
+            3 => Ok(Kind::Close),
+            4 => Ok(Kind::Delete),
+            5 => Ok(Kind::Status),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown frame kind {}", kind),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: Kind,
+    pub id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Returns the status code of a `Status` frame.
+    pub fn status(&self) -> Option<u16> {
+        match (self.kind, self.payload.as_slice()) {
+            (Kind::Status, [high, low]) => Some(u16::from_be_bytes([*high, *low])),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the next frame, `None` once the peer closed the connection between two frames.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Frame>> {
+    let mut header = [0; HEADER_SIZE];
+    if reader.read(&mut header[..1]).await? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut header[1..]).await?;
+
+    let kind = Kind::try_from(header[0])?;
+    let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    let length = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+    if length > MAX_PAYLOAD {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes, at most {} allowed", length, MAX_PAYLOAD),
+        ));
+    }
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(Frame { kind, id, payload }))
+}
+
+/// Writes a frame, the caller flushes the writer.
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    kind: Kind,
+    id: u32,
+    payload: &[u8],
+) -> io::Result<()> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "frame of {} bytes, at most {} allowed",
+                payload.len(),
+                MAX_PAYLOAD
+            ),
+        ));
+    }
+    let mut header = [0; HEADER_SIZE];
+    header[0] = kind as u8;
+    header[1..5].copy_from_slice(&id.to_be_bytes());
+    header[5..].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+    writer.write_all(&header).await?;
+    writer.write_all(payload).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, Kind::Open, 7, b"/s1/0/1.m4s")
+            .await
+            .unwrap();
+        write_frame(&mut buffer, Kind::Close, 7, &[]).await.unwrap();
+        write_frame(&mut buffer, Kind::Status, 7, &200u16.to_be_bytes())
+            .await
+            .unwrap();
+
+        let mut reader = buffer.as_slice();
+        let open = read_frame(&mut reader).await.unwrap().unwrap();
+        assert_eq!((open.kind, open.id), (Kind::Open, 7));
+        assert_eq!(open.payload, b"/s1/0/1.m4s");
+        let close = read_frame(&mut reader).await.unwrap().unwrap();
+        assert_eq!((close.kind, close.payload.len()), (Kind::Close, 0));
+        let status = read_frame(&mut reader).await.unwrap().unwrap();
+        assert_eq!(status.status(), Some(200));
+        assert!(read_frame(&mut reader).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_frames() {
+        let unknown = [9, 0, 0, 0, 1, 0, 0, 0, 0];
+        assert!(read_frame(&mut unknown.as_slice()).await.is_err());
+
+        let mut oversized = vec![Kind::Data as u8, 0, 0, 0, 1];
+        oversized.extend_from_slice(&(MAX_PAYLOAD as u32 + 1).to_be_bytes());
+        assert!(read_frame(&mut oversized.as_slice()).await.is_err());
+
+        // connection closed within a frame
+        let truncated = [Kind::Data as u8, 0, 0, 0, 1, 0, 0, 0, 4, 1, 2];
+        assert!(read_frame(&mut truncated.as_slice()).await.is_err());
+    }
+}
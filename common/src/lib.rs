@@ -1,4 +1,8 @@
 pub mod defaults;
+pub mod framing;
+pub mod listenfd;
+pub mod logging;
+pub mod otlp;
 pub mod runtime;
 pub mod socket;
 pub mod systemd;
@@ -0,0 +1,164 @@
+use crate::socket::{Address, SocketOptions};
+use socket2::Socket;
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::os::fd::{FromRawFd, RawFd};
+use std::process;
+use std::sync::{Mutex, OnceLock};
+use tracing::{info, warn};
+
+/// The first file descriptor passed by systemd or by the parent process on reload.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+pub const LISTEN_FDS: &str = "LISTEN_FDS";
+pub const LISTEN_PID: &str = "LISTEN_PID";
+pub const LISTEN_FDNAMES: &str = "LISTEN_FDNAMES";
+
+static INHERITED: OnceLock<Mutex<Vec<(String, Socket)>>> = OnceLock::new();
+static REGISTERED: OnceLock<Mutex<HashMap<String, Socket>>> = OnceLock::new();
+
+/// Returns a listening socket for the given name.
+///
+/// The socket is taken from the descriptors passed by systemd socket activation or by
+/// the parent process on reload, matched by name first and by the local address second.
+/// A new socket is bound only if nothing was inherited. Every returned socket is kept
+/// in the registry so it can be handed over to the next process on reload.
+pub fn listener(name: &str, addr: &Address, options: &SocketOptions) -> io::Result<Socket> {
+    register(name, addr, || crate::socket::listen_socket(addr, options))
+}
+
+/// Same as [`listener`], a new socket is bound with `SO_REUSEPORT` so several listeners
+/// can share the address.
+pub fn reuse_port_listener(
+    name: &str,
+    addr: &Address,
+    options: &SocketOptions,
+) -> io::Result<Socket> {
+    register(name, addr, || {
+        crate::socket::listen_reuse_socket(addr, options)
+    })
+}
+
+fn register<F>(name: &str, addr: &Address, bind: F) -> io::Result<Socket>
+where
+    F: FnOnce() -> io::Result<Socket>,
+{
+    let socket = match take_inherited(name, addr)? {
+        Some(socket) => {
+            info!("{}: using inherited listener for {}", name, addr);
+            socket
+        }
+        None => bind()?,
+    };
+
+    let registered = REGISTERED.get_or_init(|| Mutex::new(HashMap::new()));
+    registered
+        .lock()
+        .unwrap()
+        .insert(name.to_string(), socket.try_clone()?);
+
+    Ok(socket)
+}
+
+/// Removes the listener from the registry, the socket is closed once the caller drops
+/// its own as well.
+pub fn release(name: &str) {
+    if let Some(registered) = REGISTERED.get() {
+        registered.lock().unwrap().remove(name);
+    }
+}
+
+/// Returns duplicates of all registered listeners ordered by name.
+pub fn registered() -> io::Result<Vec<(String, Socket)>> {
+    let registered = match REGISTERED.get() {
+        Some(registered) => registered.lock().unwrap(),
+        None => return Ok(Vec::new()),
+    };
+
+    let mut sockets = Vec::with_capacity(registered.len());
+    for (name, socket) in registered.iter() {
+        sockets.push((name.clone(), socket.try_clone()?));
+    }
+    sockets.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Ok(sockets)
+}
+
+fn take_inherited(name: &str, addr: &Address) -> io::Result<Option<Socket>> {
+    let inherited = INHERITED.get_or_init(|| Mutex::new(inherited()));
+    let mut inherited = inherited.lock().unwrap();
+
+    let mut idx = inherited.iter().position(|(n, _)| n == name);
+    if idx.is_none() {
+        idx = inherited
+            .iter()
+            .position(|(_, socket)| match (socket.local_addr(), addr) {
+                (Ok(local), Address::Tcp(addr)) => local.as_socket() == Some(*addr),
+                (Ok(local), Address::Unix(path)) => local.as_pathname() == Some(path.as_path()),
+                (Err(_), _) => false,
+            });
+    }
+
+    match idx {
+        Some(idx) => {
+            let (_, socket) = inherited.remove(idx);
+            socket.set_nonblocking(true)?;
+            Ok(Some(socket))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Takes the descriptors passed by systemd or by the parent process on reload and
+/// removes the variables describing them from the environment, so child processes
+/// don't adopt them as well. Call it before other threads read the environment, the
+/// first listener does it otherwise.
+pub fn adopt() {
+    INHERITED.get_or_init(|| Mutex::new(inherited()));
+}
+
+/// Parses `LISTEN_FDS`, `LISTEN_PID` and `LISTEN_FDNAMES` as defined by `sd_listen_fds(3)`
+/// and unsets them.
+fn inherited() -> Vec<(String, Socket)> {
+    let count = env::var(LISTEN_FDS);
+    let pid = env::var(LISTEN_PID);
+    let names = env::var(LISTEN_FDNAMES).unwrap_or_default();
+    for name in [LISTEN_FDS, LISTEN_PID, LISTEN_FDNAMES] {
+        env::remove_var(name);
+    }
+
+    let count = match count {
+        Ok(count) => count,
+        Err(_) => return Vec::new(),
+    };
+    // the descriptors were passed to another process that left the variables behind
+    if pid.ok().and_then(|pid| pid.parse::<u32>().ok()) != Some(process::id()) {
+        warn!(
+            "ignoring {} without a {} of this process",
+            LISTEN_FDS, LISTEN_PID
+        );
+        return Vec::new();
+    }
+
+    let count = match count.parse::<RawFd>() {
+        Ok(count) => count,
+        Err(e) => {
+            warn!("invalid {}: {}", LISTEN_FDS, e);
+            return Vec::new();
+        }
+    };
+
+    let mut names = names.split(':');
+    let mut sockets = Vec::with_capacity(count as usize);
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        let name = names.next().unwrap_or("unknown").to_string();
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        if let Err(e) = socket.set_cloexec(true) {
+            warn!("inherited fd {}: set cloexec: {}", fd, e);
+        }
+        sockets.push((name, socket));
+    }
+
+    sockets
+}
@@ -0,0 +1,238 @@
+use crate::otlp::{self, OtlpLayer};
+use serde::Deserialize;
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{fmt, reload, Registry};
+
+/// Logging section of the config file shared by all binaries.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Log {
+    #[serde(default = "Log::default_level")]
+    pub level: String,
+    #[serde(default)]
+    pub format: Format,
+    /// Exports spans over OTLP/HTTP when set.
+    pub otlp: Option<Otlp>,
+}
+
+impl Log {
+    fn default_level() -> String {
+        "info".to_string()
+    }
+
+    pub fn level(&self) -> Result<LevelFilter, String> {
+        LevelFilter::from_str(&self.level)
+            .map_err(|e| format!("log.level: '{}': {}", self.level, e))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.level()?;
+        if let Some(otlp) = &self.otlp {
+            otlp::Endpoint::parse(&otlp.endpoint)
+                .map_err(|e| format!("log.otlp.endpoint: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Log {
+            level: Log::default_level(),
+            format: Format::default(),
+            otlp: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Otlp {
+    /// Collector address, e.g. `http://127.0.0.1:4318`. Spans are sent to `<endpoint>/v1/traces`.
+    pub endpoint: String,
+    /// Reported as `service.name`, the name of the binary when not set.
+    pub service_name: Option<String>,
+}
+
+/// Command line flags taking precedence over the logging section of the config file.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct LogArgs {
+    /// Log level: off, error, warn, info, debug or trace
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+
+    /// Log format
+    #[arg(long, global = true, value_enum)]
+    pub log_format: Option<Format>,
+
+    /// OTLP/HTTP collector to export spans to, e.g. `http://127.0.0.1:4318`
+    #[arg(long, global = true)]
+    pub otlp_endpoint: Option<String>,
+}
+
+impl LogArgs {
+    /// Returns the config with the flags given on the command line applied.
+    pub fn apply(&self, log: &Log) -> Log {
+        let mut log = log.clone();
+        if let Some(level) = &self.log_level {
+            log.level = level.clone();
+        }
+        if let Some(format) = self.log_format {
+            log.format = format;
+        }
+        if let Some(endpoint) = &self.otlp_endpoint {
+            match &mut log.otlp {
+                Some(otlp) => otlp.endpoint = endpoint.clone(),
+                None => {
+                    log.otlp = Some(Otlp {
+                        endpoint: endpoint.clone(),
+                        service_name: None,
+                    })
+                }
+            }
+        }
+        log
+    }
+}
+
+/// Handle to the installed logger.
+#[derive(Clone)]
+pub struct Logging {
+    level: reload::Handle<LevelFilter, Registry>,
+    exporter: Option<otlp::Exporter>,
+}
+
+impl Logging {
+    pub fn set_level(&self, level: LevelFilter) -> Result<(), String> {
+        self.level
+            .modify(|filter| *filter = level)
+            .map_err(|e| format!("log.level: {}", e))
+    }
+
+    /// Sends the finished spans that are still queued and waits until they are exported.
+    pub fn flush(&self) {
+        if let Some(exporter) = &self.exporter {
+            exporter.flush();
+        }
+    }
+}
+
+/// Installs the global logger of the binary `service`. An invalid level falls back to
+/// `info` and an invalid OTLP endpoint disables the export, both are reported once the
+/// logger is installed.
+pub fn init(service: &str, config: &Log) -> Logging {
+    install(service, config, false)
+}
+
+/// Same as [`init`] with the logs written to stderr, for binaries whose stdout carries
+/// the output of another program.
+pub fn init_stderr(service: &str, config: &Log) -> Logging {
+    install(service, config, true)
+}
+
+fn install(service: &str, config: &Log, stderr: bool) -> Logging {
+    let mut problems = Vec::new();
+    let level = config.level().unwrap_or_else(|e| {
+        problems.push(e);
+        LevelFilter::INFO
+    });
+    let (level, handle) = reload::Layer::new(level);
+
+    let (writer, ansi) = if stderr {
+        (BoxMakeWriter::new(io::stderr), io::stderr().is_terminal())
+    } else {
+        (BoxMakeWriter::new(io::stdout), io::stdout().is_terminal())
+    };
+    let (text, json) = match config.format {
+        Format::Text => (
+            Some(
+                fmt::layer()
+                    .with_writer(writer)
+                    .with_target(false)
+                    .with_ansi(ansi)
+                    .with_thread_names(false)
+                    .with_thread_ids(false)
+                    .with_line_number(false)
+                    .with_file(false),
+            ),
+            None,
+        ),
+        Format::Json => (
+            None,
+            Some(
+                fmt::layer()
+                    .json()
+                    .with_writer(writer)
+                    .with_target(false)
+                    .with_current_span(true)
+                    .with_span_list(false),
+            ),
+        ),
+    };
+
+    let mut exporter = None;
+    if let Some(otlp) = &config.otlp {
+        let service = otlp.service_name.as_deref().unwrap_or(service);
+        match otlp::Exporter::start(service, &otlp.endpoint) {
+            Ok(started) => exporter = Some(started),
+            Err(e) => problems.push(format!("log.otlp: {}", e)),
+        }
+    }
+    let spans = exporter.clone().map(OtlpLayer::new);
+
+    let subscriber = tracing_subscriber::registry()
+        .with(level)
+        .with(text)
+        .with(json)
+        .with(spans);
+    tracing::subscriber::set_global_default(subscriber)
+        .expect("Unable to set a global logger instance");
+
+    for problem in problems {
+        tracing::error!("{}", problem);
+    }
+    if let Some(otlp) = &config.otlp {
+        if exporter.is_some() {
+            tracing::info!("exporting spans to {}", otlp.endpoint);
+        }
+    }
+
+    Logging {
+        level: handle,
+        exporter,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn args_override_config() {
+        let config = Log {
+            level: "warn".to_string(),
+            format: Format::Text,
+            otlp: None,
+        };
+        let args = LogArgs {
+            log_level: Some("debug".to_string()),
+            log_format: None,
+            otlp_endpoint: Some("http://127.0.0.1:4318".to_string()),
+        };
+
+        let log = args.apply(&config);
+        assert_eq!(log.level().unwrap(), LevelFilter::DEBUG);
+        assert_eq!(log.format, Format::Text);
+        assert_eq!(log.otlp.unwrap().endpoint, "http://127.0.0.1:4318");
+    }
+}
@@ -0,0 +1,540 @@
+use serde_json::{json, Value as Json};
+use std::fmt::{self, Debug};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{warn, Dispatch, Span, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Registry;
+
+/// Finished spans waiting to be exported, further spans are dropped.
+const QUEUE_SIZE: usize = 8192;
+/// Most spans sent in one request.
+const BATCH_SIZE: usize = 512;
+/// Longest time a finished span waits for its batch to fill up.
+const BATCH_DELAY: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Span field holding a W3C `traceparent` of the caller, the span joins the caller's
+/// trace instead of starting a new one.
+pub const TRACEPARENT: &str = "traceparent";
+/// Span field holding a kind other than internal: `server` or `client`.
+pub const KIND: &str = "otel.kind";
+/// Span field marking the span as failed with the recorded message.
+pub const ERROR: &str = "error";
+
+/// Collector address of the form `http://host:port[/path]`, TLS is not supported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    authority: String,
+    path: String,
+}
+
+impl Endpoint {
+    pub fn parse(endpoint: &str) -> Result<Self, String> {
+        let rest = endpoint
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("'{}': only http:// endpoints are supported", endpoint))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        if authority.is_empty() {
+            return Err(format!("'{}': missing host", endpoint));
+        }
+
+        Ok(Endpoint {
+            authority: authority.to_string(),
+            path: format!("{}/v1/traces", path),
+        })
+    }
+}
+
+enum Message {
+    Span(SpanData),
+    Flush(mpsc::Sender<()>),
+}
+
+/// Sends finished spans to an OTLP/HTTP collector in JSON from a dedicated thread,
+/// so a slow collector never blocks the code being traced.
+#[derive(Clone)]
+pub struct Exporter {
+    sender: SyncSender<Message>,
+}
+
+impl Exporter {
+    pub fn start(service: &str, endpoint: &str) -> Result<Self, String> {
+        Exporter::with_delay(service, endpoint, BATCH_DELAY)
+    }
+
+    fn with_delay(service: &str, endpoint: &str, delay: Duration) -> Result<Self, String> {
+        let endpoint = Endpoint::parse(endpoint)?;
+        let service = service.to_string();
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        thread::Builder::new()
+            .name("otlp-exporter".to_string())
+            .spawn(move || export(receiver, endpoint, service, delay))
+            .map_err(|e| format!("start exporter: {}", e))?;
+        Ok(Exporter { sender })
+    }
+
+    /// Exports the queued spans, waits at most a few seconds for the collector.
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.sender.send(Message::Flush(done)).is_ok() {
+            let _ = wait.recv_timeout(TIMEOUT * 2);
+        }
+    }
+
+    fn send(&self, span: SpanData) {
+        let _ = self.sender.try_send(Message::Span(span));
+    }
+}
+
+fn export(receiver: Receiver<Message>, endpoint: Endpoint, service: String, delay: Duration) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    // the delay counts from the first span of the batch, not from the latest one
+    let mut deadline = None;
+    loop {
+        let mut done = None;
+        let wait = deadline.map_or(delay, |deadline: Instant| {
+            deadline.saturating_duration_since(Instant::now())
+        });
+        match receiver.recv_timeout(wait) {
+            Ok(Message::Span(span)) => {
+                batch.push(span);
+                let deadline = *deadline.get_or_insert_with(|| Instant::now() + delay);
+                if batch.len() < BATCH_SIZE && Instant::now() < deadline {
+                    continue;
+                }
+            }
+            Ok(Message::Flush(sender)) => done = Some(sender),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                if !batch.is_empty() {
+                    let _ = post(&endpoint, &encode(&service, &batch));
+                }
+                return;
+            }
+        }
+
+        if !batch.is_empty() {
+            if let Err(e) = post(&endpoint, &encode(&service, &batch)) {
+                warn!("otlp: export {} spans: {}", batch.len(), e);
+            }
+            batch.clear();
+        }
+        deadline = None;
+        if let Some(done) = done {
+            let _ = done.send(());
+        }
+    }
+}
+
+fn post(endpoint: &Endpoint, body: &[u8]) -> io::Result<()> {
+    let addr = endpoint
+        .authority
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        endpoint.path,
+        endpoint.authority,
+        body.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let status = String::from_utf8_lossy(&response)
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or_default()
+        .to_string();
+    if !status.starts_with('2') {
+        return Err(io::Error::other(format!(
+            "collector responded '{}'",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the W3C `traceparent` of the current span to pass to a downstream service,
+/// `None` when spans are not exported.
+pub fn traceparent() -> Option<String> {
+    Span::current()
+        .with_subscriber(|(id, dispatch)| current_traceparent(id, dispatch))
+        .flatten()
+}
+
+fn current_traceparent(id: &Id, dispatch: &Dispatch) -> Option<String> {
+    let registry = dispatch.downcast_ref::<Registry>()?;
+    let span = registry.span(id)?;
+    let extensions = span.extensions();
+    let data = extensions.get::<SpanData>()?;
+    Some(format!(
+        "00-{:032x}-{:016x}-01",
+        data.trace_id, data.span_id
+    ))
+}
+
+fn parse_traceparent(value: &str) -> Option<(u128, u64)> {
+    let mut parts = value.trim().split('-');
+    let (version, trace_id, span_id) = (parts.next()?, parts.next()?, parts.next()?);
+    if version.len() != 2 || trace_id.len() != 32 || span_id.len() != 16 {
+        return None;
+    }
+
+    let trace_id = u128::from_str_radix(trace_id, 16).ok()?;
+    let span_id = u64::from_str_radix(span_id, 16).ok()?;
+    if trace_id == 0 || span_id == 0 {
+        return None;
+    }
+    Some((trace_id, span_id))
+}
+
+/// Layer recording the spans of the subscriber and handing finished ones to the exporter.
+pub struct OtlpLayer {
+    exporter: Exporter,
+}
+
+impl OtlpLayer {
+    pub fn new(exporter: Exporter) -> Self {
+        OtlpLayer { exporter }
+    }
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+
+        let mut data = SpanData::new(attrs.metadata().name());
+        attrs.record(&mut data);
+
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<SpanData>()
+                .map(|p| (p.trace_id, p.span_id))
+        });
+        match parent.or(data.remote.take()) {
+            Some((trace_id, span_id)) => {
+                data.trace_id = trace_id;
+                data.parent = Some(span_id);
+            }
+            None => data.trace_id = rand::random::<u128>().max(1),
+        }
+
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(data);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            if let Some(mut data) = span.extensions_mut().remove::<SpanData>() {
+                data.end = SystemTime::now();
+                self.exporter.send(data);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+#[derive(Debug)]
+struct SpanData {
+    trace_id: u128,
+    span_id: u64,
+    parent: Option<u64>,
+    remote: Option<(u128, u64)>,
+    name: &'static str,
+    kind: u8,
+    error: Option<String>,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+}
+
+impl SpanData {
+    fn new(name: &'static str) -> Self {
+        let now = SystemTime::now();
+        SpanData {
+            trace_id: 0,
+            span_id: rand::random::<u64>().max(1),
+            parent: None,
+            remote: None,
+            name,
+            kind: 1,
+            error: None,
+            start: now,
+            end: now,
+            attributes: Vec::new(),
+        }
+    }
+
+    fn set(&mut self, field: &Field, value: Value) {
+        let text = || match &value {
+            Value::Str(text) => text.clone(),
+            value => format!("{:?}", value),
+        };
+        match field.name() {
+            TRACEPARENT => self.remote = parse_traceparent(&text()),
+            KIND => {
+                self.kind = match text().as_str() {
+                    "server" => 2,
+                    "client" => 3,
+                    _ => 1,
+                }
+            }
+            ERROR => self.error = Some(text()),
+            name => match self.attributes.iter_mut().find(|(key, _)| *key == name) {
+                Some((_, current)) => *current = value,
+                None => self.attributes.push((name, value)),
+            },
+        }
+    }
+}
+
+impl Visit for SpanData {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.set(field, Value::Float(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field, Value::Int(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field, Value::Int(value as i64));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field, Value::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, Value::Str(value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.set(field, Value::Str(format!("{:?}", value)));
+    }
+}
+
+struct Nanos(SystemTime);
+
+impl fmt::Display for Nanos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let nanos = self
+            .0
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        write!(f, "{}", nanos)
+    }
+}
+
+fn attribute(key: &str, value: &Value) -> Json {
+    let value = match value {
+        Value::Str(value) => json!({ "stringValue": value }),
+        Value::Int(value) => json!({ "intValue": value.to_string() }),
+        Value::Float(value) => json!({ "doubleValue": value }),
+        Value::Bool(value) => json!({ "boolValue": value }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+/// Encodes the spans as an OTLP `ExportTraceServiceRequest`.
+fn encode(service: &str, spans: &[SpanData]) -> Vec<u8> {
+    let spans: Vec<Json> = spans
+        .iter()
+        .map(|span| {
+            let mut encoded = json!({
+                "traceId": format!("{:032x}", span.trace_id),
+                "spanId": format!("{:016x}", span.span_id),
+                "name": span.name,
+                "kind": span.kind,
+                "startTimeUnixNano": Nanos(span.start).to_string(),
+                "endTimeUnixNano": Nanos(span.end).to_string(),
+                "attributes": span
+                    .attributes
+                    .iter()
+                    .map(|(key, value)| attribute(key, value))
+                    .collect::<Vec<_>>(),
+            });
+            if let Some(parent) = span.parent {
+                encoded["parentSpanId"] = json!(format!("{:016x}", parent));
+            }
+            if let Some(error) = &span.error {
+                encoded["status"] = json!({ "code": 2, "message": error });
+            }
+            encoded
+        })
+        .collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [attribute("service.name", &Value::Str(service.to_string()))],
+            },
+            "scopeSpans": [{
+                "scope": { "name": service },
+                "spans": spans,
+            }],
+        }],
+    })
+    .to_string()
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Collector stand-in accepting a single export request.
+    fn collector() -> (String, Receiver<(String, Json)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            sender
+                .send((request, serde_json::from_slice(&body).unwrap()))
+                .unwrap();
+        });
+        (format!("http://{}", addr), receiver)
+    }
+
+    #[test]
+    fn export_spans_to_collector() {
+        let (endpoint, requests) = collector();
+        let exporter = Exporter::start("server", &endpoint).unwrap();
+        let subscriber = tracing_subscriber::registry().with(OtlpLayer::new(exporter.clone()));
+
+        let caller = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let mut header = None;
+        tracing::subscriber::with_default(subscriber, || {
+            let ingest = info_span!("ingest", key = "/s1/1.m4s", traceparent = caller);
+            let _entered = ingest.enter();
+            info_span!("cache.get", bytes = 1024u64, error = "upload aborted").in_scope(|| {
+                header = traceparent();
+            });
+        });
+        exporter.flush();
+
+        let (request, body) = requests.recv_timeout(TIMEOUT).unwrap();
+        assert!(request.starts_with("POST /v1/traces "), "{}", request);
+
+        let resource = &body["resourceSpans"][0];
+        assert_eq!(
+            resource["resource"]["attributes"][0]["value"]["stringValue"],
+            "server"
+        );
+        let spans = resource["scopeSpans"][0]["spans"].as_array().unwrap();
+        assert_eq!(spans.len(), 2);
+        let (lookup, ingest) = (&spans[0], &spans[1]);
+
+        assert_eq!(ingest["name"], "ingest");
+        assert_eq!(ingest["traceId"], "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(ingest["parentSpanId"], "b7ad6b7169203331");
+        assert_eq!(ingest["attributes"][0]["key"], "key");
+
+        assert_eq!(lookup["name"], "cache.get");
+        assert_eq!(lookup["traceId"], ingest["traceId"]);
+        assert_eq!(lookup["parentSpanId"], ingest["spanId"]);
+        assert_eq!(lookup["attributes"][0]["value"]["intValue"], "1024");
+        assert_eq!(lookup["status"]["code"], 2);
+        assert_eq!(
+            header.unwrap(),
+            format!(
+                "00-0af7651916cd43dd8448eb211c80319c-{}-01",
+                lookup["spanId"].as_str().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn export_partial_batch_under_steady_traffic() {
+        let (endpoint, requests) = collector();
+        let delay = Duration::from_millis(200);
+        let exporter = Exporter::with_delay("server", &endpoint, delay).unwrap();
+        let subscriber = tracing_subscriber::registry().with(OtlpLayer::new(exporter));
+
+        // a span more often than the delay, the batch never fills up
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..12 {
+                info_span!("deliver").in_scope(|| thread::sleep(delay / 4));
+            }
+        });
+
+        let (_, body) = requests.try_recv().unwrap();
+        let spans = body["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        assert!(spans.len() < 12);
+    }
+
+    #[test]
+    fn parse_endpoint() {
+        let endpoint = Endpoint::parse("http://collector:4318/otlp/").unwrap();
+        assert_eq!(endpoint.authority, "collector:4318");
+        assert_eq!(endpoint.path, "/otlp/v1/traces");
+        assert!(Endpoint::parse("https://collector:4318").is_err());
+    }
+}
@@ -1,15 +1,140 @@
+use serde::Deserialize;
+use std::future::Future;
 use std::io;
+use std::mem;
+use std::thread;
 use tokio::runtime::Runtime;
-use tracing::info;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// A single work-stealing runtime shared by all listeners.
+    #[default]
+    MultiThread,
+    /// A current-thread runtime per core, each pinned to its core and running its own
+    /// listeners.
+    ThreadPerCore,
+}
 
 pub fn build(threads: Option<usize>) -> io::Result<Runtime> {
     let mut builder = tokio::runtime::Builder::new_multi_thread();
     builder.enable_all();
-    if threads.is_some() {
-        let threads = threads.unwrap();
+    if let Some(threads) = threads {
         info!("custom runtime threads: {}", threads);
         builder.worker_threads(threads);
     }
 
     builder.build()
 }
+
+pub fn build_current_thread() -> io::Result<Runtime> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+}
+
+/// Returns the cores to run on: the given ones, or the first `threads` of the cores the
+/// process may use, or all of them.
+pub fn cores(threads: Option<usize>, cores: &[usize]) -> io::Result<Vec<usize>> {
+    let allowed = allowed_cores()?;
+    if !cores.is_empty() {
+        if let Some(core) = cores.iter().find(|core| !allowed.contains(core)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("core {} is not available, available: {:?}", core, allowed),
+            ));
+        }
+        return Ok(cores.to_vec());
+    }
+
+    match threads {
+        Some(threads) if threads > allowed.len() => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} threads, only {} cores available",
+                threads,
+                allowed.len()
+            ),
+        )),
+        Some(threads) => Ok(allowed[..threads].to_vec()),
+        None => Ok(allowed),
+    }
+}
+
+fn allowed_cores() -> io::Result<Vec<usize>> {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    let result = unsafe { libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok((0..libc::CPU_SETSIZE as usize)
+        .filter(|core| unsafe { libc::CPU_ISSET(*core, &set) })
+        .collect())
+}
+
+/// Pins the calling thread to the core.
+pub fn pin(core: usize) -> io::Result<()> {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    unsafe { libc::CPU_SET(core, &mut set) };
+    let result = unsafe { libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Starts a thread per core, pinned to it, that runs the future made by `f` on its own
+/// current-thread runtime. `f` gets the index of the core in `cores`.
+pub fn spawn_per_core<F, Fut>(cores: &[usize], f: F) -> io::Result<Vec<thread::JoinHandle<()>>>
+where
+    F: Fn(usize) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ()>,
+{
+    let mut handles = Vec::with_capacity(cores.len());
+    for (i, core) in cores.iter().copied().enumerate() {
+        let f = f.clone();
+        let handle = thread::Builder::new()
+            .name(format!("core-{}", core))
+            .spawn(move || {
+                if let Err(e) = pin(core) {
+                    warn!("pin thread to core {}: {}", core, e);
+                }
+                match build_current_thread() {
+                    Ok(runtime) => runtime.block_on(f(i)),
+                    Err(e) => warn!("core {}: create runtime: {}", core, e),
+                }
+            })?;
+        handles.push(handle);
+    }
+
+    info!("thread per core runtime on cores {:?}", cores);
+    Ok(handles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn runs_pinned_runtime_per_core() {
+        let cores = cores(Some(1), &[]).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let handles = spawn_per_core(&cores, move |i| {
+            let sender = sender.clone();
+            async move {
+                tokio::task::yield_now().await;
+                sender.send((i, allowed_cores().unwrap())).unwrap();
+            }
+        })
+        .unwrap();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(receiver.recv().unwrap(), (0, cores));
+        assert!(super::cores(None, &[usize::MAX]).is_err());
+    }
+}
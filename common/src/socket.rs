@@ -1,20 +1,442 @@
-use socket2::{Domain, Socket, Type};
-use std::net::{AddrParseError, SocketAddr};
+use serde::Deserialize;
+use socket2::{Domain, SockAddr, SockRef, Socket, TcpKeepalive, Type};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tracing::warn;
 
-pub fn listen_reuse_socket(addr: &SocketAddr) -> Result<Socket, std::io::Error> {
-    let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
+const DEFAULT_BACKLOG: i32 = 128;
+
+/// Address to listen on: `host:port`, `[::]:port`, `:port` for all IPv4 interfaces or
+/// `unix:<path>` for a Unix-domain socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Options of a listening socket and the connections it accepts, `None` keeps the
+/// system default.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct SocketOptions {
+    /// Length of the queue of connections waiting to be accepted, 128 by default.
+    pub backlog: Option<i32>,
+    /// Accept only IPv6 connections on an IPv6 address, both IPv4 and IPv6 by default.
+    #[serde(default)]
+    pub ipv6_only: bool,
+    /// Disable Nagle's algorithm on accepted connections.
+    pub nodelay: Option<bool>,
+    /// Size of the kernel send buffer in bytes.
+    pub send_buffer: Option<usize>,
+    /// Size of the kernel receive buffer in bytes.
+    pub recv_buffer: Option<usize>,
+    /// Seconds a connection may be idle before keepalive probes are sent.
+    pub keepalive: Option<u64>,
+    /// Unsent bytes above which the socket stops being writable (`TCP_NOTSENT_LOWAT`),
+    /// keeps data queued in the application instead of the kernel.
+    pub notsent_lowat: Option<u32>,
+    /// Permissions of the file of a Unix-domain socket, e.g. `0o660`.
+    pub mode: Option<u32>,
+}
+
+impl SocketOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if matches!(self.backlog, Some(backlog) if backlog <= 0) {
+            return Err("backlog: must be greater than 0".to_string());
+        }
+        let sizes = [
+            ("send_buffer", self.send_buffer.map(|size| size as u64)),
+            ("recv_buffer", self.recv_buffer.map(|size| size as u64)),
+            ("keepalive", self.keepalive),
+        ];
+        for (name, size) in sizes {
+            if size == Some(0) {
+                return Err(format!("{}: must be greater than 0", name));
+            }
+        }
+        if matches!(self.mode, Some(mode) if mode > 0o777) {
+            return Err("mode: must be at most 0o777".to_string());
+        }
+        Ok(())
+    }
+
+    /// Applies the options of accepted connections, TCP-only options are skipped for
+    /// Unix-domain sockets.
+    pub fn apply(&self, socket: SockRef<'_>, tcp: bool) -> io::Result<()> {
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if !tcp {
+            return Ok(());
+        }
+
+        if let Some(nodelay) = self.nodelay {
+            socket.set_nodelay(nodelay)?;
+        }
+        if let Some(idle) = self.keepalive {
+            let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(idle));
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if let Some(lowat) = self.notsent_lowat {
+            set_notsent_lowat(&socket, lowat)?;
+        }
+        Ok(())
+    }
+}
+
+fn set_notsent_lowat(socket: &SockRef<'_>, lowat: u32) -> io::Result<()> {
+    let value = lowat as libc::c_int;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_NOTSENT_LOWAT,
+            &value as *const libc::c_int as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn listen_reuse_socket(addr: &Address, options: &SocketOptions) -> io::Result<Socket> {
+    bind(addr, options, true)
+}
+
+pub fn listen_socket(addr: &Address, options: &SocketOptions) -> io::Result<Socket> {
+    bind(addr, options, false)
+}
+
+fn bind(addr: &Address, options: &SocketOptions, reuse_port: bool) -> io::Result<Socket> {
+    let socket = match addr {
+        Address::Tcp(addr) => {
+            let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, None)?;
+            if addr.is_ipv6() {
+                socket.set_only_v6(options.ipv6_only)?;
+            }
+            if reuse_port {
+                socket.set_reuse_port(true)?;
+            }
+            socket.set_reuse_address(true)?;
+            socket
+        }
+        Address::Unix(path) => {
+            remove_stale_socket(path)?;
+            Socket::new(Domain::UNIX, Type::STREAM, None)?
+        }
+    };
     socket.set_nonblocking(true)?;
-    socket.set_reuse_port(true)?;
-    socket.set_reuse_address(true)?;
-    socket.bind(&(*addr).into())?;
-    socket.listen(128)?;
+    // accepted connections inherit the buffer sizes, the receive window depends on them
+    if let Some(size) = options.send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = options.recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+
+    match addr {
+        Address::Tcp(addr) => socket.bind(&(*addr).into())?,
+        Address::Unix(path) => {
+            socket.bind(&SockAddr::unix(path)?)?;
+            if let Some(mode) = options.mode {
+                fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+            }
+        }
+    }
+    socket.listen(options.backlog.unwrap_or(DEFAULT_BACKLOG))?;
     Ok(socket)
 }
 
-pub fn parse_address(mut addr: String) -> Result<SocketAddr, AddrParseError> {
+/// Removes the file of a Unix-domain socket left by a previous process, fails if a
+/// process is still listening on it.
+fn remove_stale_socket(path: &PathBuf) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("'{}' exists and is not a socket", path.display()),
+        ));
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("'{}' is in use", path.display()),
+        ));
+    }
+    fs::remove_file(path)
+}
+
+pub fn parse_address(addr: String) -> io::Result<Address> {
+    if let Some(path) = addr.strip_prefix("unix:") {
+        if path.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unix: missing socket path",
+            ));
+        }
+        return Ok(Address::Unix(PathBuf::from(path)));
+    }
+
+    let mut addr = addr;
     if addr.starts_with(':') {
         addr.insert_str(0, "0.0.0.0");
     }
-
     addr.parse()
+        .map(Address::Tcp)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("'{}': {}", addr, e)))
+}
+
+/// Listener accepting TCP or Unix-domain connections.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Wraps a listening socket, the kind is taken from the address it is bound to.
+    pub fn from_socket(socket: Socket) -> io::Result<Self> {
+        let tcp = socket.local_addr()?.as_socket().is_some();
+        let fd = OwnedFd::from(socket);
+        if tcp {
+            Ok(Listener::Tcp(TcpListener::from_std(fd.into())?))
+        } else {
+            Ok(Listener::Unix(UnixListener::from_std(fd.into())?))
+        }
+    }
+
+    /// Accepts a connection and applies the options to it. The peer address is `None`
+    /// for Unix-domain connections.
+    pub async fn accept(
+        &self,
+        options: &SocketOptions,
+    ) -> io::Result<(Stream, Option<SocketAddr>)> {
+        let (stream, remote) = match self {
+            Listener::Tcp(listener) => {
+                let (stream, remote) = listener.accept().await?;
+                if let Err(e) = options.apply(SockRef::from(&stream), true) {
+                    warn!("{}: set socket options: {}", remote, e);
+                }
+                (Stream::Tcp(stream), Some(remote))
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                if let Err(e) = options.apply(SockRef::from(&stream), false) {
+                    warn!("unix: set socket options: {}", e);
+                }
+                (Stream::Unix(stream), None)
+            }
+        };
+        Ok((stream, remote))
+    }
+}
+
+/// Connection accepted by a [`Listener`].
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Sends up to `len` bytes of the file from `offset` with `sendfile(2)`, so they are
+    /// not copied through user space. Returns the number of bytes sent, 0 at the end of
+    /// the file.
+    pub fn poll_sendfile(
+        &self,
+        cx: &mut Context<'_>,
+        file: &fs::File,
+        offset: u64,
+        len: usize,
+    ) -> Poll<io::Result<usize>> {
+        let sendfile = |socket: i32| {
+            let mut offset = offset as libc::off_t;
+            let sent = unsafe { libc::sendfile(socket, file.as_raw_fd(), &mut offset, len) };
+            if sent < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(sent as usize)
+        };
+        loop {
+            let result = match self {
+                Stream::Tcp(stream) => {
+                    ready!(stream.poll_write_ready(cx))?;
+                    stream.try_io(Interest::WRITABLE, || sendfile(stream.as_raw_fd()))
+                }
+                Stream::Unix(stream) => {
+                    ready!(stream.poll_write_ready(cx))?;
+                    stream.try_io(Interest::WRITABLE, || sendfile(stream.as_raw_fd()))
+                }
+            };
+            match result {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Stream::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Stream::Tcp(stream) => stream.is_write_vectored(),
+            Stream::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn parse_addresses() {
+        assert_eq!(
+            parse_address(":8080".to_string()).unwrap(),
+            Address::Tcp("0.0.0.0:8080".parse().unwrap())
+        );
+        assert_eq!(
+            parse_address("[::]:8080".to_string()).unwrap(),
+            Address::Tcp("[::]:8080".parse().unwrap())
+        );
+        assert_eq!(
+            parse_address("unix:/run/arp/ingest.sock".to_string()).unwrap(),
+            Address::Unix(PathBuf::from("/run/arp/ingest.sock"))
+        );
+        assert!(parse_address("unix:".to_string()).is_err());
+        assert!(parse_address("localhost".to_string()).is_err());
+    }
+
+    #[tokio::test]
+    async fn dual_stack_accepts_ipv4() {
+        let options = SocketOptions {
+            nodelay: Some(true),
+            keepalive: Some(30),
+            notsent_lowat: Some(16384),
+            ..Default::default()
+        };
+        let addr = parse_address("[::]:0".to_string()).unwrap();
+        let listener = Listener::from_socket(listen_socket(&addr, &options).unwrap()).unwrap();
+        let port = match &listener {
+            Listener::Tcp(listener) => listener.local_addr().unwrap().port(),
+            Listener::Unix(_) => unreachable!(),
+        };
+
+        let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let (mut stream, remote) = listener.accept(&options).await.unwrap();
+        assert!(remote.unwrap().ip().to_canonical().is_loopback());
+        if let Stream::Tcp(stream) = &stream {
+            assert!(stream.nodelay().unwrap());
+        }
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn unix_listener_replaces_stale_socket() {
+        let path = std::env::temp_dir().join(format!("socket-{}.sock", std::process::id()));
+        let addr = Address::Unix(path.clone());
+        let options = SocketOptions {
+            mode: Some(0o600),
+            ..Default::default()
+        };
+
+        // a socket file without a listener is left behind by a stopped process
+        drop(listen_socket(&addr, &options).unwrap());
+        let listener = Listener::from_socket(listen_socket(&addr, &options).unwrap()).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let (mut stream, remote) = listener.accept(&options).await.unwrap();
+        assert!(remote.is_none());
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // the socket of a running listener is not replaced
+        assert!(listen_socket(&addr, &options).is_err());
+        fs::remove_file(&path).unwrap();
+    }
 }
@@ -1,76 +1,232 @@
+use crate::listenfd::{self, LISTEN_FDNAMES, LISTEN_FDS, LISTEN_FDS_START, LISTEN_PID};
 use sd_notify::NotifyState;
-use std::process::{Command, Stdio};
-use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use socket2::Socket;
+use std::ffi::{CString, OsString};
+use std::fs::File;
+use std::io::{self, PipeWriter, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{env, process};
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::Notify;
-use tokio::time::sleep;
-use tracing::{error, info};
+use tokio::time::{interval, sleep, timeout};
+use tracing::{error, info, warn};
 
-pub fn run(notifier: Arc<Notify>) {
-    tokio::spawn(handle_signals(notifier.clone()));
+/// Descriptor the reloaded child writes to once it is ready to accept connections.
+pub const RELOAD_READY_FD: &str = "RELOAD_READY_FD";
+const WATCHDOG_USEC: &str = "WATCHDOG_USEC";
+const WATCHDOG_PID: &str = "WATCHDOG_PID";
+
+static READY: AtomicBool = AtomicBool::new(false);
+static HANDED_OVER: AtomicBool = AtomicBool::new(false);
+static WATCHDOG: OnceLock<Option<Duration>> = OnceLock::new();
+static HEARTBEATS: Mutex<Vec<Arc<Heartbeat>>> = Mutex::new(Vec::new());
+
+/// Grace periods of a reload and a shutdown.
+#[derive(Debug, Clone, Copy)]
+pub struct GracePeriods {
+    /// Time the reloaded process gets to report that it is ready.
+    pub reload: Duration,
+    /// Time the connections get to finish once the process stops accepting.
+    pub shutdown: Duration,
+}
+
+/// Handles the signals and, if systemd asks for it, sends watchdog keepalives.
+/// Readiness is reported by [`Readiness`] once the listeners accept connections.
+pub fn run(notifier: Arc<Notify>, grace: GracePeriods) {
+    tokio::spawn(handle_signals(notifier, grace));
+    if let Some(period) = watchdog_interval() {
+        info!("watchdog: keepalive every {:?}", period / 2);
+        tokio::spawn(watchdog(period));
+    }
+}
+
+/// Reports the service ready once the given number of listeners accept connections.
+#[derive(Debug, Clone)]
+pub struct Readiness {
+    pending: Arc<AtomicUsize>,
+}
+
+impl Readiness {
+    pub fn new(listeners: usize) -> Self {
+        if listeners == 0 {
+            ready();
+        }
+        Readiness {
+            pending: Arc::new(AtomicUsize::new(listeners)),
+        }
+    }
+
+    /// Marks one listener as accepting connections.
+    pub fn listening(&self) {
+        if self.pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+            ready();
+        }
+    }
+}
+
+/// Returns true once a reload handed the listeners over to a new process.
+pub fn handed_over() -> bool {
+    HANDED_OVER.load(Ordering::Acquire)
+}
+
+/// Publishes a human-readable status line, shown by `systemctl status`.
+pub fn status(line: &str) {
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Status(line)]) {
+        error!("notify status: {}", e);
+    }
+}
+
+struct Heartbeat {
+    name: String,
+    last: Mutex<Instant>,
+}
+
+/// Keeps a heartbeat of the current runtime, the watchdog stops the keepalives once a
+/// runtime misses its beats. Does nothing without a watchdog.
+pub fn heartbeat(name: impl Into<String>) {
+    let period = match watchdog_interval() {
+        Some(period) => period / 4,
+        None => return,
+    };
+
+    let heartbeat = Arc::new(Heartbeat {
+        name: name.into(),
+        last: Mutex::new(Instant::now()),
+    });
+    HEARTBEATS.lock().unwrap().push(Arc::clone(&heartbeat));
     tokio::spawn(async move {
-        // @todo make it configurable
-        sleep(Duration::from_secs(1)).await;
-        let r = sd_notify::notify(false, &[NotifyState::Ready]);
-        if let Err(e) = r {
-            error!("notify ready: {}", e);
+        let mut ticker = interval(period);
+        loop {
+            ticker.tick().await;
+            *heartbeat.last.lock().unwrap() = Instant::now();
         }
     });
 }
 
-async fn handle_signals(notifier: Arc<Notify>) {
+/// Returns the watchdog timeout set by systemd.
+fn watchdog_interval() -> Option<Duration> {
+    *WATCHDOG.get_or_init(|| {
+        let usec = env::var(WATCHDOG_USEC).ok()?.parse::<u64>().ok()?;
+        // a reloaded child inherits the timeout without the pid of the parent, see spawn
+        match env::var(WATCHDOG_PID) {
+            Ok(pid) if pid.parse::<u32>().ok() != Some(process::id()) => None,
+            _ => Some(Duration::from_micros(usec)),
+        }
+    })
+}
+
+/// Sends a keepalive every half of the timeout while the runtimes are healthy: this task
+/// keeps running and every runtime with a heartbeat has beaten within that time.
+async fn watchdog(period: Duration) {
+    let mut ticker = interval(period / 2);
+    loop {
+        ticker.tick().await;
+        if let Some(name) = stalled(period / 2) {
+            warn!("watchdog: runtime {} is stalled, skip keepalive", name);
+            continue;
+        }
+
+        if let Err(e) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+            error!("notify watchdog: {}", e);
+        }
+    }
+}
+
+fn stalled(max: Duration) -> Option<String> {
+    HEARTBEATS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|heartbeat| heartbeat.last.lock().unwrap().elapsed() > max)
+        .map(|heartbeat| heartbeat.name.clone())
+}
+
+/// Notifies systemd and, if the process was started by a reload, the parent process
+/// that the service is ready. Only the first call has an effect.
+pub fn ready() {
+    if READY.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    let r = sd_notify::notify(false, &[NotifyState::Ready]);
+    if let Err(e) = r {
+        error!("notify ready: {}", e);
+    }
+
+    let fd = match env::var(RELOAD_READY_FD) {
+        Ok(fd) => fd,
+        Err(_) => return,
+    };
+
+    match fd.parse::<RawFd>() {
+        Ok(fd) => {
+            let mut pipe = unsafe { File::from_raw_fd(fd) };
+            if let Err(e) = pipe.write_all(&[1]) {
+                error!("notify parent ready: {}", e);
+            }
+        }
+        Err(e) => error!("invalid {}: {}", RELOAD_READY_FD, e),
+    }
+}
+
+async fn handle_signals(notifier: Arc<Notify>, grace: GracePeriods) {
     let mut interrupt = signal(SignalKind::interrupt()).unwrap();
     let mut terminate = signal(SignalKind::terminate()).unwrap();
     let mut quit = signal(SignalKind::quit()).unwrap();
     let mut hup = signal(SignalKind::hangup()).unwrap();
 
     let mut sigint = false;
-    let mut sighup = false;
-    tokio::select! {
-        _ = interrupt.recv() => {
-            info!("received interrupt signal");
-            sigint = true;
-        },
-        _ = hup.recv() => {
-            info!("received hup signal");
-            sighup = true;
-        },
-        _ = terminate.recv() => {
-            info!("received terminate signal");
-        },
-        _ = quit.recv() => {
-            info!("received quit signal");
-        },
-    }
-
-    if sighup {
-        let pid = fork();
-        if let Err(e) = pid {
-            info!("fork: {}", e);
-            process::exit(1);
-        }
-
-        let pid = pid.unwrap();
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("system time before Unix epoch");
-        let r = sd_notify::notify(
-            false,
-            &[
-                NotifyState::Reloading,
-                NotifyState::MonotonicUsec(now.as_micros() as i128),
-                NotifyState::MainPid(pid),
-            ],
-        );
-        if let Err(e) = r {
-            error!("notify reloading: {}", e);
-        }
-
-        exit_after(Duration::from_secs(30)).await;
-    } else {
-        let _ = sd_notify::notify(true, &[NotifyState::Stopping]);
+    loop {
+        let mut sighup = false;
+        tokio::select! {
+            _ = interrupt.recv() => {
+                info!("received interrupt signal");
+                sigint = true;
+            },
+            _ = hup.recv() => {
+                info!("received hup signal");
+                sighup = true;
+            },
+            _ = terminate.recv() => {
+                info!("received terminate signal");
+            },
+            _ = quit.recv() => {
+                info!("received quit signal");
+            },
+        }
+
+        if !sighup {
+            let _ = sd_notify::notify(true, &[NotifyState::Stopping]);
+            break;
+        }
+
+        match reload(grace.reload).await {
+            Ok(()) => {
+                // the child accepts on the same sockets, stop accepting and drain connections
+                info!("reload: child is ready, stop accepting connections");
+                HANDED_OVER.store(true, Ordering::Release);
+                tokio::spawn(exit_after(grace.shutdown));
+                break;
+            }
+            Err(e) => {
+                error!("reload: {}", e);
+                let r = sd_notify::notify(
+                    false,
+                    &[NotifyState::Ready, NotifyState::MainPid(process::id())],
+                );
+                if let Err(e) = r {
+                    error!("notify ready: {}", e);
+                }
+            }
+        }
     }
 
     notifier.notify_waiters();
@@ -84,14 +240,234 @@ async fn exit_after(duration: Duration) {
     process::exit(0);
 }
 
-fn fork() -> Result<u32, Box<dyn std::error::Error>> {
+/// Starts a new instance of the binary, hands over all registered listeners and waits
+/// until the child reports that it is ready.
+async fn reload(wait: Duration) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let listeners = listenfd::registered()?;
+    let (mut reader, writer) = io::pipe()?;
+    let mut child = spawn(&listeners, writer)?;
+
+    let pid = child.id();
+    info!(
+        "reload: started child {} with {} listeners",
+        pid,
+        listeners.len()
+    );
+    drop(listeners);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before Unix epoch");
+    let r = sd_notify::notify(
+        false,
+        &[
+            NotifyState::Reloading,
+            NotifyState::MonotonicUsec(now.as_micros() as i128),
+            NotifyState::MainPid(pid),
+        ],
+    );
+    if let Err(e) = r {
+        error!("notify reloading: {}", e);
+    }
+
+    // the read returns zero bytes if the child exits without reporting readiness
+    let mut read = tokio::task::spawn_blocking(move || {
+        let mut buf = [0u8; 1];
+        reader.read(&mut buf)
+    });
+
+    let result = match timeout(wait, &mut read).await {
+        Ok(Ok(Ok(1))) => return Ok(()),
+        Ok(Ok(Ok(_))) => format!("child {} exited before ready", pid),
+        Ok(Ok(Err(e))) => format!("wait for child {}: {}", pid, e),
+        Ok(Err(e)) => format!("wait for child {}: {}", pid, e),
+        Err(_) => format!("child {} is not ready after {:?}", pid, wait),
+    };
+
+    let _ = child.kill();
+    tokio::task::spawn_blocking(move || child.wait());
+    Err(result.into())
+}
+
+fn spawn(listeners: &[(String, Socket)], ready: PipeWriter) -> io::Result<Child> {
     let exe = env::current_exe()?;
-    let child = Command::new(exe)
-        .args(env::args().skip(1))
-        .stdin(Stdio::null())
+    let names = listeners
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<&str>>()
+        .join(":");
+
+    let mut fds = listeners
+        .iter()
+        .map(|(_, socket)| socket.as_raw_fd())
+        .collect::<Vec<RawFd>>();
+    fds.push(ready.as_raw_fd());
+    let ready_fd = LISTEN_FDS_START + listeners.len() as RawFd;
+
+    let replaced = [
+        LISTEN_FDS,
+        LISTEN_FDNAMES,
+        LISTEN_PID,
+        RELOAD_READY_FD,
+        WATCHDOG_PID,
+    ];
+    let mut vars = env::vars_os()
+        .filter(|(name, _)| !replaced.iter().any(|replaced| name == replaced))
+        .collect::<Vec<(OsString, OsString)>>();
+    vars.push((LISTEN_FDS.into(), listeners.len().to_string().into()));
+    vars.push((LISTEN_FDNAMES.into(), names.into()));
+    vars.push((RELOAD_READY_FD.into(), ready_fd.to_string().into()));
+    let mut exec = Exec::new(&exe, env::args_os().skip(1), vars)?;
+
+    let mut cmd = Command::new(exe);
+    cmd.stdin(Stdio::null())
         .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()?;
+        .stderr(Stdio::inherit());
+    unsafe {
+        cmd.pre_exec(move || {
+            handover_fds(&mut fds)?;
+            Err(exec.exec())
+        });
+    }
+
+    cmd.spawn()
+}
+
+/// Program executed from `pre_exec` with `LISTEN_PID` set to the pid of the child, which
+/// is only known after the fork. Everything is allocated before, the child only writes
+/// the digits of its pid.
+struct Exec {
+    program: CString,
+    _strings: Vec<CString>,
+    argv: Vec<*const libc::c_char>,
+    envp: Vec<*const libc::c_char>,
+    _pid: Vec<u8>,
+    /// Start of the value in the `LISTEN_PID` entry of `envp`.
+    pid: *mut u8,
+}
+
+// the pointers point into the buffers owned by the struct
+unsafe impl Send for Exec {}
+unsafe impl Sync for Exec {}
+
+impl Exec {
+    fn new(
+        program: &Path,
+        args: impl Iterator<Item = OsString>,
+        vars: Vec<(OsString, OsString)>,
+    ) -> io::Result<Exec> {
+        let program = c_string(program.as_os_str().as_bytes().to_vec())?;
+        let args = std::iter::once(Ok(program.clone()))
+            .chain(args.map(|arg| c_string(arg.into_vec())))
+            .collect::<io::Result<Vec<CString>>>()?;
+        let vars = vars
+            .into_iter()
+            .map(|(name, value)| {
+                let mut var = name.into_vec();
+                var.push(b'=');
+                var.extend_from_slice(value.as_bytes());
+                c_string(var)
+            })
+            .collect::<io::Result<Vec<CString>>>()?;
+
+        // room for the digits of any pid and the terminating zero
+        let mut pid = format!("{}=", LISTEN_PID).into_bytes();
+        let start = pid.len();
+        pid.resize(start + 11, 0);
+        let pid_ptr = unsafe { pid.as_mut_ptr().add(start) };
+
+        let mut argv = args.iter().map(|arg| arg.as_ptr()).collect::<Vec<_>>();
+        argv.push(ptr::null());
+        let mut envp = vars.iter().map(|var| var.as_ptr()).collect::<Vec<_>>();
+        envp.push(pid.as_ptr() as *const libc::c_char);
+        envp.push(ptr::null());
 
-    Ok(child.id())
+        Ok(Exec {
+            program,
+            _strings: args.into_iter().chain(vars).collect(),
+            argv,
+            envp,
+            _pid: pid,
+            pid: pid_ptr,
+        })
+    }
+
+    /// Replaces the process, returns only on failure. Runs between fork and exec.
+    fn exec(&mut self) -> io::Error {
+        let mut pid = unsafe { libc::getpid() } as u32;
+        let mut digits = [0u8; 10];
+        let mut len = 0;
+        loop {
+            digits[len] = b'0' + (pid % 10) as u8;
+            pid /= 10;
+            len += 1;
+            if pid == 0 {
+                break;
+            }
+        }
+        for (i, digit) in digits[..len].iter().rev().enumerate() {
+            unsafe { *self.pid.add(i) = *digit };
+        }
+
+        unsafe {
+            libc::execve(
+                self.program.as_ptr(),
+                self.argv.as_ptr(),
+                self.envp.as_ptr(),
+            )
+        };
+        io::Error::last_os_error()
+    }
+}
+
+fn c_string(bytes: Vec<u8>) -> io::Result<CString> {
+    CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Places the descriptors at `LISTEN_FDS_START..` without the close-on-exec flag.
+/// Runs between fork and exec, so it must not allocate.
+fn handover_fds(fds: &mut [RawFd]) -> io::Result<()> {
+    let above = LISTEN_FDS_START + fds.len() as RawFd;
+    for fd in fds.iter_mut() {
+        // move out of the target range first so dup2 does not clobber another descriptor
+        let moved = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, above) };
+        if moved < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        *fd = moved;
+    }
+
+    for (i, fd) in fds.iter().enumerate() {
+        let target = LISTEN_FDS_START + i as RawFd;
+        if unsafe { libc::dup2(*fd, target) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_once_all_listeners_accept() {
+        let readiness = Readiness::new(2);
+        readiness.clone().listening();
+        assert!(!READY.load(Ordering::Acquire));
+        readiness.listening();
+        assert!(READY.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn exec_with_pid_of_child() {
+        let vars = vec![("PATH".into(), env::var_os("PATH").unwrap_or_default())];
+        let args = ["-c", "test \"$LISTEN_PID\" = \"$$\""].map(OsString::from);
+        let mut exec = Exec::new(Path::new("/bin/sh"), args.into_iter(), vars).unwrap();
+        let mut cmd = Command::new("/bin/sh");
+        unsafe {
+            cmd.pre_exec(move || Err(exec.exec()));
+        }
+        assert!(cmd.status().unwrap().success());
+    }
 }
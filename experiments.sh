@@ -16,7 +16,7 @@ cpu_command() {
 
   local ld_preload='LD_PRELOAD=/usr/lib/x86_64-linux-gnu/libprofiler.so'
   local executable='./target/release/server'
-  local config_arg='-c ./configs/server.toml'
+  local config_arg="-c ${CONFIG:-./configs/server.toml}"
 
   local cpu_profile="CPUPROFILE=$cpu_profile_path"
   local base_command="env $ld_preload $cpu_profile $executable $config_arg"
@@ -41,7 +41,7 @@ heap_command() {
 
   local ld_preload='LD_PRELOAD=/usr/lib/x86_64-linux-gnu/libtcmalloc_and_profiler.so'
   local executable='./target/release/server'
-  local config_arg='-c ./configs/server.toml'
+  local config_arg="-c ${CONFIG:-./configs/server.toml}"
 
   local heap_profile="HEAPPROFILE=$heap_profile_path"
   local base_command="env $ld_preload $heap_profile $executable $config_arg"
@@ -87,4 +87,22 @@ do
             bash ./run_experiment.sh "$heap_cmd" "task replayer" "$dir"
         done
     done
-done
\ No newline at end of file
+done
+
+exp='runtime'
+for mode in "multi_thread" "thread_per_core"
+do
+    # same number of threads for both modes: one per available core
+    config="$base_path/$exp/server-$mode.toml"
+    mkdir -p "$base_path/$exp"
+    sed "s/^threads = .*/mode = \"$mode\"/" ./configs/server.toml > "$config"
+    for (( i=0; i<10; i++ ))
+    do
+        dir="$base_path/$exp/$mode/iter$i/cpu"
+        cpu_cmd=$(CONFIG="$config" cpu_command "$dir/server.cpu.prof" "list:copy")
+        bash ./run_experiment.sh "$cpu_cmd" "task replayer" "$dir"
+        dir="$base_path/$exp/$mode/iter$i/heap"
+        heap_cmd=$(CONFIG="$config" heap_command "$dir/server.heap" "list:copy")
+        bash ./run_experiment.sh "$heap_cmd" "task replayer" "$dir"
+    done
+done
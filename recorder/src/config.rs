@@ -8,6 +8,8 @@ pub struct Settings {
     pub storage: Storage,
     #[serde(default)]
     pub stream: StreamSettings,
+    #[serde(default)]
+    pub log: common::logging::Log,
 }
 
 /// HTTP server configuration
@@ -15,6 +17,8 @@ pub struct Settings {
 pub struct HttpServer {
     #[serde(default = "HttpServer::default_addr")]
     pub addr: String,
+    #[serde(default)]
+    pub socket: common::socket::SocketOptions,
 }
 
 impl HttpServer {
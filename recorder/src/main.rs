@@ -9,11 +9,12 @@ use crate::error::RecorderError;
 use crate::service::CMAFUploader;
 use crate::storage::FileStorage;
 use clap::Parser as ClapParser;
+use common::logging::LogArgs;
+use common::socket::Listener;
 use hyper::server::conn::http1;
 use hyper_util::rt::TokioIo;
 use std::sync::Arc;
 use std::{fs, process};
-use tokio::net::TcpListener;
 use tracing::{debug, error, info};
 
 #[derive(ClapParser, Debug)]
@@ -21,15 +22,21 @@ use tracing::{debug, error, info};
 struct Cli {
     #[arg(short, long, default_value = "recorder.toml")]
     config: String,
+
+    #[command(flatten)]
+    log: LogArgs,
 }
 
 #[tokio::main]
 async fn main() {
-    // Initialize tracing
-    tracing_subscriber::fmt::init();
-
     let cli = Cli::parse();
-    let settings = match build_settings(cli.config.as_str()) {
+    let settings = build_settings(cli.config.as_str());
+    let log_config = match &settings {
+        Ok(settings) => cli.log.apply(&settings.log),
+        Err(_) => cli.log.apply(&Default::default()),
+    };
+    let log = common::logging::init("recorder", &log_config);
+    let settings = match settings {
         Ok(settings) => settings,
         Err(e) => {
             error!("Failed to load configuration: {}", e);
@@ -39,19 +46,25 @@ async fn main() {
 
     if let Err(e) = run(&settings).await {
         error!("Server error: {}", e);
+        log.flush();
         process::exit(1);
     }
+    log.flush();
 }
 
 pub async fn run(settings: &Settings) -> Result<(), RecorderError> {
     let addr = common::socket::parse_address(settings.http.addr.clone())
         .map_err(|e| RecorderError::NetworkError(e.to_string()))?;
-    let socket = common::socket::listen_reuse_socket(&addr)
-        .map_err(|e| RecorderError::NetworkError(e.to_string()))?;
-    let listener = TcpListener::from_std(socket.into())
+    let options = &settings.http.socket;
+    options
+        .validate()
+        .map_err(|e| RecorderError::ConfigError(format!("http.socket.{}", e)))?;
+    let socket = common::socket::listen_reuse_socket(&addr, options)
         .map_err(|e| RecorderError::NetworkError(e.to_string()))?;
+    let listener =
+        Listener::from_socket(socket).map_err(|e| RecorderError::NetworkError(e.to_string()))?;
 
-    info!("Listening on http://{}", addr);
+    info!("Listening on {}", addr);
 
     // Create the file storage system
     let storage = Arc::new(FileStorage::new(settings.storage.path.clone()));
@@ -61,16 +74,16 @@ pub async fn run(settings: &Settings) -> Result<(), RecorderError> {
     let http = http1::Builder::new();
 
     loop {
-        let (tcp_stream, remote_addr) = listener
-            .accept()
+        let (stream, remote_addr) = listener
+            .accept(options)
             .await
             .map_err(|e| RecorderError::NetworkError(e.to_string()))?;
 
         let service = service.clone();
-        let io = TokioIo::new(tcp_stream);
+        let io = TokioIo::new(stream);
         let conn = http.serve_connection(io, service);
 
-        debug!("Connection accepted from {}", remote_addr);
+        debug!("Connection accepted from {:?}", remote_addr);
         tokio::spawn(async move {
             if let Err(e) = conn.await {
                 error!("Connection error: {:?}", e);
@@ -7,12 +7,17 @@ pub struct Settings {
     pub target: Target,
     pub storage: Storage,
     pub schedule: Schedule,
+    #[serde(default)]
+    pub log: common::logging::Log,
 }
 
 /// Target configuration
 #[derive(Debug, Deserialize)]
 pub struct Target {
     pub url: String,
+    /// Address of the framed ingest listener, `host:port` or `unix:<path>`. When set
+    /// the files are uploaded over the framed protocol, `url` still names their targets.
+    pub framed: Option<String>,
 }
 
 /// Storage configuration
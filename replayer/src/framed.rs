@@ -0,0 +1,78 @@
+use crate::error::ReplayerError;
+use common::framing::{self, Kind};
+use common::socket::Stream;
+use tokio::io::{AsyncWriteExt, BufStream};
+use tokio::net::{TcpStream, UnixStream};
+
+/// Client of the framed ingest protocol of the server, uploading one file at a time.
+pub struct FramedClient {
+    stream: BufStream<Stream>,
+    next_id: u32,
+}
+
+impl FramedClient {
+    /// Connects to `host:port` or `unix:<path>`.
+    pub async fn connect(addr: &str) -> Result<Self, ReplayerError> {
+        let stream = match addr.strip_prefix("unix:") {
+            Some(path) => UnixStream::connect(path).await.map(Stream::Unix),
+            None => TcpStream::connect(addr).await.map(|stream| {
+                let _ = stream.set_nodelay(true);
+                Stream::Tcp(stream)
+            }),
+        }
+        .map_err(|e| ReplayerError::NetworkError(format!("connect to {}: {}", addr, e)))?;
+
+        Ok(FramedClient {
+            stream: BufStream::new(stream),
+            next_id: 0,
+        })
+    }
+
+    pub async fn open(&mut self, target: &str) -> Result<u32, ReplayerError> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.write(Kind::Open, id, target.as_bytes()).await?;
+        Ok(id)
+    }
+
+    pub async fn data(&mut self, id: u32, data: &[u8]) -> Result<(), ReplayerError> {
+        self.write(Kind::Data, id, data).await
+    }
+
+    /// Completes the upload and returns the status code the server answered with, an
+    /// error unless it is a success.
+    pub async fn close(&mut self, id: u32) -> Result<u16, ReplayerError> {
+        self.write(Kind::Close, id, &[]).await?;
+        loop {
+            let frame = framing::read_frame(&mut self.stream)
+                .await
+                .map_err(|e| ReplayerError::NetworkError(format!("read frame: {}", e)))?
+                .ok_or_else(|| {
+                    ReplayerError::NetworkError("connection closed by the server".to_string())
+                })?;
+            // statuses of earlier uploads that failed before they were closed
+            if frame.id != id {
+                continue;
+            }
+            return match frame.status() {
+                Some(status) if (200..300).contains(&status) => Ok(status),
+                Some(status) => Err(ReplayerError::RequestError(format!(
+                    "upload rejected with status {}",
+                    status
+                ))),
+                None => Err(ReplayerError::RequestError(format!(
+                    "unexpected frame {:?}",
+                    frame.kind
+                ))),
+            };
+        }
+    }
+
+    async fn write(&mut self, kind: Kind, id: u32, payload: &[u8]) -> Result<(), ReplayerError> {
+        let mut result = framing::write_frame(&mut self.stream, kind, id, payload).await;
+        if result.is_ok() {
+            result = self.stream.flush().await;
+        }
+        result.map_err(|e| ReplayerError::NetworkError(format!("write frame: {}", e)))
+    }
+}
@@ -1,5 +1,6 @@
 mod config;
 mod error;
+mod framed;
 mod replayer;
 mod storage;
 mod stream;
@@ -9,6 +10,7 @@ use crate::error::ReplayerError;
 use crate::storage::FileStorage;
 use crate::stream::StreamMetadata;
 use clap::Parser as ClapParser;
+use common::logging::LogArgs;
 use hyper::Uri;
 use replayer::Replayer;
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -18,31 +20,27 @@ use std::{fs, process};
 use tokio::task::JoinSet;
 use tokio::time::Instant;
 use tracing::{error, info};
-use tracing_subscriber::{fmt, layer::SubscriberExt};
 
 #[derive(ClapParser, Debug)]
 #[command(version)]
 struct Cli {
     #[arg(short, long, default_value = "config.toml")]
     config: String,
+
+    #[command(flatten)]
+    log: LogArgs,
 }
 
 #[tokio::main]
 async fn main() {
-    let subscriber = tracing_subscriber::registry().with(
-        fmt::Layer::default()
-            .with_target(false)
-            .with_thread_names(false)
-            .with_ansi(true)
-            .with_line_number(false)
-            .with_file(false)
-            .with_thread_ids(false),
-    );
-    tracing::subscriber::set_global_default(subscriber)
-        .expect("Unable to set a global logger instance");
-
     let cli = Cli::parse();
-    let settings = match build_settings(cli.config.as_str()) {
+    let settings = build_settings(cli.config.as_str());
+    let log_config = match &settings {
+        Ok(settings) => cli.log.apply(&settings.log),
+        Err(_) => cli.log.apply(&Default::default()),
+    };
+    let log = common::logging::init("replayer", &log_config);
+    let settings = match settings {
         Ok(settings) => settings,
         Err(e) => {
             error!("Failed to load configuration: {}", e);
@@ -52,8 +50,10 @@ async fn main() {
 
     if let Err(e) = run(&settings).await {
         error!("Server error: {}", e);
+        log.flush();
         process::exit(1);
     }
+    log.flush();
 }
 
 pub async fn run(settings: &Settings) -> Result<(), ReplayerError> {
@@ -94,12 +94,13 @@ pub async fn run(settings: &Settings) -> Result<(), ReplayerError> {
         let mut set = JoinSet::new();
         for i in 0..step.parallel {
             let uri = uri.clone();
+            let framed = settings.target.framed.clone();
             let metadata = metadata.clone();
             let storage = storage.clone();
             let duration = step.duration.clone();
             let bytes_sent = bytes_sent.clone();
             set.spawn(async move {
-                let res = play(i, metadata, storage, uri, duration).await;
+                let res = play(i, metadata, storage, uri, framed, duration).await;
                 if res.is_err() {
                     error!("thread {}: {}", i, res.err().unwrap());
                     return;
@@ -129,6 +130,7 @@ async fn play(
     meta: Arc<StreamMetadata>,
     storage: Arc<FileStorage>,
     uri: Uri,
+    framed: Option<String>,
     duration: Option<Duration>,
 ) -> Result<usize, ReplayerError> {
     let bytes_sent = Arc::new(AtomicUsize::new(0));
@@ -155,9 +157,12 @@ async fn play(
             .build()
             .unwrap();
 
+        let framed = framed.clone();
         let bytes_sent = bytes_sent.clone();
         set.spawn(async move {
-            let res = replayer.play(uri.clone(), duration).await;
+            let res = replayer
+                .play(uri.clone(), framed.as_deref(), duration)
+                .await;
             if res.is_err() {
                 error!("replay: uri {}: {}", uri.to_string(), res.err().unwrap());
                 return;
@@ -1,4 +1,5 @@
 use crate::error::ReplayerError;
+use crate::framed::FramedClient;
 use crate::storage::FileStorage;
 use crate::stream::{FileMetadata, RepresentationMetadata};
 use bytes::Bytes;
@@ -17,8 +18,15 @@ use std::task::{Context, Poll};
 use std::time::Duration;
 use tokio::net::TcpStream;
 use tokio::time::Instant;
-use tokio_stream::Stream;
-use tracing::error;
+use tokio_stream::{Stream, StreamExt};
+use tracing::field::Empty;
+use tracing::{error, info_span, Instrument};
+
+/// Connection the files of a representation are uploaded over.
+enum Connection {
+    Http(http1::SendRequest<BoxBody<Bytes, Infallible>>),
+    Framed(FramedClient),
+}
 
 pub struct Replayer {
     meta: Arc<RepresentationMetadata>,
@@ -30,13 +38,26 @@ impl Replayer {
         Self { meta, storage }
     }
 
-    pub async fn play(&self, uri: Uri, duration: Option<Duration>) -> Result<usize, ReplayerError> {
-        let (mut sender, conn) = self.connect(&uri).await?;
-        tokio::task::spawn(async move {
-            if let Err(err) = conn.await {
-                error!("connection: {:?}", err);
+    /// Uploads the files of the representation to the target, over the framed ingest
+    /// protocol when its address is given.
+    pub async fn play(
+        &self,
+        uri: Uri,
+        framed: Option<&str>,
+        duration: Option<Duration>,
+    ) -> Result<usize, ReplayerError> {
+        let mut connection = match framed {
+            Some(addr) => Connection::Framed(FramedClient::connect(addr).await?),
+            None => {
+                let (sender, conn) = self.connect(&uri).await?;
+                tokio::task::spawn(async move {
+                    if let Err(err) = conn.await {
+                        error!("connection: {:?}", err);
+                    }
+                });
+                Connection::Http(sender)
             }
-        });
+        };
 
         let start = Instant::now();
         let mut total_bytes_sent: usize = 0;
@@ -45,7 +66,7 @@ impl Replayer {
         if self.meta.init.is_some() {
             let init = self.meta.init.clone().unwrap();
             let uri = Self::build_init_uri(uri.clone(), self.meta.idx);
-            let bytes_sent = self.send_file(&mut sender, uri, init.clone()).await?;
+            let bytes_sent = self.send_file(&mut connection, uri, init.clone()).await?;
             total_bytes_sent += bytes_sent;
             last_time_offset = init.time_offset + init.chunks[init.chunks.len() - 1].0
         }
@@ -79,7 +100,9 @@ impl Replayer {
             }
 
             let uri = Self::build_segment_uri(uri.clone(), self.meta.idx, segment.segment.unwrap());
-            let bytes_sent = self.send_file(&mut sender, uri, segment.clone()).await?;
+            let bytes_sent = self
+                .send_file(&mut connection, uri, segment.clone())
+                .await?;
             total_bytes_sent += bytes_sent;
 
             i = i + 1;
@@ -142,7 +165,7 @@ impl Replayer {
 
     async fn send_file(
         &self,
-        sender: &mut http1::SendRequest<BoxBody<Bytes, Infallible>>,
+        connection: &mut Connection,
         uri: Uri,
         file: Arc<FileMetadata>,
     ) -> Result<usize, ReplayerError> {
@@ -154,29 +177,64 @@ impl Replayer {
             )));
         }
 
-        let stream = FileStream::from(file.clone(), data.unwrap());
+        let mut stream = FileStream::from(file.clone(), data.unwrap());
         let bytes_sent = stream.bytes_sent();
+        let span = info_span!(
+            "upload",
+            otel.kind = "client",
+            path = uri.path(),
+            status = Empty,
+            bytes = Empty
+        );
+        let sender = match connection {
+            Connection::Http(sender) => sender,
+            Connection::Framed(client) => {
+                let status = async {
+                    let id = client.open(&uri.to_string()).await?;
+                    while let Some(Ok(frame)) = stream.next().await {
+                        if let Some(data) = frame.data_ref() {
+                            client.data(id, data).await?;
+                        }
+                    }
+                    client.close(id).await
+                }
+                .instrument(span.clone())
+                .await?;
+
+                let bytes_sent = bytes_sent.load(Relaxed);
+                span.record("status", status);
+                span.record("bytes", bytes_sent);
+                return Ok(bytes_sent);
+            }
+        };
+
         let body = StreamBody::new(stream);
         let body = BoxBody::new(body);
-
-        let req = Request::builder()
+        let mut req = Request::builder()
             .method(Method::PUT)
             .uri(uri)
             .header(header::USER_AGENT, "dash-replayer/1.0")
-            .header(header::TRANSFER_ENCODING, "chunked") // Important for streaming
-            .body(body);
+            .header(header::TRANSFER_ENCODING, "chunked"); // Important for streaming
+        if let Some(traceparent) = span.in_scope(common::otlp::traceparent) {
+            req = req.header(common::otlp::TRACEPARENT, traceparent);
+        }
+        let req = req.body(body);
 
         if let Err(e) = req {
             return Err(ReplayerError::RequestError(format!("build request: {}", e)));
         }
 
         let req = req.unwrap();
-        let res = sender.send_request(req).await;
-        if let Err(e) = res {
-            return Err(ReplayerError::RequestError(format!("send request: {}", e)));
-        }
+        let res = sender.send_request(req).instrument(span.clone()).await;
+        let res = match res {
+            Ok(res) => res,
+            Err(e) => return Err(ReplayerError::RequestError(format!("send request: {}", e))),
+        };
 
-        Ok(bytes_sent.load(Relaxed))
+        let bytes_sent = bytes_sent.load(Relaxed);
+        span.record("status", res.status().as_u16());
+        span.record("bytes", bytes_sent);
+        Ok(bytes_sent)
     }
 
     async fn connect(
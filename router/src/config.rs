@@ -0,0 +1,177 @@
+use crate::error::RouterError;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Main configuration structure for the router
+#[derive(Debug, Deserialize)]
+pub struct Settings {
+    /// Listener of the uploads, forwarded to the ingesters of the backends.
+    pub ingester: Listen,
+    /// Listener of the viewers, forwarded to the transmitters of the backends.
+    pub transmitter: Listen,
+    #[serde(default)]
+    pub hash: Hash,
+    #[serde(default)]
+    pub health: HealthCheck,
+    #[serde(rename = "backend")]
+    pub backends: Vec<Backend>,
+    #[serde(default)]
+    pub log: common::logging::Log,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Listen {
+    pub addr: String,
+    #[serde(default)]
+    pub socket: common::socket::SocketOptions,
+}
+
+/// Part of the path the backend is chosen by.
+#[derive(Debug, Deserialize)]
+pub struct Hash {
+    /// Leading path segments naming a stream, 2 for tenants with a path prefix.
+    #[serde(default = "Hash::default_segments")]
+    pub segments: usize,
+}
+
+impl Default for Hash {
+    fn default() -> Self {
+        Hash {
+            segments: Hash::default_segments(),
+        }
+    }
+}
+
+impl Hash {
+    fn default_segments() -> usize {
+        1
+    }
+}
+
+/// Probes of the backends, a backend failing them gets no requests.
+#[derive(Debug, Deserialize)]
+pub struct HealthCheck {
+    /// Seconds between two probes of a backend.
+    #[serde(default = "HealthCheck::default_interval")]
+    pub interval: u64,
+    /// Seconds a probe may take.
+    #[serde(default = "HealthCheck::default_timeout")]
+    pub timeout: u64,
+    /// Failed probes or requests in a row taking a backend out.
+    #[serde(default = "HealthCheck::default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+    /// Passed probes in a row bringing a backend back.
+    #[serde(default = "HealthCheck::default_healthy_threshold")]
+    pub healthy_threshold: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            interval: HealthCheck::default_interval(),
+            timeout: HealthCheck::default_timeout(),
+            unhealthy_threshold: HealthCheck::default_unhealthy_threshold(),
+            healthy_threshold: HealthCheck::default_healthy_threshold(),
+        }
+    }
+}
+
+impl HealthCheck {
+    fn default_interval() -> u64 {
+        2
+    }
+
+    fn default_timeout() -> u64 {
+        1
+    }
+
+    fn default_unhealthy_threshold() -> u32 {
+        2
+    }
+
+    fn default_healthy_threshold() -> u32 {
+        2
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+/// Server instance of the pool.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Backend {
+    /// Name the backend is placed on the hash ring by, keep it when an address changes.
+    pub name: String,
+    /// `host:port` of the ingester.
+    pub ingester: String,
+    /// `host:port` of the transmitter.
+    pub transmitter: String,
+    /// `host:port` of the admin server, probed at `/readyz`. Without it the transmitter
+    /// is probed with a TCP connect.
+    pub admin: Option<String>,
+    /// Share of the streams relative to the other backends.
+    #[serde(default = "Backend::default_weight")]
+    pub weight: u32,
+}
+
+impl Backend {
+    fn default_weight() -> u32 {
+        1
+    }
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), RouterError> {
+        if self.backends.is_empty() {
+            return Err(RouterError::ConfigError(
+                "at least one backend is required".to_string(),
+            ));
+        }
+        for (i, backend) in self.backends.iter().enumerate() {
+            if self.backends[..i].iter().any(|b| b.name == backend.name) {
+                return Err(RouterError::ConfigError(format!(
+                    "backend '{}': duplicate name",
+                    backend.name
+                )));
+            }
+            if backend.weight == 0 {
+                return Err(RouterError::ConfigError(format!(
+                    "backend '{}': weight must be greater than 0",
+                    backend.name
+                )));
+            }
+            let addrs = [
+                Some(&backend.ingester),
+                Some(&backend.transmitter),
+                backend.admin.as_ref(),
+            ];
+            for addr in addrs.into_iter().flatten() {
+                format!("http://{}/", addr)
+                    .parse::<hyper::Uri>()
+                    .map_err(|e| {
+                        RouterError::ConfigError(format!(
+                            "backend '{}': '{}': {}",
+                            backend.name, addr, e
+                        ))
+                    })?;
+            }
+        }
+        if self.hash.segments == 0 {
+            return Err(RouterError::ConfigError(
+                "hash.segments: must be greater than 0".to_string(),
+            ));
+        }
+        if self.health.interval == 0 || self.health.timeout == 0 {
+            return Err(RouterError::ConfigError(
+                "health: interval and timeout must be greater than 0".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,18 @@
+use std::{error::Error, fmt};
+
+#[derive(Debug)]
+pub enum RouterError {
+    ConfigError(String),
+    NetworkError(String),
+}
+
+impl fmt::Display for RouterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouterError::ConfigError(msg) => write!(f, "Configuration error: {}", msg),
+            RouterError::NetworkError(msg) => write!(f, "Network error: {}", msg),
+        }
+    }
+}
+
+impl Error for RouterError {}
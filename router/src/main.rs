@@ -0,0 +1,132 @@
+mod config;
+mod error;
+mod pool;
+mod ring;
+mod service;
+
+use crate::config::{Listen, Settings};
+use crate::error::RouterError;
+use crate::pool::Pool;
+use crate::service::{Proxy, Role};
+use clap::Parser as ClapParser;
+use common::logging::LogArgs;
+use common::socket::Listener;
+use hyper::server::conn::http1;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::sync::Arc;
+use std::{fs, process};
+use tracing::{debug, error, info};
+
+#[derive(ClapParser, Debug)]
+#[command(version)]
+struct Cli {
+    #[arg(short, long, default_value = "router.toml")]
+    config: String,
+
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    let settings = build_settings(cli.config.as_str());
+    let log_config = match &settings {
+        Ok(settings) => cli.log.apply(&settings.log),
+        Err(_) => cli.log.apply(&Default::default()),
+    };
+    let log = common::logging::init("router", &log_config);
+    let settings = match settings {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("Failed to load configuration: {}", e);
+            process::exit(1);
+        }
+    };
+
+    if let Err(e) = run(settings).await {
+        error!("Router error: {}", e);
+        log.flush();
+        process::exit(1);
+    }
+    log.flush();
+}
+
+pub async fn run(settings: Settings) -> Result<(), RouterError> {
+    let ingester = listen("ingester", &settings.ingester)?;
+    let transmitter = listen("transmitter", &settings.transmitter)?;
+    for backend in settings.backends.iter() {
+        info!(
+            "backend {}: ingester {}, transmitter {}, weight {}",
+            backend.name, backend.ingester, backend.transmitter, backend.weight
+        );
+    }
+
+    let (ingester_options, transmitter_options) = (
+        settings.ingester.socket.clone(),
+        settings.transmitter.socket.clone(),
+    );
+    let pool = Arc::new(Pool::new(settings));
+    tokio::spawn(Arc::clone(&pool).check());
+
+    let client = Client::builder(TokioExecutor::new()).build_http();
+    let ingest = Proxy::new(Arc::clone(&pool), Role::Ingester, client.clone());
+    let transmit = Proxy::new(pool, Role::Transmitter, client);
+    tokio::try_join!(
+        serve(ingester, ingester_options, ingest),
+        serve(transmitter, transmitter_options, transmit),
+    )?;
+    Ok(())
+}
+
+fn listen(name: &str, listen: &Listen) -> Result<Listener, RouterError> {
+    let addr = common::socket::parse_address(listen.addr.clone())
+        .map_err(|e| RouterError::NetworkError(format!("{}: {}", name, e)))?;
+    listen
+        .socket
+        .validate()
+        .map_err(|e| RouterError::ConfigError(format!("{}.socket.{}", name, e)))?;
+    let socket = common::socket::listen_reuse_socket(&addr, &listen.socket)
+        .map_err(|e| RouterError::NetworkError(format!("{}: {}", name, e)))?;
+    let listener =
+        Listener::from_socket(socket).map_err(|e| RouterError::NetworkError(e.to_string()))?;
+
+    info!("{}: listening on {}", name, addr);
+    Ok(listener)
+}
+
+async fn serve(
+    listener: Listener,
+    options: common::socket::SocketOptions,
+    proxy: Proxy,
+) -> Result<(), RouterError> {
+    let http = http1::Builder::new();
+    loop {
+        let (stream, remote_addr) = listener
+            .accept(&options)
+            .await
+            .map_err(|e| RouterError::NetworkError(e.to_string()))?;
+
+        let service = proxy.with_remote(remote_addr.map(|addr| addr.ip()));
+        let conn = http.serve_connection(TokioIo::new(stream), service);
+
+        debug!("Connection accepted from {:?}", remote_addr);
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                debug!("Connection error: {:?}", e);
+            }
+        });
+    }
+}
+
+fn build_settings(config_path: &str) -> Result<Settings, RouterError> {
+    let data = fs::read_to_string(config_path).map_err(|_| {
+        RouterError::ConfigError(format!("Config file '{}' does not exist", config_path))
+    })?;
+
+    let settings: Settings = toml::from_str(&data)
+        .map_err(|e| RouterError::ConfigError(format!("Invalid configuration: {}", e)))?;
+    settings.validate()?;
+    Ok(settings)
+}
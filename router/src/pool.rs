@@ -0,0 +1,144 @@
+use crate::config::{self, HealthCheck, Settings};
+use crate::ring::{self, Ring};
+use bytes::Bytes;
+use http_body_util::Empty;
+use hyper::Request;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+/// Server instances behind the router, placed on a hash ring by their streams.
+#[derive(Debug)]
+pub struct Pool {
+    backends: Vec<Backend>,
+    ring: Ring,
+    segments: usize,
+    health: HealthCheck,
+}
+
+impl Pool {
+    pub fn new(settings: Settings) -> Self {
+        let ring = Ring::new(
+            settings
+                .backends
+                .iter()
+                .map(|backend| (backend.name.as_str(), backend.weight)),
+        );
+        Pool {
+            backends: settings.backends.into_iter().map(Backend::new).collect(),
+            ring,
+            segments: settings.hash.segments,
+            health: settings.health,
+        }
+    }
+
+    /// Returns the healthy backend serving the stream of the path.
+    pub fn backend(&self, path: &str) -> Option<&Backend> {
+        let key = ring::key(path, self.segments);
+        self.ring
+            .node(key, |node| self.backends[node].is_healthy())
+            .map(|node| &self.backends[node])
+    }
+
+    /// Reports a failed request to a backend, it counts like a failed probe.
+    pub fn failed(&self, backend: &Backend) {
+        backend.report(false, &self.health);
+    }
+
+    /// Probes the backends in the configured interval, forever.
+    pub async fn check(self: Arc<Self>) {
+        let client: Client<HttpConnector, Empty<Bytes>> =
+            Client::builder(TokioExecutor::new()).build_http();
+        let mut ticker = tokio::time::interval(self.health.interval());
+        loop {
+            ticker.tick().await;
+            for node in 0..self.backends.len() {
+                let (pool, client) = (Arc::clone(&self), client.clone());
+                tokio::spawn(async move {
+                    let backend = &pool.backends[node];
+                    let probe = backend.probe(&client);
+                    let ok = matches!(
+                        tokio::time::timeout(pool.health.timeout(), probe).await,
+                        Ok(true)
+                    );
+                    backend.report(ok, &pool.health);
+                });
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Backend {
+    pub config: config::Backend,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    healthy: bool,
+    /// Probes in a row that disagree with `healthy`.
+    streak: u32,
+}
+
+impl Backend {
+    fn new(config: config::Backend) -> Self {
+        Backend {
+            config,
+            // a backend gets requests until its probes fail
+            state: Mutex::new(State {
+                healthy: true,
+                streak: 0,
+            }),
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.state.lock().unwrap().healthy
+    }
+
+    async fn probe(&self, client: &Client<HttpConnector, Empty<Bytes>>) -> bool {
+        match &self.config.admin {
+            Some(admin) => {
+                let req = Request::get(format!("http://{}/readyz", admin))
+                    .body(Empty::new())
+                    .unwrap();
+                match client.request(req).await {
+                    Ok(response) => response.status().is_success(),
+                    Err(_) => false,
+                }
+            }
+            None => TcpStream::connect(&self.config.transmitter).await.is_ok(),
+        }
+    }
+
+    fn report(&self, ok: bool, health: &HealthCheck) {
+        let mut state = self.state.lock().unwrap();
+        if ok == state.healthy {
+            state.streak = 0;
+            return;
+        }
+
+        state.streak += 1;
+        let threshold = if ok {
+            health.healthy_threshold
+        } else {
+            health.unhealthy_threshold
+        };
+        if state.streak >= threshold {
+            state.healthy = ok;
+            state.streak = 0;
+            if ok {
+                info!("backend {}: healthy", self.config.name);
+            } else {
+                warn!(
+                    "backend {}: unhealthy, its streams move on",
+                    self.config.name
+                );
+            }
+        }
+    }
+}
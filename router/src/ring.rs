@@ -0,0 +1,113 @@
+/// Points on the ring per unit of weight, more points spread the keys more evenly.
+const POINTS_PER_WEIGHT: u32 = 160;
+
+/// Consistent hash ring of weighted nodes. Adding a node only moves the keys that land
+/// on its points, removing one only the keys it owned.
+#[derive(Debug)]
+pub struct Ring {
+    /// Points sorted by hash with the index of their node.
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    /// Places the nodes, given by name and weight, on the ring.
+    pub fn new<'a>(nodes: impl IntoIterator<Item = (&'a str, u32)>) -> Self {
+        let mut points = Vec::new();
+        for (node, (name, weight)) in nodes.into_iter().enumerate() {
+            for point in 0..weight * POINTS_PER_WEIGHT {
+                points.push((hash(format!("{}#{}", name, point).as_bytes()), node));
+            }
+        }
+        points.sort_unstable();
+        Ring { points }
+    }
+
+    /// Returns the node owning the key, the next one along the ring is taken while
+    /// `usable` rejects a node.
+    pub fn node(&self, key: &str, usable: impl Fn(usize) -> bool) -> Option<usize> {
+        let hash = hash(key.as_bytes());
+        let start = self.points.partition_point(|(point, _)| *point < hash);
+        let (below, above) = self.points.split_at(start);
+        above
+            .iter()
+            .chain(below)
+            .map(|(_, node)| *node)
+            .find(|node| usable(*node))
+    }
+}
+
+/// Returns the leading `segments` segments of the path, `/live/s1/0/1.m4s` is keyed by
+/// `live/s1` with 2 segments.
+pub fn key(path: &str, segments: usize) -> &str {
+    let path = path.trim_start_matches('/');
+    match path.match_indices('/').nth(segments - 1) {
+        Some((end, _)) => &path[..end],
+        None => path,
+    }
+}
+
+/// FNV-1a with the finalizer of MurmurHash3, stable across builds and platforms.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owners(ring: &Ring, keys: &[String]) -> Vec<usize> {
+        keys.iter()
+            .map(|key| ring.node(key, |_| true).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn key_by_stream_prefix() {
+        assert_eq!(key("/s1/0/1.m4s", 1), "s1");
+        assert_eq!(key("/s1/index.mpd", 1), "s1");
+        assert_eq!(key("/live/s1/0/1.m4s", 2), "live/s1");
+        assert_eq!(key("/s1", 2), "s1");
+    }
+
+    #[test]
+    fn adding_a_node_moves_only_its_keys() {
+        let keys: Vec<String> = (0..10000).map(|i| format!("stream-{}", i)).collect();
+        let before = owners(&Ring::new([("a", 1), ("b", 1), ("c", 1)]), &keys);
+        let after = owners(&Ring::new([("a", 1), ("b", 1), ("c", 1), ("d", 1)]), &keys);
+
+        let moved = before.iter().zip(after.iter()).filter(|(b, a)| b != a);
+        assert!(moved.clone().all(|(_, a)| *a == 3));
+        // about a quarter of the keys move to the new node
+        let moved = moved.count();
+        assert!((2000..3000).contains(&moved), "{} keys moved", moved);
+    }
+
+    #[test]
+    fn weighted_nodes_and_failover() {
+        let keys: Vec<String> = (0..10000).map(|i| format!("stream-{}", i)).collect();
+        let ring = Ring::new([("a", 1), ("b", 3)]);
+        let on_b = owners(&ring, &keys).iter().filter(|n| **n == 1).count();
+        assert!((7000..8000).contains(&on_b), "{} keys on b", on_b);
+
+        // keys of an unusable node go to the others, the rest stay
+        let ring = Ring::new([("a", 1), ("b", 1), ("c", 1)]);
+        for key in keys.iter() {
+            let owner = ring.node(key, |_| true).unwrap();
+            let failover = ring.node(key, |node| node != 1).unwrap();
+            assert_ne!(failover, 1);
+            if owner != 1 {
+                assert_eq!(failover, owner);
+            }
+        }
+        assert_eq!(ring.node("s1", |_| false), None);
+    }
+}
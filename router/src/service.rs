@@ -0,0 +1,115 @@
+use crate::pool::Pool;
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Incoming;
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::service::Service;
+use hyper::{Request, Response, StatusCode, Uri};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// Headers of a single connection, not forwarded.
+const HOP_BY_HOP: [HeaderName; 4] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    header::TE,
+    header::UPGRADE,
+];
+
+/// Listener a request came in on, it goes to the same listener of the backend.
+#[derive(Debug, Clone, Copy)]
+pub enum Role {
+    Ingester,
+    Transmitter,
+}
+
+/// Forwards the requests to the backend owning their stream.
+#[derive(Clone)]
+pub struct Proxy {
+    pool: Arc<Pool>,
+    role: Role,
+    client: Client<HttpConnector, Incoming>,
+    remote: Option<IpAddr>,
+}
+
+impl Proxy {
+    pub fn new(pool: Arc<Pool>, role: Role, client: Client<HttpConnector, Incoming>) -> Self {
+        Proxy {
+            pool,
+            role,
+            client,
+            remote: None,
+        }
+    }
+
+    /// Returns the service for a connection from the given address.
+    pub fn with_remote(&self, remote: Option<IpAddr>) -> Self {
+        let mut proxy = self.clone();
+        proxy.remote = remote;
+        proxy
+    }
+
+    async fn forward(&self, req: Request<Incoming>) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let backend = match self.pool.backend(req.uri().path()) {
+            Some(backend) => backend,
+            None => {
+                warn!("{}: no healthy backend", req.uri().path());
+                return empty_response(StatusCode::SERVICE_UNAVAILABLE);
+            }
+        };
+        let addr = match self.role {
+            Role::Ingester => &backend.config.ingester,
+            Role::Transmitter => &backend.config.transmitter,
+        };
+
+        let (mut parts, body) = req.into_parts();
+        let path = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+        parts.uri = match format!("http://{}{}", addr, path).parse::<Uri>() {
+            Ok(uri) => uri,
+            Err(_) => return empty_response(StatusCode::BAD_REQUEST),
+        };
+        for name in HOP_BY_HOP.iter() {
+            parts.headers.remove(name);
+        }
+        if let Some(remote) = self.remote {
+            if let Ok(remote) = HeaderValue::from_str(&remote.to_string()) {
+                parts.headers.append("x-forwarded-for", remote);
+            }
+        }
+
+        debug!("{} -> {}", parts.uri.path(), backend.config.name);
+        match self.client.request(Request::from_parts(parts, body)).await {
+            Ok(response) => response.map(|body| body.boxed()),
+            Err(e) => {
+                warn!("backend {}: {}", backend.config.name, e);
+                self.pool.failed(backend);
+                empty_response(StatusCode::BAD_GATEWAY)
+            }
+        }
+    }
+}
+
+impl Service<Request<Incoming>> for Proxy {
+    type Response = Response<BoxBody<Bytes, hyper::Error>>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, req: Request<Incoming>) -> Self::Future {
+        let this = self.clone();
+        Box::pin(async move { Ok(this.forward(req).await) })
+    }
+}
+
+fn empty_response(status: StatusCode) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let body = Empty::new().map_err(|never| match never {}).boxed();
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response
+}
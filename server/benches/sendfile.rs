@@ -0,0 +1,91 @@
+use bytes::Bytes;
+use common::socket::Stream;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::fs::File;
+use std::future::poll_fn;
+use std::io::Write;
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Builder;
+
+/// Segments sent over loopback TCP from memory through the user-space write path and
+/// from their file with sendfile, as `transmitter.sendfile` does for static caches.
+fn transmit(c: &mut Criterion) {
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let mut group = c.benchmark_group("Transmit");
+    let size_config = [100_000, 300_000, 1_000_000, 4_000_000];
+    for size in size_config {
+        group.throughput(Throughput::Bytes(size as u64));
+
+        let path = std::env::temp_dir().join(format!("sendfile-bench-{}", size));
+        let data = Bytes::from(vec![7u8; size]);
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        let file = File::open(&path).unwrap();
+
+        group.bench_with_input(BenchmarkId::new("write", size), &size, |b, _| {
+            b.to_async(&runtime).iter_custom(|iters| {
+                let data = data.clone();
+                async move {
+                    let mut stream = connect(size as u64 * iters).await;
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        stream.write_all(&data).await.unwrap();
+                    }
+                    start.elapsed()
+                }
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("sendfile", size), &size, |b, _| {
+            b.to_async(&runtime).iter_custom(|iters| {
+                let file = &file;
+                async move {
+                    let stream = connect(size as u64 * iters).await;
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        let mut sent = 0;
+                        while sent < size {
+                            sent += poll_fn(|cx| {
+                                stream.poll_sendfile(cx, file, sent as u64, size - sent)
+                            })
+                            .await
+                            .unwrap();
+                        }
+                    }
+                    start.elapsed()
+                }
+            })
+        });
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    group.finish();
+}
+
+/// Returns a connection to a reader that drains `total` bytes.
+async fn connect(total: u64) -> Stream {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut reader, _) = listener.accept().await.unwrap();
+        let mut buffer = vec![0; 256 * 1024];
+        let mut received = 0;
+        while received < total {
+            match reader.read(&mut buffer).await.unwrap() {
+                0 => break,
+                n => received += n as u64,
+            }
+        }
+    });
+    Stream::Tcp(TcpStream::connect(addr).await.unwrap())
+}
+
+criterion_group!(benches, transmit);
+criterion_main!(benches);
@@ -0,0 +1,393 @@
+use crate::config::{self, AccessLogFormat};
+use crate::errors::ServerError;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::{header, Request};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+/// Lines waiting to be written, further lines are dropped.
+const QUEUE_SIZE: usize = 65536;
+
+/// Access log of a listener. Lines are written by a dedicated thread so slow storage
+/// never blocks request handling.
+#[derive(Debug)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+    sample: f64,
+    sender: SyncSender<String>,
+}
+
+impl AccessLog {
+    pub fn new(listener: &str, config: &config::AccessLog) -> Result<Self, ServerError> {
+        let output: Box<dyn Write + Send> = match &config.path {
+            Some(path) => {
+                let file =
+                    RotatingFile::open(PathBuf::from(path), config.max_size, config.max_files)
+                        .map_err(|e| {
+                            ServerError::StorageError(format!("access log '{}': {}", path, e))
+                        })?;
+                Box::new(file)
+            }
+            None => Box::new(io::stdout()),
+        };
+
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        thread::Builder::new()
+            .name(format!("{}-access-log", listener))
+            .spawn(move || write_lines(receiver, output))
+            .map_err(|e| ServerError::StorageError(format!("access log: {}", e)))?;
+
+        Ok(AccessLog {
+            format: config.format,
+            sample: config.sample,
+            sender,
+        })
+    }
+
+    /// Writes the entry. Successful requests are sampled, errors are always logged.
+    pub fn log(&self, entry: &Entry) {
+        if entry.status < 400 && self.sample < 1.0 && rand::random::<f64>() >= self.sample {
+            return;
+        }
+
+        let line = match self.format {
+            AccessLogFormat::Json => entry.json(),
+            AccessLogFormat::Combined => entry.combined(),
+        };
+        let _ = self.sender.try_send(line);
+    }
+}
+
+/// Request details recorded in the access log.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    started: Instant,
+    time: DateTime<Utc>,
+    remote: Option<IpAddr>,
+    method: String,
+    path: String,
+    version: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    pub status: u16,
+    pub bytes: u64,
+    pub first_byte: Option<Duration>,
+    pub duration: Duration,
+    /// The response waited for data of an upload in progress.
+    pub live: bool,
+    /// The response body was sent to the end.
+    pub completed: bool,
+}
+
+impl Entry {
+    pub fn new<B>(req: &Request<B>, remote: Option<IpAddr>) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string())
+        };
+
+        Entry {
+            started: Instant::now(),
+            time: Utc::now(),
+            remote,
+            method: req.method().to_string(),
+            path: req.uri().path().to_string(),
+            version: format!("{:?}", req.version()),
+            referer: header(header::REFERER),
+            user_agent: header(header::USER_AGENT),
+            status: 0,
+            bytes: 0,
+            first_byte: None,
+            duration: Duration::ZERO,
+            live: false,
+            completed: false,
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    fn json(&self) -> String {
+        serde_json::json!({
+            "time": self.time.to_rfc3339(),
+            "remote": self.remote.map(|remote| remote.to_string()),
+            "method": self.method,
+            "path": self.path,
+            "status": self.status,
+            "bytes": self.bytes,
+            "ttfb_ms": self.first_byte.map(|ttfb| ttfb.as_secs_f64() * 1000.0),
+            "duration_ms": self.duration.as_secs_f64() * 1000.0,
+            "live": self.live,
+            "completed": self.completed,
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+        })
+        .to_string()
+    }
+
+    /// Combined log format followed by the time to first byte and the duration in
+    /// milliseconds and whether the response was live.
+    fn combined(&self) -> String {
+        let ttfb = match self.first_byte {
+            Some(ttfb) => format!("{:.3}", ttfb.as_secs_f64() * 1000.0),
+            None => "-".to_string(),
+        };
+
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {} {:.3} {}",
+            self.remote
+                .map_or("-".to_string(), |remote| remote.to_string()),
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            self.path,
+            self.version,
+            self.status,
+            self.bytes,
+            self.referer.as_deref().unwrap_or("-"),
+            self.user_agent.as_deref().unwrap_or("-"),
+            ttfb,
+            self.duration.as_secs_f64() * 1000.0,
+            if self.live { "live" } else { "-" },
+        )
+    }
+}
+
+/// Response body that records its progress and logs the entry when it is dropped.
+pub struct Logged<B: Body> {
+    inner: B,
+    entry: Entry,
+    log: Arc<AccessLog>,
+}
+
+impl<B: Body> Logged<B> {
+    pub fn new(inner: B, entry: Entry, log: Arc<AccessLog>) -> Self {
+        Logged { inner, entry, log }
+    }
+}
+
+impl<B> Body for Logged<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let result = Pin::new(&mut self.inner).poll_frame(cx);
+        let entry = &mut self.entry;
+        match &result {
+            Poll::Pending => entry.live = true,
+            Poll::Ready(frame) => {
+                if entry.first_byte.is_none() {
+                    entry.first_byte = Some(entry.elapsed());
+                }
+                match frame {
+                    Some(Ok(frame)) => {
+                        if let Some(data) = frame.data_ref() {
+                            entry.bytes += data.len() as u64;
+                        }
+                    }
+                    Some(Err(_)) => {}
+                    None => entry.completed = true,
+                }
+            }
+        }
+
+        result
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<B: Body> Drop for Logged<B> {
+    fn drop(&mut self) {
+        // hyper doesn't poll bodies known to be empty
+        self.entry.completed |= self.inner.is_end_stream();
+        self.entry.duration = self.entry.elapsed();
+        self.log.log(&self.entry);
+    }
+}
+
+fn write_lines(receiver: Receiver<String>, output: Box<dyn Write + Send>) {
+    let mut output = BufWriter::new(output);
+    while let Ok(line) = receiver.recv() {
+        let mut line = Some(line);
+        // write what is queued before flushing
+        while let Some(next) = line {
+            if let Err(e) = writeln!(output, "{}", next) {
+                error!("access log: write: {}", e);
+            }
+            line = match receiver.try_recv() {
+                Ok(next) => Some(next),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+            };
+        }
+
+        if let Err(e) = output.flush() {
+            error!("access log: flush: {}", e);
+        }
+    }
+}
+
+/// File renamed to `<path>.1`, `<path>.2`, ... once it grows above the maximum size,
+/// keeping at most `max_files` old files.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated(&self, i: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", i));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+            self.size = 0;
+            return Ok(());
+        }
+
+        for i in (1..self.max_files).rev() {
+            let from = self.rotated(i);
+            if from.exists() {
+                fs::rename(&from, self.rotated(i + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> Entry {
+        let req = Request::builder()
+            .uri("/s1/0/1.m4s")
+            .header(header::USER_AGENT, "player")
+            .body(())
+            .unwrap();
+        let mut entry = Entry::new(&req, Some(IpAddr::from([10, 0, 0, 1])));
+        entry.status = 200;
+        entry.bytes = 1024;
+        entry.first_byte = Some(Duration::from_millis(2));
+        entry.duration = Duration::from_millis(40);
+        entry.live = true;
+        entry
+    }
+
+    #[test]
+    fn combined_format() {
+        let line = entry().combined();
+        assert!(line.starts_with("10.0.0.1 - - ["), "{}", line);
+        assert!(
+            line.ends_with(
+                "\"GET /s1/0/1.m4s HTTP/1.1\" 200 1024 \"-\" \"player\" 2.000 40.000 live"
+            ),
+            "{}",
+            line
+        );
+    }
+
+    #[test]
+    fn json_format() {
+        let line: serde_json::Value = serde_json::from_str(&entry().json()).unwrap();
+        assert_eq!(line["remote"], "10.0.0.1");
+        assert_eq!(line["status"], 200);
+        assert_eq!(line["bytes"], 1024);
+        assert_eq!(line["live"], true);
+    }
+
+    #[test]
+    fn rotate_files() {
+        let dir = std::env::temp_dir().join(format!("access-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in [
+            "first line\n",
+            "second line\n",
+            "third line\n",
+            "fourth line\n",
+        ] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth line\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("access.log.1")).unwrap(),
+            "third line\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("access.log.2")).unwrap(),
+            "second line\n"
+        );
+        assert!(!dir.join("access.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+use crate::api::http::server::{close, listen, listener_name, Lifecycle};
+use crate::api::http::service::IngesterService;
+use crate::errors::ServerError;
+use crate::ingester::UploadBody;
+use bytes::Bytes;
+use common::framing::{self, Kind};
+use common::socket::{SocketOptions, Stream};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::{Body, Frame};
+use hyper::service::Service;
+use hyper::{Method, Request, Uri};
+use std::collections::HashMap;
+use std::io;
+use std::pin::{self, Pin};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, WriteHalf};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tracing::{debug, error, info};
+
+/// Chunks buffered per upload, reading the connection waits while an upload is full.
+const UPLOAD_BUFFER: usize = 16;
+
+/// Runs the listener of the framed ingest protocol. Its uploads and deletes go through
+/// the ingester service like the HTTP requests they stand for.
+pub async fn start_framed_ingester(
+    lifecycle: Lifecycle,
+    addr: String,
+    socket: SocketOptions,
+    core: Option<usize>,
+    ingester_service: IngesterService,
+) -> Result<(), ServerError> {
+    let name: Arc<str> = listener_name("framed", core).into();
+    let listener = listen(&name, addr, &socket, core.is_some())?;
+    lifecycle.readiness.listening();
+
+    let (shutdown, stopping) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    let mut signal = pin::pin!(lifecycle.stop_accepting());
+
+    loop {
+        tokio::select! {
+            Ok((stream, remote)) = listener.accept(&socket) => {
+                let service = ingester_service.with_remote(remote.map(|remote| remote.ip()));
+                let stopping = stopping.clone();
+                let name = Arc::clone(&name);
+                connections.spawn(async move {
+                    if let Err(e) = serve(stream, service, stopping).await {
+                        error!("{}: downstream: serve: {}", name, e);
+                    }
+                });
+            },
+            Some(_) = connections.join_next() => {}
+            _ = &mut signal => {
+                info!("{}: framed server: graceful shutdown", name);
+                break;
+            }
+        }
+    }
+    close(&name, listener);
+
+    shutdown.send_replace(true);
+    tokio::select! {
+        _ = async { while connections.join_next().await.is_some() {} } => {
+            info!("{}: framed server: all connections gracefully closed", name);
+        },
+        _ = tokio::time::sleep(lifecycle.grace_period) => {
+            info!("{}: timed out wait for all connections to close", name);
+        }
+    }
+    Ok(())
+}
+
+/// Reads the frames of a connection until it is closed or, on shutdown, until none of
+/// its uploads is open.
+async fn serve(
+    stream: Stream,
+    service: IngesterService,
+    mut stopping: watch::Receiver<bool>,
+) -> Result<(), ServerError> {
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let (status, statuses) = mpsc::unbounded_channel();
+    let writer = tokio::spawn(write_statuses(writer, statuses));
+    let mut uploads: HashMap<u32, mpsc::Sender<Option<Bytes>>> = HashMap::new();
+
+    let result = loop {
+        uploads.retain(|_, upload| !upload.is_closed());
+        if uploads.is_empty() && *stopping.borrow() {
+            break Ok(());
+        }
+        tokio::select! {
+            ready = reader.fill_buf() => match ready {
+                Ok([]) => break Ok(()),
+                Ok(_) => {}
+                Err(e) => break Err(e),
+            },
+            _ = stopping.changed(), if uploads.is_empty() => continue,
+        }
+
+        let frame = match framing::read_frame(&mut reader).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        let id = frame.id;
+        match frame.kind {
+            Kind::Open | Kind::Delete => {
+                if uploads.contains_key(&id) {
+                    break Err(protocol_error(format!("upload {} is open already", id)));
+                }
+                let target = match String::from_utf8(frame.payload)
+                    .ok()
+                    .and_then(|target| target.parse::<Uri>().ok())
+                {
+                    Some(target) => target,
+                    None => {
+                        // the data of the upload is dropped as the one of an unknown id
+                        let _ = status.send((id, 400));
+                        continue;
+                    }
+                };
+                let (method, body) = if frame.kind == Kind::Open {
+                    let (sender, receiver) = mpsc::channel(UPLOAD_BUFFER);
+                    uploads.insert(id, sender);
+                    (Method::PUT, FramedBody::new(receiver).boxed())
+                } else {
+                    let body = Empty::new().map_err(|never| match never {}).boxed();
+                    (Method::DELETE, body)
+                };
+                let req = Request::builder()
+                    .method(method)
+                    .uri(target)
+                    .body(body)
+                    .unwrap();
+                tokio::spawn(respond(service.clone(), id, req, status.clone()));
+            }
+            Kind::Data => {
+                // an upload that failed stops receiving, its status is sent already
+                if let Some(upload) = uploads.get(&id) {
+                    if upload.send(Some(Bytes::from(frame.payload))).await.is_err() {
+                        uploads.remove(&id);
+                    }
+                }
+            }
+            Kind::Close => {
+                if let Some(upload) = uploads.remove(&id) {
+                    let _ = upload.send(None).await;
+                }
+            }
+            Kind::Status => break Err(protocol_error("status frame from the client".to_string())),
+        }
+    };
+
+    // uploads still open fail, the statuses of the others are written before closing
+    drop((uploads, status));
+    if let Ok(Err(e)) = writer.await {
+        debug!("framed: write status: {}", e);
+    }
+    result.map_err(|e| ServerError::NetworkError(e.to_string()))
+}
+
+async fn respond(
+    service: IngesterService,
+    id: u32,
+    req: Request<UploadBody>,
+    status: mpsc::UnboundedSender<(u32, u16)>,
+) {
+    let response = match service.call(req).await {
+        Ok(response) => response,
+        Err(never) => match never {},
+    };
+    let _ = status.send((id, response.status().as_u16()));
+}
+
+async fn write_statuses(
+    writer: WriteHalf<Stream>,
+    mut statuses: mpsc::UnboundedReceiver<(u32, u16)>,
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(writer);
+    while let Some((id, status)) = statuses.recv().await {
+        framing::write_frame(&mut writer, Kind::Status, id, &status.to_be_bytes()).await?;
+        if statuses.is_empty() {
+            writer.flush().await?;
+        }
+    }
+    writer.shutdown().await
+}
+
+fn protocol_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Body of an upload fed by the data frames of its id. `None` stands for the close
+/// frame, without it the upload is incomplete.
+struct FramedBody {
+    receiver: mpsc::Receiver<Option<Bytes>>,
+    closed: bool,
+}
+
+impl FramedBody {
+    fn new(receiver: mpsc::Receiver<Option<Bytes>>) -> Self {
+        FramedBody {
+            receiver,
+            closed: false,
+        }
+    }
+}
+
+impl Body for FramedBody {
+    type Data = Bytes;
+    type Error = ServerError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, ServerError>>> {
+        if self.closed {
+            return Poll::Ready(None);
+        }
+        match self.receiver.poll_recv(cx) {
+            Poll::Ready(Some(Some(data))) => Poll::Ready(Some(Ok(Frame::data(data)))),
+            Poll::Ready(Some(None)) => {
+                self.closed = true;
+                Poll::Ready(None)
+            }
+            Poll::Ready(None) => Poll::Ready(Some(Err(ServerError::NetworkError(
+                "read body: connection closed before the upload was complete".to_string(),
+            )))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::LagPolicy;
+    use crate::tenant::{Router, Tenant};
+    use common::framing::{read_frame, write_frame};
+    use tokio::net::UnixStream;
+
+    async fn router() -> Arc<Router> {
+        let tenant = Tenant::for_test("list:copy").await;
+        Arc::new(Router::new(vec![Arc::new(tenant)]))
+    }
+
+    async fn cached(router: &Router, key: &str) -> Result<Bytes, ServerError> {
+        let tenant = router.route(&Request::get(key).body(()).unwrap()).unwrap();
+        let body = tenant
+            .shard(key)
+            .cache
+            .get(key, &LagPolicy::default())
+            .await?;
+        Ok(body.unwrap().collect().await?.to_bytes())
+    }
+
+    #[tokio::test]
+    async fn interleaved_uploads() {
+        let router = router().await;
+        let service = IngesterService::new(Arc::clone(&router), None, None, None, None);
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let (_shutdown, stopping) = watch::channel(false);
+        let connection = tokio::spawn(serve(Stream::Unix(server), service, stopping));
+
+        let frames: [(Kind, u32, &[u8]); 9] = [
+            (Kind::Open, 1, b"/s1/0/1.m4s"),
+            (Kind::Open, 2, b"/s1/1/1.m4s"),
+            (Kind::Data, 1, b"abc"),
+            (Kind::Data, 2, b"xy"),
+            (Kind::Data, 1, b"def"),
+            (Kind::Close, 1, b""),
+            (Kind::Close, 2, b""),
+            (Kind::Delete, 3, b"/s1/0/0.m4s"),
+            (Kind::Open, 4, b"not a target"),
+        ];
+        for (kind, id, payload) in frames {
+            write_frame(&mut client, kind, id, payload).await.unwrap();
+        }
+        let mut statuses = HashMap::new();
+        while statuses.len() < 4 {
+            let frame = read_frame(&mut client).await.unwrap().unwrap();
+            statuses.insert(frame.id, frame.status().unwrap());
+        }
+        assert_eq!(
+            statuses,
+            HashMap::from([(1, 200), (2, 200), (3, 200), (4, 400)])
+        );
+        assert_eq!(cached(&router, "/s1/0/1.m4s").await.unwrap(), "abcdef");
+        assert_eq!(cached(&router, "/s1/1/1.m4s").await.unwrap(), "xy");
+
+        // closing the connection fails the uploads that are still open
+        write_frame(&mut client, Kind::Open, 5, b"/s1/0/2.m4s")
+            .await
+            .unwrap();
+        write_frame(&mut client, Kind::Data, 5, b"abc")
+            .await
+            .unwrap();
+        drop(client);
+        assert!(connection.await.unwrap().is_ok());
+        assert!(cached(&router, "/s1/0/2.m4s").await.is_err());
+    }
+}
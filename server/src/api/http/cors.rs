@@ -0,0 +1,157 @@
+use crate::config::Cors;
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Response, StatusCode};
+
+/// Methods served by the transmitter.
+const METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// Adds the CORS headers of the policy for the origin of a request to its response.
+pub fn apply(cors: &Cors, origin: Option<&HeaderValue>, response: &mut HeaderMap) {
+    if allow_origin(cors, origin, response) && !cors.exposed_headers.is_empty() {
+        response.insert(
+            header::ACCESS_CONTROL_EXPOSE_HEADERS,
+            list(&cors.exposed_headers),
+        );
+    }
+}
+
+/// Answers an `OPTIONS` request, a preflight of an allowed origin gets the methods and
+/// headers it may use.
+pub fn preflight<E>(cors: &Cors, request: &HeaderMap) -> Response<BoxBody<Bytes, E>> {
+    let mut response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(header::ALLOW, METHODS)
+        .body(BoxBody::default())
+        .unwrap();
+    let headers = response.headers_mut();
+    let preflight = request.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    if preflight && allow_origin(cors, request.get(header::ORIGIN), headers) {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_static(METHODS),
+        );
+        if !cors.allowed_headers.is_empty() {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                list(&cors.allowed_headers),
+            );
+        }
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, cors.max_age.into());
+    }
+    response
+}
+
+/// Adds the allowed origin and returns whether the origin of the request is allowed.
+fn allow_origin(cors: &Cors, origin: Option<&HeaderValue>, response: &mut HeaderMap) -> bool {
+    if cors.allowed_origins.iter().any(|origin| origin == "*") {
+        response.insert(
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            HeaderValue::from_static("*"),
+        );
+        return true;
+    }
+
+    // the answer depends on the origin for any cache in between
+    response.append(header::VARY, HeaderValue::from_static("Origin"));
+    let origin = match origin {
+        Some(origin) => origin,
+        None => return false,
+    };
+    let allowed = cors
+        .allowed_origins
+        .iter()
+        .any(|allowed| allowed.as_bytes().eq_ignore_ascii_case(origin.as_bytes()));
+    if !allowed {
+        return false;
+    }
+    response.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+    if cors.allow_credentials {
+        response.insert(
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+    true
+}
+
+fn list(values: &[String]) -> HeaderValue {
+    // validated with the setting
+    HeaderValue::from_str(&values.join(", ")).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+
+    fn origin(origin: &str) -> HeaderValue {
+        HeaderValue::from_str(origin).unwrap()
+    }
+
+    fn preflight_request(from: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ORIGIN, origin(from));
+        headers.insert(
+            header::ACCESS_CONTROL_REQUEST_METHOD,
+            HeaderValue::from_static("GET"),
+        );
+        headers
+    }
+
+    #[test]
+    fn any_origin() {
+        let cors = Cors::default();
+        let mut response = HeaderMap::new();
+        apply(
+            &cors,
+            Some(&origin("https://player.example")),
+            &mut response,
+        );
+        assert_eq!(response[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(
+            response[header::ACCESS_CONTROL_EXPOSE_HEADERS],
+            "Content-Range, Date, ETag"
+        );
+        assert!(!response.contains_key(header::VARY));
+
+        let preflight =
+            preflight::<Infallible>(&cors, &preflight_request("https://player.example"));
+        let headers = preflight.headers();
+        assert_eq!(preflight.status(), StatusCode::NO_CONTENT);
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS], "Range");
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "3600");
+    }
+
+    #[test]
+    fn credentialed_origins() {
+        let cors = Cors {
+            allowed_origins: vec!["https://player.example".to_string()],
+            allow_credentials: true,
+            ..Cors::default()
+        };
+        let mut response = HeaderMap::new();
+        apply(
+            &cors,
+            Some(&origin("https://player.example")),
+            &mut response,
+        );
+        assert_eq!(
+            response[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://player.example"
+        );
+        assert_eq!(response[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(response[header::VARY], "Origin");
+
+        let mut response = HeaderMap::new();
+        apply(&cors, Some(&origin("https://other.example")), &mut response);
+        assert!(!response.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert!(!response.contains_key(header::ACCESS_CONTROL_EXPOSE_HEADERS));
+
+        let preflight = preflight::<Infallible>(&cors, &preflight_request("https://other.example"));
+        assert!(!preflight
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_METHODS));
+    }
+}
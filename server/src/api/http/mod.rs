@@ -1,2 +1,4 @@
+pub mod cors;
 pub mod server;
 pub mod service;
+pub mod timeout;
@@ -1,128 +1,287 @@
-use crate::api::http::service::{IngesterService, TransmitterService};
-use crate::cache::Cache;
+use crate::api::http::cors;
+use crate::api::http::service::{
+    limit_response, AdminService, IngesterService, TransmitterService,
+};
+use crate::api::http::timeout::WriteTimeout;
+use crate::config::Setting;
 use crate::errors::ServerError;
-use crate::ingester::Ingester;
+use crate::health::Health;
+use crate::limits::ConnectionLimiter;
+use crate::live::LiveSetting;
+use crate::sendfile::Sendfile;
+use crate::websocket::Connection;
+use common::socket::{Address, Listener, SocketOptions};
+use common::systemd::Readiness;
+use hyper::body::Incoming;
+use hyper::header;
 use hyper::server::conn::http1;
-use hyper_util::rt::TokioIo;
+use hyper::service::service_fn;
+use hyper::Request;
+use hyper_util::rt::{TokioIo, TokioTimer};
+use std::convert::Infallible;
 use std::pin;
 use std::sync::Arc;
-use tokio::net::TcpListener;
-use tokio::sync::Notify;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinSet;
 use tracing::{error, info};
 
+/// Shutdown signal, readiness and grace period shared by the listeners.
+#[derive(Clone)]
+pub struct Lifecycle {
+    pub notifier: Arc<Notify>,
+    pub readiness: Readiness,
+    /// Time open connections get to finish after a shutdown.
+    pub grace_period: Duration,
+    /// Time the listeners keep accepting after the shutdown signal.
+    pub drain_delay: Duration,
+}
+
+impl Lifecycle {
+    /// Completes when the listeners stop accepting: `drain_delay` after the shutdown
+    /// signal, while `/readyz` fails and load balancers take the server out. After a
+    /// reload it completes right away, the new process accepts on the same sockets.
+    pub async fn stop_accepting(&self) {
+        self.notifier.notified().await;
+        if !common::systemd::handed_over() {
+            tokio::time::sleep(self.drain_delay).await;
+        }
+    }
+}
+
 pub async fn start_ingester(
-    notifier: Arc<Notify>,
+    lifecycle: Lifecycle,
     addr: String,
+    socket: SocketOptions,
+    core: Option<usize>,
     max_buffer_size: Option<usize>,
-    ingester: Arc<dyn Ingester + Send + Sync>,
+    header_timeout: Option<Duration>,
+    ingester_service: IngesterService,
 ) -> Result<(), ServerError> {
-    let addr = common::socket::parse_address(addr.clone())
-        .map_err(|e| ServerError::NetworkError(e.to_string()))?;
-    let socket = common::socket::listen_reuse_socket(&addr)
-        .map_err(|e| ServerError::NetworkError(e.to_string()))?;
-    let listener = TcpListener::from_std(socket.into())
-        .map_err(|e| ServerError::NetworkError(e.to_string()))?;
-
-    info!("ingester: listening on http://{}", addr);
+    let name: Arc<str> = listener_name("ingester", core).into();
+    let listener = listen(&name, addr, &socket, core.is_some())?;
+    lifecycle.readiness.listening();
 
     let mut custom_buffer = false;
     let mut http = http1::Builder::new();
     if let Some(max_buffer_size) = max_buffer_size {
         if max_buffer_size > 0 {
             custom_buffer = true;
-            info!("ingester: max buffer size is set to {}", max_buffer_size);
+            info!("{}: max buffer size is set to {}", name, max_buffer_size);
             http.max_buf_size(max_buffer_size);
         }
     }
 
     if !custom_buffer {
-        info!("ingester: max buffer size is default ~400KB");
+        info!("{}: max buffer size is default ~400KB", name);
     }
 
+    http.timer(TokioTimer::new());
+    http.header_read_timeout(header_timeout);
+
     let graceful = hyper_util::server::graceful::GracefulShutdown::new();
-    let mut signal = pin::pin!(notifier.notified());
-    let ingester_service = IngesterService::new(Arc::clone(&ingester));
+    let mut signal = pin::pin!(lifecycle.stop_accepting());
 
     loop {
         tokio::select! {
-            Ok((stream, _addr)) = listener.accept() => {
-                let service = ingester_service.clone();
+            Ok((stream, remote)) = listener.accept(&socket) => {
+                let service = ingester_service.with_remote(remote.map(|remote| remote.ip()));
                 let io = TokioIo::new(stream);
                 let conn = http.serve_connection(io, service);
                 let fut = graceful.watch(conn);
+                let name = Arc::clone(&name);
                 tokio::spawn(async move {
                     if let Err(e) = fut.await {
-                        error!("ingester: downstream: serve: {:?}", e);
+                        error!("{}: downstream: serve: {:?}", name, e);
                     }
                 });
             },
             _ = &mut signal => {
-                info!("ingester: http server: graceful shutdown");
+                info!("{}: http server: graceful shutdown", name);
                 break;
             }
         }
     }
+    close(&name, listener);
 
     tokio::select! {
         _ = graceful.shutdown() => {
-            info!("ingester: http server: all connections gracefully closed");
+            info!("{}: http server: all connections gracefully closed", name);
         },
-        // @todo make it configurable
-        _ = tokio::time::sleep(std::time::Duration::from_secs(30)) => {
-            info!("ingester: timed out wait for all connections to close");
+        _ = tokio::time::sleep(lifecycle.grace_period) => {
+            info!("{}: timed out wait for all connections to close", name);
         }
     }
     Ok(())
 }
 
 pub async fn start_transmitter(
-    notifier: Arc<Notify>,
-    addr: String,
+    lifecycle: Lifecycle,
+    core: Option<usize>,
     max_buffer_size: Option<usize>,
-    cache: Arc<dyn Cache + Send + Sync>,
+    transmitter_service: TransmitterService,
+    connections: Arc<ConnectionLimiter>,
+    setting: watch::Receiver<Arc<Setting>>,
 ) -> Result<(), ServerError> {
-    let addr = common::socket::parse_address(addr.clone())
-        .map_err(|e| ServerError::NetworkError(e.to_string()))?;
-    let socket = common::socket::listen_reuse_socket(&addr)
-        .map_err(|e| ServerError::NetworkError(e.to_string()))?;
-    let listener = TcpListener::from_std(socket.into())
-        .map_err(|e| ServerError::NetworkError(e.to_string()))?;
-
-    info!("transmitter: listening on http://{}", addr);
+    let name: Arc<str> = listener_name("transmitter", core).into();
+    let (addr, socket) = {
+        let setting = setting.borrow();
+        (
+            setting.transmitter.addr.clone(),
+            setting.transmitter.socket.clone(),
+        )
+    };
+    let listener = listen(&name, addr, &socket, core.is_some())?;
+    lifecycle.readiness.listening();
 
     let mut custom_buffer = false;
     let mut http = http1::Builder::new();
     if let Some(max_buffer_size) = max_buffer_size {
         if max_buffer_size > 0 {
             custom_buffer = true;
-            info!("transmitter: max buffer size is set to {}", max_buffer_size);
+            info!("{}: max buffer size is set to {}", name, max_buffer_size);
             http.max_buf_size(max_buffer_size);
         }
     }
 
     if !custom_buffer {
-        info!("transmitter: max buffer size is default ~400KB");
+        info!("{}: max buffer size is default ~400KB", name);
+    }
+
+    // rejected connections get a single response and are closed
+    let mut reject_http = http1::Builder::new();
+    reject_http.keep_alive(false);
+
+    // connections taken over by WebSocket subscribers can't be watched by
+    // `GracefulShutdown`, they are shut down on this signal instead and keep their
+    // permit until the subscriber leaves
+    let (shutdown, stopping) = watch::channel(false);
+    let mut served = JoinSet::new();
+    let mut signal = pin::pin!(lifecycle.stop_accepting());
+
+    loop {
+        tokio::select! {
+            Ok((stream, remote)) = listener.accept(&socket) => {
+                let remote = remote.map(|remote| remote.ip());
+                let (limits, write_timeout, sendfile, cors) = {
+                    let setting = setting.borrow();
+                    (
+                        setting.transmitter.limits.clone(),
+                        setting.transmitter.viewers.write_timeout(),
+                        setting.transmitter.sendfile,
+                        setting.transmitter.cors.clone(),
+                    )
+                };
+                let permit = connections.acquire(
+                    remote,
+                    limits.max_connections,
+                    limits.max_connections_per_ip,
+                );
+                let io = TokioIo::new(WriteTimeout::new(Sendfile::new(stream, sendfile), write_timeout));
+                match permit {
+                    Ok(permit) => {
+                        let (connection, mut released) = Connection::new(stopping.clone());
+                        let service = transmitter_service.with_connection(remote, connection);
+                        let conn = http.serve_connection(io, service).with_upgrades();
+                        let mut stopping = stopping.clone();
+                        let name = Arc::clone(&name);
+                        served.spawn(async move {
+                            let result = {
+                                let mut conn = pin::pin!(conn);
+                                tokio::select! {
+                                    result = conn.as_mut() => result,
+                                    _ = stopped(&mut stopping) => {
+                                        conn.as_mut().graceful_shutdown();
+                                        conn.await
+                                    }
+                                }
+                            };
+                            if let Err(e) = result {
+                                error!("{}: downstream: serve: {:?}", name, e);
+                            }
+                            // completes once a subscriber that took over the connection left
+                            let _ = released.recv().await;
+                            drop(permit);
+                        });
+                    }
+                    Err(rejection) => {
+                        let service = service_fn(move |req: Request<Incoming>| {
+                            let mut response = limit_response::<Infallible>(rejection, limits.retry_after);
+                            let origin = req.headers().get(header::ORIGIN);
+                            cors::apply(&cors, origin, response.headers_mut());
+                            async move { Ok::<_, Infallible>(response) }
+                        });
+                        let conn = reject_http.serve_connection(io, service);
+                        tokio::spawn(async move {
+                            let _ = tokio::time::timeout(Duration::from_secs(5), conn).await;
+                        });
+                    }
+                }
+            },
+            Some(_) = served.join_next() => {}
+            _ = &mut signal => {
+                info!("{}: http server: graceful shutdown", name);
+                break;
+            }
+        }
+    }
+    close(&name, listener);
+
+    shutdown.send_replace(true);
+    tokio::select! {
+        _ = async { while served.join_next().await.is_some() {} } => {
+            info!("{}: http server: all connections gracefully closed", name);
+        },
+        _ = tokio::time::sleep(lifecycle.grace_period) => {
+            info!("{}: timed out wait for all connections to close", name);
+        }
     }
+    Ok(())
+}
 
+/// Runs the admin server. On shutdown it keeps answering, with `/readyz` failing, until
+/// `stop` is notified once the other listeners are drained.
+pub async fn start_admin(
+    lifecycle: Lifecycle,
+    stop: Arc<Notify>,
+    addr: String,
+    socket: SocketOptions,
+    setting: Arc<LiveSetting>,
+    health: Arc<Health>,
+) -> Result<(), ServerError> {
+    let listener = listen("admin", addr, &socket, false)?;
+    lifecycle.readiness.listening();
+
+    let http = http1::Builder::new();
     let graceful = hyper_util::server::graceful::GracefulShutdown::new();
-    let mut signal = pin::pin!(notifier.notified());
-    let transmitter_service = Arc::new(TransmitterService::new(cache));
+    let mut signal = pin::pin!(lifecycle.notifier.notified());
+    let mut stop = pin::pin!(stop.notified());
+    let admin_service = AdminService::new(setting, Arc::clone(&health));
 
     loop {
         tokio::select! {
-            Ok((stream, _addr)) = listener.accept() => {
-                let service = Arc::clone(&transmitter_service);
+            Ok((stream, _remote)) = listener.accept(&socket) => {
+                let service = admin_service.clone();
                 let io = TokioIo::new(stream);
                 let conn = http.serve_connection(io, service);
                 let fut = graceful.watch(conn);
                 tokio::spawn(async move {
                     if let Err(e) = fut.await {
-                        error!("transmitter: downstream: serve: {:?}", e);
+                        error!("admin: serve: {:?}", e);
                     }
                 });
             },
-            _ = &mut signal => {
-                info!("transmitter: http server: graceful shutdown");
+            _ = &mut signal, if !health.draining() => {
+                // after a reload the new process answers the probes on the same socket
+                if common::systemd::handed_over() {
+                    info!("admin: http server: graceful shutdown");
+                    break;
+                }
+                info!("admin: draining, not ready");
+                health.drain();
+            }
+            _ = &mut stop => {
+                info!("admin: http server: graceful shutdown");
                 break;
             }
         }
@@ -130,12 +289,57 @@ pub async fn start_transmitter(
 
     tokio::select! {
         _ = graceful.shutdown() => {
-            info!("transmitter: http server: all connections gracefully closed");
+            info!("admin: http server: all connections gracefully closed");
         },
-        // @todo make it configurable
-        _ = tokio::time::sleep(std::time::Duration::from_secs(30)) => {
-            info!("transmitter: timed out wait for all connections to close");
+        _ = tokio::time::sleep(std::time::Duration::from_secs(5)) => {
+            info!("admin: timed out wait for all connections to close");
         }
     }
     Ok(())
 }
+
+/// Closes a listener that stopped accepting, connections queued for it are refused
+/// instead of waiting for an accept that never comes.
+pub fn close(name: &str, listener: Listener) {
+    drop(listener);
+    common::listenfd::release(name);
+}
+
+/// Completes once the shutdown of the listener is signalled.
+async fn stopped(stopping: &mut watch::Receiver<bool>) {
+    let _ = stopping.wait_for(|stop| *stop).await;
+}
+
+/// Name of a listener, suffixed by the core index in thread per core mode.
+pub fn listener_name(listener: &str, core: Option<usize>) -> String {
+    match core {
+        Some(core) => format!("{}-{}", listener, core),
+        None => listener.to_string(),
+    }
+}
+
+/// Binds the listener or takes it over from systemd or the previous process. Listeners
+/// of the cores share the address with `SO_REUSEPORT`.
+pub fn listen(
+    name: &str,
+    addr: String,
+    socket: &SocketOptions,
+    reuse_port: bool,
+) -> Result<Listener, ServerError> {
+    let addr = common::socket::parse_address(addr)
+        .map_err(|e| ServerError::NetworkError(e.to_string()))?;
+    let listener = if reuse_port {
+        common::listenfd::reuse_port_listener(name, &addr, socket)
+    } else {
+        common::listenfd::listener(name, &addr, socket)
+    };
+    let listener = listener
+        .and_then(Listener::from_socket)
+        .map_err(|e| ServerError::NetworkError(format!("{}: {}", addr, e)))?;
+
+    match addr {
+        Address::Tcp(addr) => info!("{}: listening on http://{}", name, addr),
+        Address::Unix(_) => info!("{}: listening on {}", name, addr),
+    }
+    Ok(listener)
+}
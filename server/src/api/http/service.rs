@@ -1,36 +1,161 @@
-use crate::cache::Cache;
-use crate::ingester::Ingester;
+use crate::access_log::{AccessLog, Entry, Logged};
+use crate::api::http::cors;
+use crate::cache::manifest::{is_manifest, Encoding, Manifest};
+use crate::clock;
+use crate::config::Setting;
+use crate::errors::ServerError;
+use crate::events::{self, Events, Feed};
+use crate::health::Health;
+use crate::ingester::UploadBody;
+use crate::limits::{Guarded, RateLimiter, Rejection, ViewerLimiter};
+use crate::live::LiveSetting;
+use crate::metrics::{self, Counted};
+use crate::replication::Replication;
+use crate::tenant::Router;
+use crate::webhooks::Webhooks;
+use crate::websocket::{self, Subscriber};
 use bytes::Bytes;
 use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
 use hyper::body::Incoming;
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::http::response;
 use hyper::service::Service;
-use hyper::{Request, Response, StatusCode};
+use hyper::{Method, Request, Response, StatusCode};
+use std::collections::BTreeMap;
 use std::convert::Infallible;
 use std::future::Future;
+use std::net::IpAddr;
 use std::pin::Pin;
 use std::sync::Arc;
-use tracing::error;
-
-const COMMON_HEADERS: [(&str, &str); 1] = [("Access-Control-Allow-Origin", "*")];
-const MEDIA_HEADERS: [(&str, &str); 1] = [("Content-Type", "video/mp4")];
+use std::time::Instant;
+use tokio::sync::watch;
+use tracing::field::Empty;
+use tracing::{error, info, info_span, Instrument, Span};
 
 #[derive(Clone)]
 pub struct IngesterService {
-    ingester: Arc<dyn Ingester + Send + Sync>,
+    router: Arc<Router>,
+    access_log: Option<Arc<AccessLog>>,
+    events: Option<Arc<Events>>,
+    webhooks: Option<Arc<Webhooks>>,
+    replication: Option<Arc<Replication>>,
+    remote: Option<IpAddr>,
 }
 
 impl IngesterService {
-    pub fn new(ingester: Arc<dyn Ingester + Send + Sync>) -> Self {
-        IngesterService { ingester }
+    pub fn new(
+        router: Arc<Router>,
+        access_log: Option<Arc<AccessLog>>,
+        events: Option<Arc<Events>>,
+        webhooks: Option<Arc<Webhooks>>,
+        replication: Option<Arc<Replication>>,
+    ) -> Self {
+        IngesterService {
+            router,
+            access_log,
+            events,
+            webhooks,
+            replication,
+            remote: None,
+        }
+    }
+
+    /// Returns the service for a connection from the given address.
+    pub fn with_remote(&self, remote: Option<IpAddr>) -> Self {
+        let mut service = self.clone();
+        service.remote = remote;
+        service
     }
 
+    /// Returns the response together with the number of bytes received.
     async fn handle(
         &self,
-        req: Request<Incoming>,
-    ) -> Result<Response<BoxBody<Bytes, Infallible>>, Infallible> {
-        self.ingester.ingest(req).await;
+        mut req: Request<UploadBody>,
+    ) -> (Response<BoxBody<Bytes, Infallible>>, u64) {
+        let tenant = match self.router.route(&req) {
+            Some(tenant) => Arc::clone(tenant),
+            None => return (empty_response(StatusCode::NOT_FOUND), 0),
+        };
 
-        Ok(empty_response(StatusCode::OK))
+        tenant.metrics.ingest_requests.inc();
+        let path = req.uri().path().to_string();
+        let span = Span::current();
+        span.record("tenant", tenant.name.as_str());
+        span.record("stream", tenant.stream(&path));
+        let stream = tenant.stream(&path);
+        // the server the copy comes from already asked for the stream
+        let replica = self
+            .replication
+            .as_ref()
+            .is_some_and(|r| r.is_replica(&req, self.remote));
+        if let Some(webhooks) = &self.webhooks {
+            if !replica && !tenant.streams.is_active(stream) {
+                let allowed = webhooks
+                    .allow_publish(&tenant.name, stream, &path, self.remote)
+                    .await;
+                if let Err(e) = allowed {
+                    tenant.metrics.ingest_errors.inc();
+                    span.record("error", e.as_str());
+                    error!("ingest: tenant {}: {}: {}", tenant.name, path, e);
+                    let mut response = empty_response(StatusCode::FORBIDDEN);
+                    response
+                        .headers_mut()
+                        .insert(header::CONNECTION, HeaderValue::from_static("close"));
+                    return (response, 0);
+                }
+            }
+        }
+        // the ingester passes the body on to the copies while it reads it
+        if let Some(tee) = self.replication.as_ref().and_then(|r| r.tee(&req)) {
+            req.extensions_mut().insert(tee);
+        }
+        let started = Instant::now();
+        let result = tenant.shard(&path).ingester.ingest(req).await;
+        if let Err(e) = result {
+            tenant.metrics.ingest_errors.inc();
+            span.record("error", e.to_string());
+            error!("ingest: tenant {}: {}: {}", tenant.name, path, e);
+            if let Some(events) = &self.events {
+                let (stream, within) = tenant.split(&path);
+                events.ingest_failed(&tenant.name, stream, within, &path, e.to_string());
+            }
+            let status = match e {
+                ServerError::QuotaError(_) => StatusCode::TOO_MANY_REQUESTS,
+                ServerError::RequestError(_) => StatusCode::BAD_REQUEST,
+                ServerError::SizeError(_) => StatusCode::PAYLOAD_TOO_LARGE,
+                ServerError::StalledError(_) => {
+                    tenant.metrics.ingest_stalled.inc();
+                    StatusCode::REQUEST_TIMEOUT
+                }
+                ServerError::TimeoutError(_) => {
+                    tenant.metrics.ingest_too_long.inc();
+                    StatusCode::REQUEST_TIMEOUT
+                }
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+
+            // the rest of the body is not read, drop the connection after the response
+            let mut response = empty_response(status);
+            response
+                .headers_mut()
+                .insert(header::CONNECTION, HeaderValue::from_static("close"));
+            return (response, 0);
+        }
+
+        let received = result.unwrap_or_default();
+        if let Some(events) = &self.events {
+            let (stream, within) = tenant.split(&path);
+            events.ingest_completed(
+                &tenant.name,
+                stream,
+                within,
+                &path,
+                received,
+                started.elapsed(),
+            );
+        }
+        (empty_response(StatusCode::OK), received)
     }
 }
 
@@ -40,50 +165,406 @@ impl Service<Request<Incoming>> for IngesterService {
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn call(&self, req: Request<Incoming>) -> Self::Future {
+        let req = req.map(|body| {
+            body.map_err(|e| ServerError::NetworkError(format!("read body: {}", e)))
+                .boxed()
+        });
+        Service::<Request<UploadBody>>::call(self, req)
+    }
+}
+
+/// Uploads of the framed protocol, handled like the ones received over HTTP.
+impl Service<Request<UploadBody>> for IngesterService {
+    type Response = Response<BoxBody<Bytes, Infallible>>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, req: Request<UploadBody>) -> Self::Future {
         let this = self.clone();
-        Box::pin(async move { this.handle(req).await })
+        let span = info_span!(
+            "ingest",
+            otel.kind = "server",
+            traceparent = traceparent(&req),
+            path = req.uri().path(),
+            tenant = Empty,
+            stream = Empty,
+            status = Empty,
+            bytes = Empty,
+            error = Empty,
+        );
+        Box::pin(
+            async move {
+                let entry = this
+                    .access_log
+                    .as_ref()
+                    .map(|_| Entry::new(&req, this.remote));
+                let (response, received) = this.handle(req).await;
+                let span = Span::current();
+                span.record("status", response.status().as_u16());
+                span.record("bytes", received);
+                if let (Some(access_log), Some(mut entry)) = (&this.access_log, entry) {
+                    entry.status = response.status().as_u16();
+                    entry.bytes = received;
+                    entry.duration = entry.elapsed();
+                    entry.first_byte = Some(entry.duration);
+                    entry.completed = true;
+                    access_log.log(&entry);
+                }
+                Ok(response)
+            }
+            .instrument(span),
+        )
     }
 }
 
 #[derive(Clone)]
 pub struct TransmitterService {
-    cache: Arc<dyn Cache + Send + Sync>,
+    router: Arc<Router>,
+    setting: watch::Receiver<Arc<Setting>>,
+    rate: Arc<RateLimiter>,
+    viewers: Arc<ViewerLimiter>,
+    access_log: Option<Arc<AccessLog>>,
+    events: Option<Arc<Events>>,
+    remote: Option<IpAddr>,
+    /// Connection the service answers on, taken over by WebSocket subscribers.
+    connection: Option<websocket::Connection>,
 }
 
 impl TransmitterService {
-    pub fn new(cache: Arc<dyn Cache + Send + Sync>) -> Self {
-        TransmitterService { cache }
+    pub fn new(
+        router: Arc<Router>,
+        setting: watch::Receiver<Arc<Setting>>,
+        access_log: Option<Arc<AccessLog>>,
+        events: Option<Arc<Events>>,
+    ) -> Self {
+        TransmitterService {
+            router,
+            setting,
+            rate: Arc::new(RateLimiter::new("transmitter")),
+            viewers: Arc::new(ViewerLimiter::new("transmitter")),
+            access_log,
+            events,
+            remote: None,
+            connection: None,
+        }
+    }
+
+    /// Returns the service for a connection from the given address.
+    pub fn with_connection(
+        &self,
+        remote: Option<IpAddr>,
+        connection: websocket::Connection,
+    ) -> Self {
+        let mut service = self.clone();
+        service.remote = remote;
+        service.connection = Some(connection);
+        service
     }
 
     async fn handle(
         &self,
         req: Request<Incoming>,
-    ) -> Result<Response<BoxBody<Bytes, Infallible>>, Infallible> {
+    ) -> Result<Response<BoxBody<Bytes, ServerError>>, Infallible> {
+        if req.method() == Method::OPTIONS {
+            let setting = self.setting.borrow();
+            return Ok(cors::preflight(&setting.transmitter.cors, req.headers()));
+        }
+        let (limits, lag, websocket) = {
+            let setting = self.setting.borrow();
+            (
+                setting.transmitter.limits.clone(),
+                setting.transmitter.viewers.lag(),
+                setting.transmitter.websocket.clone(),
+            )
+        };
+        if let (Some(remote), Some(rate)) = (self.remote, limits.requests_per_second) {
+            let burst = limits.burst.unwrap_or(rate).max(1.0);
+            if !self.rate.check(remote, rate, burst) {
+                return Ok(limit_response(
+                    Rejection::TooManyRequests,
+                    limits.retry_after,
+                ));
+            }
+        }
+
+        if req.uri().path() == clock::PATH {
+            return Ok(clock::response());
+        }
+
+        let tenant = match self.router.route(&req) {
+            Some(tenant) => tenant,
+            None => return Ok(empty_response(StatusCode::NOT_FOUND)),
+        };
+
+        tenant.metrics.delivery_requests.inc();
         let path = req.uri().path();
-        let res = self.cache.get(path).await;
+        let span = Span::current();
+        span.record("tenant", tenant.name.as_str());
+        span.record("stream", tenant.stream(path));
+        if let Some(events) = &self.events {
+            match tenant.split(path) {
+                (events::PATH, "") => return Ok(feed_response(events.feed(&tenant.name, None))),
+                (stream, events::PATH) => {
+                    return Ok(feed_response(events.feed(&tenant.name, Some(stream))))
+                }
+                _ => {}
+            }
+        }
+        // a slot is only taken once there is something to view
+        let stream = format!("{}/{}", tenant.name, tenant.stream(path));
+        let acquire = || {
+            self.viewers
+                .acquire(stream.clone(), limits.max_viewers_per_stream)
+        };
+        let overloaded = || Ok(limit_response(Rejection::Overloaded, limits.retry_after));
+
+        let (name, within) = tenant.split(path);
+        let representation = within
+            .strip_suffix(websocket::PATH)
+            .and_then(|representation| representation.strip_suffix('/'));
+        if let (Some(representation), Some(websocket), Some(connection)) =
+            (representation, websocket, &self.connection)
+        {
+            let accept = match websocket::accept_key(&req) {
+                Some(accept) => accept,
+                None => return Ok(empty_response(StatusCode::BAD_REQUEST)),
+            };
+            let viewer = match acquire() {
+                Some(viewer) => viewer,
+                None => return overloaded(),
+            };
+            let subscriber = Subscriber::new(
+                Arc::clone(tenant),
+                name,
+                representation,
+                lag,
+                websocket.max_lag_segments,
+            );
+            let guard = (
+                viewer,
+                tenant.metrics.viewers.hold(),
+                tenant.streams.view(path),
+            );
+            subscriber.subscribe(req, guard, connection.clone());
+
+            let mut response = empty_response(StatusCode::SWITCHING_PROTOCOLS);
+            let headers = response.headers_mut();
+            headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+            headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+            headers.insert(header::SEC_WEBSOCKET_ACCEPT, accept);
+            return Ok(response);
+        }
+
+        if is_manifest(path) {
+            if let Some(manifest) = tenant.shard(path).cache.manifest(path).await {
+                let viewer = match acquire() {
+                    Some(viewer) => viewer,
+                    None => return overloaded(),
+                };
+                let guard = (
+                    viewer,
+                    tenant.metrics.viewers.hold(),
+                    tenant.streams.view(path),
+                );
+                let response = self.manifest_response(&req, &manifest).map(|data| {
+                    let body = Full::new(data).map_err(|never| match never {});
+                    let body = Counted::new(body, tenant.metrics.delivered_bytes.clone());
+                    BoxBody::new(Guarded::new(body, guard))
+                });
+                return Ok(response);
+            }
+        }
+
+        let lookup = info_span!("cache.get", key = path, found = Empty, error = Empty);
+        let res = tenant
+            .shard(path)
+            .cache
+            .get(path, &lag)
+            .instrument(lookup.clone())
+            .await;
         if let Err(e) = res {
+            lookup.record("error", e.to_string());
             error!("cache: {}", e);
             return Ok(empty_response(StatusCode::INTERNAL_SERVER_ERROR));
         }
 
         let body = res.unwrap();
+        lookup.record("found", body.is_some());
         if body.is_none() {
+            tenant.metrics.delivery_not_found.inc();
             return Ok(empty_response(StatusCode::NOT_FOUND));
         }
         let body = body.unwrap();
+        let viewer = match acquire() {
+            Some(viewer) => viewer,
+            None => return overloaded(),
+        };
 
-        let mut response = Response::builder().status(StatusCode::OK);
-        for header in COMMON_HEADERS {
-            response = response.header(header.0, header.1);
-        }
-        for header in MEDIA_HEADERS {
-            response = response.header(header.0, header.1);
+        let response = self.media_response(path, StatusCode::OK);
+        let body = Counted::new(body, tenant.metrics.delivered_bytes.clone());
+        let guard = (
+            viewer,
+            tenant.metrics.viewers.hold(),
+            tenant.streams.view(path),
+        );
+        let body = BoxBody::new(Guarded::new(body, guard));
+        let mut response = response.body(body).unwrap();
+        self.configured_headers(&mut response);
+        Ok(response)
+    }
+
+    /// Returns the response builder of a key with its content type.
+    fn media_response(&self, path: &str, status: StatusCode) -> response::Builder {
+        Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, content_type(path))
+    }
+
+    /// Sets the configured headers, they replace the built-in ones of the same name.
+    fn configured_headers<T>(&self, response: &mut Response<T>) {
+        let setting = self.setting.borrow();
+        replace_headers(&setting.transmitter.headers, response.headers_mut());
+    }
+
+    /// Answers with the version of the manifest in the encoding the viewer accepts, or
+    /// with 304 when the viewer has it already.
+    fn manifest_response<B>(&self, req: &Request<B>, manifest: &Manifest) -> Response<Bytes> {
+        let path = req.uri().path();
+        let headers = req.headers();
+        let (encoding, data) = manifest.body(Encoding::negotiate(headers));
+        let (status, data) = if manifest.not_modified(headers) {
+            (StatusCode::NOT_MODIFIED, Bytes::new())
+        } else {
+            (StatusCode::OK, data)
+        };
+
+        let mut response = self
+            .media_response(path, status)
+            .header(header::ETAG, manifest.etag(encoding))
+            .header(header::LAST_MODIFIED, manifest.last_modified())
+            .header(header::VARY, "Accept-Encoding");
+        if let Some(name) = encoding.name() {
+            response = response.header(header::CONTENT_ENCODING, name);
         }
-        Ok(response.body(body).unwrap())
+        let mut response = response.body(data).unwrap();
+        self.configured_headers(&mut response);
+        response
     }
 }
 
 impl Service<Request<Incoming>> for TransmitterService {
+    type Response = Response<BoxBody<Bytes, ServerError>>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, req: Request<Incoming>) -> Self::Future {
+        let this = self.clone();
+        let span = info_span!(
+            "deliver",
+            otel.kind = "server",
+            traceparent = traceparent(&req),
+            path = req.uri().path(),
+            tenant = Empty,
+            stream = Empty,
+            status = Empty,
+        );
+        Box::pin(
+            async move {
+                let entry = this
+                    .access_log
+                    .as_ref()
+                    .map(|_| Entry::new(&req, this.remote));
+                let preflight = req.method() == Method::OPTIONS;
+                let origin = req.headers().get(header::ORIGIN).cloned();
+                let mut response = this.handle(req).await?;
+                if !preflight {
+                    let setting = this.setting.borrow();
+                    cors::apply(
+                        &setting.transmitter.cors,
+                        origin.as_ref(),
+                        response.headers_mut(),
+                    );
+                }
+                let span = Span::current();
+                span.record("status", response.status().as_u16());
+
+                // the span lasts until the body is sent or the viewer leaves
+                let response = response.map(|body| BoxBody::new(Guarded::new(body, span)));
+                match (&this.access_log, entry) {
+                    (Some(access_log), Some(mut entry)) => {
+                        entry.status = response.status().as_u16();
+                        let access_log = Arc::clone(access_log);
+                        Ok(response.map(|body| BoxBody::new(Logged::new(body, entry, access_log))))
+                    }
+                    _ => Ok(response),
+                }
+            }
+            .instrument(span),
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct AdminService {
+    setting: Arc<LiveSetting>,
+    health: Arc<Health>,
+}
+
+impl AdminService {
+    pub fn new(setting: Arc<LiveSetting>, health: Arc<Health>) -> Self {
+        AdminService { setting, health }
+    }
+
+    async fn handle(
+        &self,
+        req: Request<Incoming>,
+    ) -> Result<Response<BoxBody<Bytes, Infallible>>, Infallible> {
+        match (req.method(), req.uri().path()) {
+            (&Method::POST, "/config/reload") => Ok(self.reload_config()),
+            (&Method::GET, "/metrics") => Ok(text_response(StatusCode::OK, metrics::render())),
+            (&Method::GET, "/healthz") => Ok(text_response(StatusCode::OK, "ok\n".to_string())),
+            (&Method::GET, "/readyz") => Ok(self.ready()),
+            _ => Ok(empty_response(StatusCode::NOT_FOUND)),
+        }
+    }
+
+    fn ready(&self) -> Response<BoxBody<Bytes, Infallible>> {
+        match self.health.not_ready() {
+            Some(reason) => text_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("not ready: {}\n", reason),
+            ),
+            None => text_response(StatusCode::OK, "ready\n".to_string()),
+        }
+    }
+
+    fn reload_config(&self) -> Response<BoxBody<Bytes, Infallible>> {
+        match self.setting.reload() {
+            Ok(changes) => {
+                info!(
+                    "config reload: applied: {:?}, restart required: {:?}",
+                    changes.applied, changes.restart
+                );
+                let body = format!(
+                    "applied: {}\nrestart required: {}\n",
+                    changes.applied.join(", "),
+                    changes.restart.join(", ")
+                );
+                text_response(StatusCode::OK, body)
+            }
+            Err(e @ ServerError::ConfigError(_)) => {
+                error!("config reload: {}", e);
+                text_response(StatusCode::BAD_REQUEST, format!("{}\n", e))
+            }
+            Err(e) => {
+                error!("config reload: {}", e);
+                text_response(StatusCode::INTERNAL_SERVER_ERROR, format!("{}\n", e))
+            }
+        }
+    }
+}
+
+impl Service<Request<Incoming>> for AdminService {
     type Response = Response<BoxBody<Bytes, Infallible>>;
     type Error = Infallible;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
@@ -94,11 +575,148 @@ impl Service<Request<Incoming>> for TransmitterService {
     }
 }
 
-fn empty_response(status: StatusCode) -> Response<BoxBody<Bytes, Infallible>> {
-    let mut response = Response::builder().status(status);
-    for header in COMMON_HEADERS {
-        response = response.header(header.0, header.1);
+/// Trace context of the caller, the request span joins its trace.
+fn traceparent<B>(req: &Request<B>) -> Option<&str> {
+    req.headers()
+        .get(common::otlp::TRACEPARENT)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Response to a request or connection rejected by a limit.
+pub fn limit_response<E>(rejection: Rejection, retry_after: u64) -> Response<BoxBody<Bytes, E>> {
+    let status = match rejection {
+        Rejection::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+        Rejection::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+    };
+
+    Response::builder()
+        .status(status)
+        .header("Retry-After", retry_after)
+        .body(BoxBody::default())
+        .unwrap()
+}
+
+fn feed_response(feed: Feed) -> Response<BoxBody<Bytes, ServerError>> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(BoxBody::new(feed))
+        .unwrap()
+}
+
+fn text_response(status: StatusCode, body: String) -> Response<BoxBody<Bytes, Infallible>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(BoxBody::new(Full::new(Bytes::from(body))))
+        .unwrap()
+}
+
+fn replace_headers(configured: &BTreeMap<String, String>, headers: &mut HeaderMap) {
+    for (name, value) in configured.iter() {
+        // validated with the setting
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+fn content_type(path: &str) -> &'static str {
+    if path.ends_with(".mpd") {
+        "application/dash+xml"
+    } else if path.ends_with(".m3u8") {
+        "application/vnd.apple.mpegurl"
+    } else {
+        "video/mp4"
+    }
+}
+
+fn empty_response<E>(status: StatusCode) -> Response<BoxBody<Bytes, E>> {
+    Response::builder()
+        .status(status)
+        .body(BoxBody::default())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tenant::Tenant;
+    use hyper::server::conn::http1;
+    use hyper_util::rt::TokioIo;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn configured_headers_replace_built_in() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("video/mp4"));
+        let configured = BTreeMap::from([
+            ("Content-Type".to_string(), "video/iso.segment".to_string()),
+            ("Cache-Control".to_string(), "max-age=2".to_string()),
+        ]);
+        replace_headers(&configured, &mut headers);
+        assert_eq!(headers.get_all(header::CONTENT_TYPE).iter().count(), 1);
+        assert_eq!(headers[header::CONTENT_TYPE], "video/iso.segment");
+        assert_eq!(headers[header::CACHE_CONTROL], "max-age=2");
+    }
+
+    async fn get(addr: std::net::SocketAddr, headers: &str) -> String {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET /s1/index.mpd HTTP/1.1\r\nHost: t\r\nConnection: close\r\n{}\r\n",
+            headers
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        String::from_utf8(response).unwrap()
     }
 
-    response.body(BoxBody::default()).unwrap()
+    #[tokio::test]
+    async fn manifest_not_modified_after_its_cell_expired() {
+        let mut setting = Tenant::test_setting("list:copy");
+        setting.tenant[0].retention = 0;
+        let tenant = Tenant::for_test_with(&setting, 1, None).await;
+        let body = Full::new(Bytes::from_static(b"<MPD/>"))
+            .map_err(|never| match never {})
+            .boxed();
+        let req = Request::put("/s1/index.mpd").body(body).unwrap();
+        let shard = tenant.shard("/s1/index.mpd");
+        shard.ingester.ingest(req).await.unwrap();
+        let cached = shard.cache.get("/s1/index.mpd", &Default::default()).await;
+        assert!(cached.unwrap().is_none());
+
+        let router = Arc::new(Router::new(vec![Arc::new(tenant)]));
+        let (_setting, setting) = watch::channel(Arc::new(setting));
+        let service = TransmitterService::new(router, setting, None, None);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (connection, _released) = websocket::Connection::new(watch::channel(false).1);
+                let service = service.with_connection(None, connection);
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        let response = get(addr, "").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("<MPD/>"), "{}", response);
+        let etag = response
+            .lines()
+            .find_map(|line| line.strip_prefix("etag: "))
+            .unwrap();
+        let response = get(addr, &format!("If-None-Match: {}\r\n", etag)).await;
+        assert!(
+            response.starts_with("HTTP/1.1 304 Not Modified"),
+            "{}",
+            response
+        );
+    }
 }
@@ -1 +1,2 @@
+pub mod framed;
 pub mod http;
@@ -1,5 +1,7 @@
-use crate::cache::Cache;
+use crate::cache::manifest::{Manifest, Manifests};
+use crate::cache::{Cache, LagAction, LagPolicy};
 use crate::errors::ServerError;
+use crate::metrics;
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures_util::Stream;
@@ -7,19 +9,21 @@ use http_body_util::combinators::BoxBody;
 use http_body_util::StreamBody;
 use hyper::body::Frame;
 use std::collections::HashMap;
-use std::convert::Infallible;
 use std::fmt::Debug;
 use std::pin::Pin;
 use std::ptr;
-use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll, Waker};
+use std::time::Duration;
 use tokio::sync::{Mutex, Notify};
+use tokio::time::Instant;
 
 #[derive(Debug, Clone)]
 pub struct ListCache {
     pub copy_before_insert: bool,
     map: Arc<Mutex<HashMap<String, Arc<Cell>>>>,
+    pub manifests: Arc<Manifests>,
 }
 
 impl ListCache {
@@ -28,31 +32,38 @@ impl ListCache {
         ListCache {
             map,
             copy_before_insert,
+            manifests: Arc::new(Manifests::default()),
         }
     }
 
+    /// Creates an empty cell for the key. A cell left by a previous upload of the same key
+    /// is replaced, viewers already reading it keep their reference.
     pub async fn cell(&self, key: &str) -> Arc<Cell> {
         let mut locked_map = self.map.lock().await;
-        let cell = locked_map.get(key);
-        if let Some(cell) = cell {
-            return Arc::clone(cell);
-        }
-
         let cell = Arc::new(Cell::new());
         locked_map.insert(key.to_string(), Arc::clone(&cell));
 
         cell
     }
 
-    pub async fn remove(&self, key: &str) {
+    /// Removes the key if it still points to the given cell.
+    pub async fn remove(&self, key: &str, cell: &Arc<Cell>) {
         let mut locked_map = self.map.lock().await;
-        locked_map.remove(key);
+        if let Some(current) = locked_map.get(key) {
+            if Arc::ptr_eq(current, cell) {
+                locked_map.remove(key);
+            }
+        }
     }
 }
 
 #[async_trait]
 impl Cache for ListCache {
-    async fn get(&self, key: &str) -> Result<Option<BoxBody<Bytes, Infallible>>, ServerError> {
+    async fn get(
+        &self,
+        key: &str,
+        lag: &LagPolicy,
+    ) -> Result<Option<BoxBody<Bytes, ServerError>>, ServerError> {
         let locked_map = self.map.lock().await;
         let data = locked_map.get(key);
         if data.is_none() {
@@ -60,16 +71,21 @@ impl Cache for ListCache {
         }
 
         let data = data.unwrap();
-        let downstream = ListDownstream::new(Arc::clone(&data));
+        let downstream = ListDownstream::new(Arc::clone(data), lag.clone());
         let body = StreamBody::new(downstream);
         Ok(Some(BoxBody::new(body)))
     }
+
+    async fn manifest(&self, key: &str) -> Option<Arc<Manifest>> {
+        self.manifests.get(key)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Cell {
     notifier: Arc<Notify>,
     data: Arc<LinkedList>,
+    aborted: Arc<AtomicBool>,
 }
 
 impl Cell {
@@ -77,6 +93,7 @@ impl Cell {
         Cell {
             data: Arc::new(LinkedList::new()),
             notifier: Arc::new(Notify::new()),
+            aborted: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -84,11 +101,32 @@ impl Cell {
         self.data.tail()
     }
 
+    /// Returns the newest node.
+    pub fn head(&self) -> Option<Arc<Node>> {
+        self.data.head()
+    }
+
+    /// Returns the number of bytes appended so far.
+    pub fn written(&self) -> u64 {
+        self.data.written()
+    }
+
     pub fn append(&self, data: Option<Bytes>) {
         self.data.insert(data);
         self.notifier.notify_waiters();
     }
 
+    /// Closes the cell of an upload that failed, viewers get an error instead of
+    /// the end of the body.
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::Release);
+        self.append(None);
+    }
+
+    pub fn aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+
     pub fn notifier(&self) -> Arc<Notify> {
         self.notifier.clone()
     }
@@ -104,19 +142,54 @@ struct ListDownstream {
     cell: Arc<Cell>,
     notifier: Arc<Notify>,
     cursor: Option<Arc<Node>>,
+    lag: LagPolicy,
+    /// Bytes written and the delay behind the writer when the reader joined,
+    /// a reader joining a segment late starts behind without lagging.
+    joined_written: u64,
+    joined_delay: Duration,
 }
 
 impl ListDownstream {
-    pub fn new(data: Arc<Cell>) -> Self {
+    pub fn new(data: Arc<Cell>, lag: LagPolicy) -> Self {
         let notifier = data.notifier();
+        let joined_written = data.written();
+        let joined_delay = data
+            .tail()
+            .map(|tail| tail.appended.elapsed())
+            .unwrap_or_default();
         ListDownstream {
             notifier,
             cell: data,
             cursor: None,
+            lag,
+            joined_written,
+            joined_delay,
+        }
+    }
+
+    /// Returns the reason if the reader fell too far behind the writer to send the node.
+    /// Readers of a closed cell are never lagging, the writer is gone.
+    fn lagging(&self, node: &Node) -> Option<&'static str> {
+        let head = self.cell.head()?;
+        head.value.as_ref()?;
+
+        if let Some(max_bytes) = self.lag.max_bytes {
+            let behind = self.cell.written() - self.joined_written;
+            if behind.saturating_sub(node.offset) > max_bytes {
+                return Some("lag_bytes");
+            }
+        }
+        if let Some(max_time) = self.lag.max_time {
+            let delay = node.appended.elapsed();
+            if delay.saturating_sub(self.joined_delay) > max_time {
+                return Some("lag_time");
+            }
         }
+
+        None
     }
 
-    fn wait(&self, waker: Waker) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+    fn wait(&self, waker: Waker) -> Poll<Option<Result<Frame<Bytes>, ServerError>>> {
         let notifier = Arc::clone(&self.notifier);
         tokio::task::spawn(async move {
             notifier.notified().await;
@@ -128,7 +201,7 @@ impl ListDownstream {
 }
 
 impl Stream for ListDownstream {
-    type Item = Result<Frame<Bytes>, Infallible>;
+    type Item = Result<Frame<Bytes>, ServerError>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let next: Option<Arc<Node>>;
@@ -142,29 +215,74 @@ impl Stream for ListDownstream {
             return self.wait(cx.waker().clone());
         }
 
-        let node = next.unwrap();
+        let mut node = next.unwrap();
+        if let Some(reason) = self.lagging(&node) {
+            match self.lag.action {
+                LagAction::Cut => {
+                    viewers_cut(reason).inc();
+                    return Poll::Ready(Some(Err(ServerError::LagError(format!(
+                        "viewer cut: {}",
+                        reason
+                    )))));
+                }
+                // the chunks in between are dropped, the viewer goes on from the newest one
+                LagAction::Skip => {
+                    viewers_skipped().inc();
+                    if let Some(head) = self.cell.head() {
+                        node = head;
+                    }
+                }
+            }
+        }
+
         self.cursor = Some(Arc::clone(&node)); // move cursor
 
         if let Some(data) = &node.value {
             let frame = Frame::data(data.clone());
             Poll::Ready(Some(Ok(frame)))
+        } else if self.cell.aborted() {
+            Poll::Ready(Some(Err(ServerError::StorageError(
+                "upload aborted".to_string(),
+            ))))
         } else {
             Poll::Ready(None)
         }
     }
 }
 
+/// Counter of viewers whose response was terminated, by reason.
+pub fn viewers_cut(reason: &str) -> metrics::Counter {
+    metrics::counter(
+        "server_viewers_cut_total",
+        "Responses terminated because the viewer fell behind.",
+        &[("reason", reason)],
+    )
+}
+
+fn viewers_skipped() -> metrics::Counter {
+    metrics::counter(
+        "server_viewers_skipped_total",
+        "Times a lagging viewer skipped ahead to the newest chunk.",
+        &[],
+    )
+}
+
 #[derive(Debug)]
 pub struct Node {
     value: Option<Bytes>,
     next: AtomicPtr<Node>,
+    /// Bytes appended to the list up to and including this node.
+    offset: u64,
+    appended: Instant,
 }
 
 impl Node {
-    pub fn new(value: Option<Bytes>) -> Node {
+    pub fn new(value: Option<Bytes>, offset: u64) -> Node {
         Node {
             value,
             next: AtomicPtr::new(ptr::null_mut()),
+            offset,
+            appended: Instant::now(),
         }
     }
 
@@ -181,6 +299,7 @@ impl Node {
 pub struct LinkedList {
     tail: AtomicPtr<Node>,
     head: AtomicPtr<Node>,
+    written: AtomicU64,
 }
 
 impl LinkedList {
@@ -188,6 +307,7 @@ impl LinkedList {
         LinkedList {
             tail: AtomicPtr::new(ptr::null_mut()),
             head: AtomicPtr::new(ptr::null_mut()),
+            written: AtomicU64::new(0),
         }
     }
 
@@ -195,8 +315,18 @@ impl LinkedList {
         strong_clone(self.tail.load(Ordering::Acquire))
     }
 
+    pub fn head(&self) -> Option<Arc<Node>> {
+        strong_clone(self.head.load(Ordering::Acquire))
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Acquire)
+    }
+
     pub fn insert(&self, value: Option<Bytes>) {
-        let new_head = Arc::new(Node::new(value));
+        let size = value.as_ref().map_or(0, |v| v.len() as u64);
+        let offset = self.written.fetch_add(size, Ordering::AcqRel) + size;
+        let new_head = Arc::new(Node::new(value, offset));
         let new_ptr = Arc::into_raw(new_head) as *mut Node;
 
         if self.tail.load(Ordering::Acquire).is_null() {
@@ -274,3 +404,60 @@ fn copy_ptr(ptr: *mut Node) -> *mut Node {
         Arc::into_raw(clone) as *mut Node
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    fn downstream(cell: &Arc<Cell>, action: LagAction) -> ListDownstream {
+        let lag = LagPolicy {
+            max_bytes: Some(4),
+            max_time: None,
+            action,
+        };
+        ListDownstream::new(Arc::clone(cell), lag)
+    }
+
+    fn data(item: Option<Result<Frame<Bytes>, ServerError>>) -> Bytes {
+        item.unwrap().unwrap().into_data().unwrap()
+    }
+
+    #[tokio::test]
+    async fn lagging_viewer_cut_or_skipped() {
+        let cell = Arc::new(Cell::new());
+        let mut cut = downstream(&cell, LagAction::Cut);
+        let mut skip = downstream(&cell, LagAction::Skip);
+        for chunk in ["aa", "bb", "cc", "dd"] {
+            cell.append(Some(Bytes::from(chunk)));
+        }
+
+        let lagging =
+            |item: Option<Result<_, _>>| matches!(item, Some(Err(ServerError::LagError(_))));
+        assert!(lagging(cut.next().await));
+        // skips to the newest chunk and goes on from there
+        assert_eq!(data(skip.next().await), "dd");
+        cell.append(Some(Bytes::from("ee")));
+        assert_eq!(data(skip.next().await), "ee");
+
+        // a viewer joining late starts from the beginning without lagging
+        let mut joined = downstream(&cell, LagAction::Cut);
+        assert_eq!(data(joined.next().await), "aa");
+
+        // readers of a closed cell may take their time
+        cell.append(None);
+        let mut late = downstream(&cell, LagAction::Cut);
+        assert_eq!(data(late.next().await), "aa");
+    }
+
+    #[tokio::test]
+    async fn aborted_cell_fails_viewers() {
+        let cell = Arc::new(Cell::new());
+        let mut viewer = downstream(&cell, LagAction::Cut);
+        cell.append(Some(Bytes::from("aa")));
+        cell.abort();
+
+        assert_eq!(data(viewer.next().await), "aa");
+        assert!(viewer.next().await.unwrap().is_err());
+    }
+}
@@ -0,0 +1,312 @@
+use bytes::Bytes;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use parking_lot::Mutex;
+use rustc_hash::FxHasher;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::io::Write;
+use std::sync::{Arc, OnceLock};
+
+/// Format of the dates of HTTP headers.
+pub const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+const BROTLI_QUALITY: u32 = 5;
+
+/// Returns whether the key is a DASH or HLS manifest.
+pub fn is_manifest(key: &str) -> bool {
+    key.ends_with(".mpd") || key.ends_with(".m3u8")
+}
+
+/// Completed version of a manifest kept by its cache, so its validators and
+/// compressed bodies are computed once per version instead of per request.
+#[derive(Debug)]
+pub struct Manifest {
+    data: Bytes,
+    hash: String,
+    /// Completion time in whole seconds, the precision of `Last-Modified`.
+    modified: DateTime<Utc>,
+    /// Set when the previous version completed within the same second, `Last-Modified`
+    /// can't tell them apart then.
+    same_second: bool,
+    gzip: OnceLock<Option<Bytes>>,
+    brotli: OnceLock<Option<Bytes>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    /// Returns the encoding the request accepts, brotli before gzip.
+    pub fn negotiate(headers: &HeaderMap) -> Encoding {
+        let (mut gzip, mut brotli) = (false, false);
+        let accepted = headers
+            .get_all(header::ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','));
+        for item in accepted {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if quality <= 0.0 {
+                continue;
+            }
+            if name.eq_ignore_ascii_case("br") {
+                brotli = true;
+            } else if name.eq_ignore_ascii_case("gzip") {
+                gzip = true;
+            }
+        }
+
+        match (brotli, gzip) {
+            (true, _) => Encoding::Brotli,
+            (false, true) => Encoding::Gzip,
+            _ => Encoding::Identity,
+        }
+    }
+
+    /// Returns the `Content-Encoding` name, `None` for the identity.
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            Encoding::Identity => None,
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Brotli => Some("br"),
+        }
+    }
+}
+
+impl Manifest {
+    /// Creates the version completed now, following the version of the key completed
+    /// before it, if any.
+    pub fn new(data: Bytes, previous: Option<&Manifest>) -> Self {
+        Manifest::completed(data, previous, Utc::now())
+    }
+
+    fn completed(data: Bytes, previous: Option<&Manifest>, now: DateTime<Utc>) -> Self {
+        let mut hasher = FxHasher::default();
+        hasher.write(&data);
+        let hash = format!("{:016x}-{:x}", hasher.finish(), data.len());
+        let modified = DateTime::from_timestamp(now.timestamp(), 0).unwrap_or_default();
+        Manifest {
+            data,
+            hash,
+            modified,
+            same_second: previous.is_some_and(|previous| previous.modified == modified),
+            gzip: OnceLock::new(),
+            brotli: OnceLock::new(),
+        }
+    }
+
+    /// Returns the body in the encoding, compressed on first use. The identity is
+    /// returned when compressing doesn't make the manifest smaller.
+    pub fn body(&self, encoding: Encoding) -> (Encoding, Bytes) {
+        let encoded = match encoding {
+            Encoding::Identity => &None,
+            Encoding::Gzip => self
+                .gzip
+                .get_or_init(|| smaller(&self.data, gzip(&self.data))),
+            Encoding::Brotli => self
+                .brotli
+                .get_or_init(|| smaller(&self.data, brotli(&self.data))),
+        };
+        match encoded {
+            Some(encoded) => (encoding, encoded.clone()),
+            None => (Encoding::Identity, self.data.clone()),
+        }
+    }
+
+    /// Returns the strong entity tag of the body in the encoding.
+    pub fn etag(&self, encoding: Encoding) -> HeaderValue {
+        let etag = match encoding.name() {
+            Some(name) => format!("\"{}-{}\"", self.hash, name),
+            None => format!("\"{}\"", self.hash),
+        };
+        HeaderValue::from_str(&etag).unwrap()
+    }
+
+    pub fn last_modified(&self) -> HeaderValue {
+        HeaderValue::from_str(&self.modified.format(HTTP_DATE).to_string()).unwrap()
+    }
+
+    /// Returns whether the conditional headers of the request match this version, an
+    /// `If-None-Match` takes precedence over an `If-Modified-Since`. The date is ignored
+    /// for a version that replaced another within the same second.
+    pub fn not_modified(&self, headers: &HeaderMap) -> bool {
+        let mut tags = headers
+            .get_all(header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .peekable();
+        if tags.peek().is_some() {
+            return tags.any(|tag| self.matches(tag.trim()));
+        }
+
+        if self.same_second {
+            return false;
+        }
+        headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|since| since.to_str().ok())
+            .and_then(|since| NaiveDateTime::parse_from_str(since, HTTP_DATE).ok())
+            .is_some_and(|since| self.modified.naive_utc() <= since)
+    }
+
+    /// Weak comparison of an entity tag with the ones of every encoding.
+    fn matches(&self, tag: &str) -> bool {
+        if tag == "*" {
+            return true;
+        }
+        let tag = tag.trim_start_matches("W/").trim_matches('"');
+        let tag = ["-gzip", "-br"]
+            .iter()
+            .find_map(|suffix| tag.strip_suffix(suffix))
+            .unwrap_or(tag);
+        tag == self.hash
+    }
+}
+
+/// Completed versions of the manifests of a cache by key. They outlive the cells of
+/// their uploads, so validators and compressed bodies are served whatever the retention.
+#[derive(Debug, Default)]
+pub struct Manifests {
+    manifests: Mutex<HashMap<String, Arc<Manifest>>>,
+}
+
+impl Manifests {
+    pub fn get(&self, key: &str) -> Option<Arc<Manifest>> {
+        self.manifests.lock().get(key).cloned()
+    }
+
+    /// Removes the version of the key while a new one is uploaded, viewers read the
+    /// upload in progress then.
+    pub fn take(&self, key: &str) -> Option<Arc<Manifest>> {
+        self.manifests.lock().remove(key)
+    }
+
+    pub fn set(&self, key: &str, manifest: Manifest) {
+        self.manifests
+            .lock()
+            .insert(key.to_string(), Arc::new(manifest));
+    }
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let _ = encoder.write_all(data);
+    encoder.finish().unwrap_or_default()
+}
+
+fn brotli(data: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    {
+        let mut encoder = brotli::CompressorWriter::new(&mut encoded, 4096, BROTLI_QUALITY, 22);
+        let _ = encoder.write_all(data);
+    }
+    encoded
+}
+
+fn smaller(data: &[u8], encoded: Vec<u8>) -> Option<Bytes> {
+    (!encoded.is_empty() && encoded.len() < data.len()).then(|| Bytes::from(encoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn negotiate_encoding() {
+        let negotiate = |value| Encoding::negotiate(&headers(&[(header::ACCEPT_ENCODING, value)]));
+        assert_eq!(negotiate("gzip, deflate, br"), Encoding::Brotli);
+        assert_eq!(negotiate("gzip, br;q=0"), Encoding::Gzip);
+        assert_eq!(negotiate("identity"), Encoding::Identity);
+        assert_eq!(Encoding::negotiate(&HeaderMap::new()), Encoding::Identity);
+    }
+
+    #[test]
+    fn conditional_and_compressed() {
+        let data = "<MPD><Period><AdaptationSet/></Period></MPD>".repeat(20);
+        let manifest = Manifest::new(Bytes::from(data.clone()), None);
+
+        let (encoding, body) = manifest.body(Encoding::Gzip);
+        assert_eq!(encoding, Encoding::Gzip);
+        let mut decoded = String::new();
+        GzDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+        // computed once for the version
+        assert_eq!(manifest.body(Encoding::Gzip).1.as_ptr(), body.as_ptr());
+
+        let etag = manifest.etag(Encoding::Brotli);
+        let etag = etag.to_str().unwrap();
+        assert!(manifest.not_modified(&headers(&[(header::IF_NONE_MATCH, etag)])));
+        let weak = format!(
+            "\"other\", W/{}",
+            manifest.etag(Encoding::Identity).to_str().unwrap()
+        );
+        assert!(manifest.not_modified(&headers(&[(header::IF_NONE_MATCH, &weak)])));
+        let modified = manifest.last_modified();
+        let modified = modified.to_str().unwrap();
+        assert!(manifest.not_modified(&headers(&[(header::IF_MODIFIED_SINCE, modified)])));
+        // a mismatching tag wins over a matching date
+        assert!(!manifest.not_modified(&headers(&[
+            (header::IF_NONE_MATCH, "\"other\""),
+            (header::IF_MODIFIED_SINCE, modified),
+        ])));
+        let since = "Sat, 01 Jan 2000 00:00:00 GMT";
+        assert!(!manifest.not_modified(&headers(&[(header::IF_MODIFIED_SINCE, since)])));
+
+        // not worth compressing
+        let tiny = Manifest::new(Bytes::from_static(b"#EXTM3U"), None);
+        assert_eq!(tiny.body(Encoding::Brotli).0, Encoding::Identity);
+    }
+
+    #[test]
+    fn versions_within_one_second() {
+        let second = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let first = Manifest::completed(Bytes::from_static(b"v1"), None, second);
+        let modified = first.last_modified();
+        let since = headers(&[(header::IF_MODIFIED_SINCE, modified.to_str().unwrap())]);
+        assert!(first.not_modified(&since));
+
+        // the date of the first version matches the second one as well
+        let later = second + chrono::Duration::milliseconds(500);
+        let second_version = Manifest::completed(Bytes::from_static(b"v2"), Some(&first), later);
+        assert_eq!(second_version.last_modified(), modified);
+        assert!(!second_version.not_modified(&since));
+        let etag = second_version.etag(Encoding::Identity);
+        let matching = headers(&[(header::IF_NONE_MATCH, etag.to_str().unwrap())]);
+        assert!(second_version.not_modified(&matching));
+
+        let next_second = second + chrono::Duration::seconds(1);
+        let third = Manifest::completed(
+            Bytes::from_static(b"v3"),
+            Some(&second_version),
+            next_second,
+        );
+        let since = headers(&[(
+            header::IF_MODIFIED_SINCE,
+            third.last_modified().to_str().unwrap(),
+        )]);
+        assert!(third.not_modified(&since));
+    }
+}
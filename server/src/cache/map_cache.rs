@@ -1,4 +1,5 @@
-use crate::cache::Cache;
+use crate::cache::manifest::{Manifest, Manifests};
+use crate::cache::{Cache, LagPolicy};
 use crate::errors::ServerError;
 use async_trait::async_trait;
 use bytes::Bytes;
@@ -6,12 +7,14 @@ use futures_util::Stream;
 use http_body_util::combinators::BoxBody;
 use http_body_util::StreamBody;
 use hyper::body::Frame;
+use parking_lot::RwLock;
 use std::collections::HashMap;
-use std::convert::Infallible;
 use std::fmt::Debug;
+use std::mem::ManuallyDrop;
 use std::pin::Pin;
 use std::ptr;
-use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll, Waker};
 use tokio::sync::{Mutex, Notify};
@@ -21,36 +24,47 @@ use tracing::error;
 pub struct MapCache {
     pub preallocate: usize,
     map: Arc<Mutex<HashMap<String, Arc<Cell>>>>,
+    pub manifests: Arc<Manifests>,
 }
 
 impl MapCache {
     pub fn new(preallocate: usize) -> Self {
         let map = Arc::new(Mutex::new(HashMap::new()));
-        MapCache { map, preallocate }
+        MapCache {
+            map,
+            preallocate,
+            manifests: Arc::new(Manifests::default()),
+        }
     }
 
-    pub async fn insert(&self, key: &str, data: Arc<Bytes>, completed: bool) {
+    /// Creates an empty cell for the key. A cell left by a previous upload of the same key
+    /// is replaced, viewers already reading it keep their reference.
+    pub async fn cell(&self, key: &str) -> Arc<Cell> {
         let mut locked_map = self.map.lock().await;
-        let cell = locked_map.get(key);
-        if let Some(cell) = cell {
-            cell.set_data(data, completed);
-            return;
-        }
+        let cell = Arc::new(Cell::new());
+        locked_map.insert(key.to_string(), Arc::clone(&cell));
 
-        let cell = Cell::new();
-        cell.set_data(data, completed);
-        locked_map.insert(key.to_string(), Arc::new(cell));
+        cell
     }
 
-    pub async fn remove(&self, key: &str) {
+    /// Removes the key if it still points to the given cell.
+    pub async fn remove(&self, key: &str, cell: &Arc<Cell>) {
         let mut locked_map = self.map.lock().await;
-        locked_map.remove(key);
+        if let Some(current) = locked_map.get(key) {
+            if Arc::ptr_eq(current, cell) {
+                locked_map.remove(key);
+            }
+        }
     }
 }
 
 #[async_trait]
 impl Cache for MapCache {
-    async fn get(&self, key: &str) -> Result<Option<BoxBody<Bytes, Infallible>>, ServerError> {
+    async fn get(
+        &self,
+        key: &str,
+        _lag: &LagPolicy,
+    ) -> Result<Option<BoxBody<Bytes, ServerError>>, ServerError> {
         let locked_map = self.map.lock().await;
         let data = locked_map.get(key);
         if data.is_none() {
@@ -62,72 +76,141 @@ impl Cache for MapCache {
         let body = StreamBody::new(downstream);
         Ok(Some(BoxBody::new(body)))
     }
+
+    async fn manifest(&self, key: &str) -> Option<Arc<Manifest>> {
+        self.manifests.get(key)
+    }
 }
 
 #[derive(Debug, Clone)]
-struct Cell {
+pub struct Cell {
     completed: Arc<AtomicBool>,
+    aborted: Arc<AtomicBool>,
     notifier: Arc<Notify>,
-    data: Arc<AtomicPtr<Bytes>>,
+    data: Arc<RwLock<Option<Bytes>>>,
 }
 
 impl Cell {
     pub fn new() -> Self {
         Cell {
             completed: Arc::new(AtomicBool::new(false)),
-            data: Arc::new(AtomicPtr::new(ptr::null_mut())),
+            aborted: Arc::new(AtomicBool::new(false)),
+            data: Arc::new(RwLock::new(None)),
             notifier: Arc::new(Notify::new()),
         }
     }
 
-    pub fn data(&self) -> Option<Arc<Bytes>> {
-        let ptr = self.data.load(Ordering::Relaxed);
-        if ptr.is_null() {
-            return None;
-        }
+    pub fn data(&self) -> Option<Bytes> {
+        self.data.read().clone()
+    }
 
-        let node = unsafe {
-            let head = Arc::from_raw(ptr);
-            let node = Arc::clone(&head);
-            let _ = Arc::into_raw(head); // avoid dropping the variable
-            node
-        };
+    /// Replaces the data with a longer version of it, a reader that saw the cell
+    /// completed sees the complete data.
+    pub fn set_data(&self, data: Bytes, completed: bool) {
+        let previous = self.data.write().replace(data);
+        self.completed.store(completed, Ordering::Release);
+        self.notifier.notify_waiters();
+        drop(previous);
+    }
 
-        Some(node)
+    /// Completes the cell of an upload that failed, viewers get an error instead of
+    /// the end of the body.
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::Release);
+        self.completed.store(true, Ordering::Release);
+        self.notifier.notify_waiters();
     }
 
-    pub fn set_data(&self, data: Arc<Bytes>, completed: bool) {
-        self.drop_data();
+    pub fn completed(&self) -> bool {
+        self.completed.load(Ordering::Acquire)
+    }
 
-        let data = Arc::clone(&data);
-        let ptr = Arc::into_raw(data) as *mut Bytes;
-        self.data.store(ptr, Ordering::Relaxed);
-        self.completed.store(completed, Ordering::Relaxed);
-        self.notifier.notify_waiters();
+    pub fn aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
     }
 
-    fn drop_data(&self) {
-        let ptr = self.data.load(Ordering::Relaxed);
-        if ptr.is_null() {
-            return;
+    pub fn notifier(&self) -> Arc<Notify> {
+        self.notifier.clone()
+    }
+}
+
+/// Contents of an upload growing in place, each [`Content::append`] returns all of it so
+/// far without copying. A full buffer moves to one of twice the size, so every byte is
+/// copied a constant number of times on average.
+pub struct Content {
+    buffer: Arc<Buffer>,
+    len: usize,
+}
+
+/// Memory written once, only past the parts handed out already. The views reach other
+/// threads through the lock of the cell, which orders the writes before their reads.
+struct Buffer {
+    ptr: *mut u8,
+    capacity: usize,
+}
+
+// the bytes handed out are never written again
+unsafe impl Send for Buffer {}
+unsafe impl Sync for Buffer {}
+
+/// Part of a buffer handed out, the bytes in front of `len`.
+struct Written {
+    buffer: Arc<Buffer>,
+    len: usize,
+}
+
+impl Content {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Content {
+            buffer: Arc::new(Buffer::new(capacity)),
+            len: 0,
         }
+    }
 
-        let node = unsafe { Arc::from_raw(ptr) };
-        drop(node);
+    /// Appends the data and returns the contents so far.
+    pub fn append(&mut self, data: &[u8]) -> Bytes {
+        let len = self.len + data.len();
+        if len > self.buffer.capacity {
+            let buffer = Buffer::new(len.max(self.buffer.capacity * 2));
+            unsafe { ptr::copy_nonoverlapping(self.buffer.ptr, buffer.ptr, self.len) };
+            self.buffer = Arc::new(buffer);
+        }
+        unsafe {
+            let end = self.buffer.ptr.add(self.len);
+            ptr::copy_nonoverlapping(data.as_ptr(), end, data.len());
+        }
+        self.len = len;
+        self.bytes()
     }
 
-    pub fn completed(&self) -> bool {
-        self.completed.load(Ordering::Relaxed)
+    /// Returns the contents so far.
+    pub fn bytes(&self) -> Bytes {
+        Bytes::from_owner(Written {
+            buffer: Arc::clone(&self.buffer),
+            len: self.len,
+        })
     }
+}
 
-    pub fn notifier(&self) -> Arc<Notify> {
-        self.notifier.clone()
+impl Buffer {
+    fn new(capacity: usize) -> Self {
+        let mut data = ManuallyDrop::new(Vec::<u8>::with_capacity(capacity));
+        Buffer {
+            ptr: data.as_mut_ptr(),
+            capacity: data.capacity(),
+        }
     }
 }
 
-impl Drop for Cell {
+impl Drop for Buffer {
     fn drop(&mut self) {
-        self.drop_data();
+        drop(unsafe { Vec::from_raw_parts(self.ptr, 0, self.capacity) });
+    }
+}
+
+impl AsRef<[u8]> for Written {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.buffer.ptr, self.len) }
     }
 }
 
@@ -147,7 +230,7 @@ impl CellDownstream {
         }
     }
 
-    fn wait(&self, waker: Waker) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+    fn wait(&self, waker: Waker) -> Poll<Option<Result<Frame<Bytes>, ServerError>>> {
         let notifier = Arc::clone(&self.notifier);
         tokio::task::spawn(async move {
             notifier.notified().await;
@@ -156,16 +239,29 @@ impl CellDownstream {
 
         Poll::Pending
     }
+
+    fn complete(&self) -> Poll<Option<Result<Frame<Bytes>, ServerError>>> {
+        if self.cell.aborted() {
+            let e = ServerError::StorageError("upload aborted".to_string());
+            return Poll::Ready(Some(Err(e)));
+        }
+
+        Poll::Ready(None)
+    }
 }
 
 impl Stream for CellDownstream {
-    type Item = Result<Frame<Bytes>, Infallible>;
+    type Item = Result<Frame<Bytes>, ServerError>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let cell_completed = self.cell.completed();
         let data = self.cell.data();
-        if data.is_none() && cell_completed {
-            return Poll::Ready(None);
+        if data.is_none() {
+            if cell_completed {
+                return self.complete();
+            }
+
+            return self.wait(cx.waker().clone());
         }
 
         let data = data.unwrap();
@@ -177,7 +273,7 @@ impl Stream for CellDownstream {
 
         if buffer_size == self.bytes_sent {
             if cell_completed {
-                return Poll::Ready(None);
+                return self.complete();
             }
 
             return self.wait(cx.waker().clone());
@@ -190,3 +286,55 @@ impl Stream for CellDownstream {
         Poll::Ready(Some(Ok(frame)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_keeps_handed_out_bytes() {
+        let mut content = Content::with_capacity(4);
+        let first = content.append(b"ab");
+        let second = content.append(b"cd");
+        // moves to a larger buffer
+        let third = content.append(b"efg");
+        assert_eq!(first, "ab");
+        assert_eq!(second, "abcd");
+        assert_eq!(third, "abcdefg");
+        assert_eq!(content.bytes(), "abcdefg");
+        assert_eq!(Content::with_capacity(0).bytes(), "");
+    }
+
+    #[test]
+    fn readers_see_written_content() {
+        const TOTAL: usize = 1 << 20;
+        let cell = Arc::new(Cell::new());
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let cell = Arc::clone(&cell);
+                std::thread::spawn(move || loop {
+                    let completed = cell.completed();
+                    let data = cell.data().unwrap_or_default();
+                    for (i, byte) in data.iter().enumerate() {
+                        assert_eq!(*byte, i as u8);
+                    }
+                    if completed {
+                        assert_eq!(data.len(), TOTAL);
+                        break;
+                    }
+                })
+            })
+            .collect();
+
+        // starts small, so the buffer moves while readers hold views of it
+        let mut content = Content::with_capacity(1);
+        let chunk: Vec<u8> = (0..=255).collect();
+        for _ in 0..TOTAL / chunk.len() {
+            cell.set_data(content.append(&chunk), false);
+        }
+        cell.set_data(content.bytes(), true);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+}
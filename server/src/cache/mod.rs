@@ -2,13 +2,47 @@ use crate::errors::ServerError;
 use async_trait::async_trait;
 use bytes::Bytes;
 use http_body_util::combinators::BoxBody;
-use std::convert::Infallible;
+use manifest::Manifest;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
 
 pub mod list_cache;
+pub mod manifest;
 pub mod map_cache;
 pub mod static_cache;
 
 #[async_trait]
 pub trait Cache {
-    async fn get(&self, key: &str) -> Result<Option<BoxBody<Bytes, Infallible>>, ServerError>;
+    /// Returns the body for the key. Caches serving data that is still being written
+    /// apply the lag policy to readers that fall behind the writer.
+    async fn get(
+        &self,
+        key: &str,
+        lag: &LagPolicy,
+    ) -> Result<Option<BoxBody<Bytes, ServerError>>, ServerError>;
+
+    /// Returns the completed version of the manifest of the key, `None` while it is
+    /// uploaded or when the cache keeps no manifests.
+    async fn manifest(&self, _key: &str) -> Option<Arc<Manifest>> {
+        None
+    }
+}
+
+/// How far a reader may fall behind the live edge of a cell and what happens then.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LagPolicy {
+    pub max_bytes: Option<u64>,
+    pub max_time: Option<Duration>,
+    pub action: LagAction,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LagAction {
+    /// Terminate the response.
+    #[default]
+    Cut,
+    /// Drop the chunks the viewer is behind, it continues with the newest chunk.
+    Skip,
 }
@@ -1,5 +1,6 @@
-use crate::cache::Cache;
+use crate::cache::{Cache, LagPolicy};
 use crate::errors::ServerError;
+use crate::sendfile::FileRegion;
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures_util::Stream;
@@ -8,7 +9,6 @@ use http_body_util::StreamBody;
 use hyper::body::Frame;
 use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
-use std::convert::Infallible;
 use std::hash::{Hash, Hasher};
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
@@ -29,7 +29,11 @@ impl StaticCache {
 
 #[async_trait]
 impl Cache for StaticCache {
-    async fn get(&self, key: &str) -> Result<Option<BoxBody<Bytes, Infallible>>, ServerError> {
+    async fn get(
+        &self,
+        key: &str,
+        _lag: &LagPolicy,
+    ) -> Result<Option<BoxBody<Bytes, ServerError>>, ServerError> {
         let data = self.map.get(key);
         if data.is_none() {
             return Ok(None);
@@ -46,18 +50,34 @@ impl Cache for StaticCache {
 pub struct ShardedStaticCache {
     shards: u64,
     map: Arc<HashMap<u64, Mutex<HashMap<String, Bytes>>>>,
+    /// Kept for the segments that are slices of it to be sent from the file.
+    _region: Arc<FileRegion>,
 }
 
 impl ShardedStaticCache {
-    pub fn new(shards: u64, streams: u64, tracks: u64, segments: u64, data: Bytes) -> Self {
-        let map = make_mutex_map(shards, streams, tracks, segments, data);
-        ShardedStaticCache { shards, map }
+    pub fn new(
+        shards: u64,
+        streams: u64,
+        tracks: u64,
+        segments: u64,
+        region: Arc<FileRegion>,
+    ) -> Self {
+        let map = make_mutex_map(shards, streams, tracks, segments, region.data());
+        ShardedStaticCache {
+            shards,
+            map,
+            _region: region,
+        }
     }
 }
 
 #[async_trait]
 impl Cache for ShardedStaticCache {
-    async fn get(&self, key: &str) -> Result<Option<BoxBody<Bytes, Infallible>>, ServerError> {
+    async fn get(
+        &self,
+        key: &str,
+        _lag: &LagPolicy,
+    ) -> Result<Option<BoxBody<Bytes, ServerError>>, ServerError> {
         let i = shard(key, self.shards);
         let locked_map = self.map.get(&i).unwrap().lock().unwrap();
         let data = locked_map.get(key);
@@ -114,7 +134,7 @@ impl StaticDownstream {
 }
 
 impl Stream for StaticDownstream {
-    type Item = Result<Frame<Bytes>, Infallible>;
+    type Item = Result<Frame<Bytes>, ServerError>;
 
     fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         if self.sent {
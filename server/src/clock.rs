@@ -0,0 +1,58 @@
+use crate::cache::manifest::HTTP_DATE;
+use bytes::Bytes;
+use chrono::{DateTime, SecondsFormat, Utc};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::header::{self, HeaderValue};
+use hyper::{Response, StatusCode};
+
+/// Path of the time of the server for the clock sync of DASH players, `UTCTiming` with
+/// the `urn:mpeg:dash:utc:http-iso:2014` or `http-xsdate:2014` scheme reads the body, the
+/// `http-head:2014` scheme the `Date` header of a `HEAD` request.
+pub const PATH: &str = "/_time";
+
+/// Answers with the current time in ISO 8601 with milliseconds, which is an
+/// `xs:dateTime` as well.
+pub fn response<E>() -> Response<BoxBody<Bytes, E>> {
+    let now = Utc::now();
+    let body = Full::new(Bytes::from(iso(now))).map_err(|never| match never {});
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .header(header::CACHE_CONTROL, "no-store")
+        .header(header::DATE, http_date(now))
+        .body(BoxBody::new(body))
+        .unwrap()
+}
+
+fn iso(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn http_date(time: DateTime<Utc>) -> HeaderValue {
+    let date = time.format(HTTP_DATE).to_string();
+    HeaderValue::from_str(&date).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+
+    #[test]
+    fn formats() {
+        let time = DateTime::from_timestamp_millis(1_700_000_000_042).unwrap();
+        assert_eq!(iso(time), "2023-11-14T22:13:20.042Z");
+        assert_eq!(http_date(time), "Tue, 14 Nov 2023 22:13:20 GMT");
+    }
+
+    #[tokio::test]
+    async fn current_time() {
+        let before = Utc::now();
+        let response = response::<Infallible>();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let time = DateTime::parse_from_rfc3339(std::str::from_utf8(&body).unwrap()).unwrap();
+        // truncated to milliseconds
+        assert!(time >= before - chrono::Duration::milliseconds(1) && time <= Utc::now());
+    }
+}
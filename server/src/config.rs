@@ -1,37 +1,960 @@
+use crate::cache::{LagAction, LagPolicy};
+use crate::errors::ServerError;
+use crate::events;
+use crate::ingester::Timeouts;
+use common::logging::Log;
+use common::runtime::Mode;
+use common::socket::SocketOptions;
+use common::systemd::GracePeriods;
+use hyper::header::{HeaderName, HeaderValue};
 use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::Duration;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Setting {
     #[serde(default)]
     pub runtime: Runtime,
+    #[serde(default)]
+    pub server: Server,
+    #[serde(default)]
+    pub log: Log,
+    pub admin: Option<Admin>,
     pub ingester: Ingester,
     pub transmitter: Transmitter,
     pub cache: Cache,
+    #[serde(default)]
+    pub tenant: Vec<Tenant>,
+    #[serde(default)]
+    pub streams: Streams,
+    pub webhooks: Option<Webhooks>,
+}
+
+impl Setting {
+    pub fn parse(data: &str) -> Result<Setting, ServerError> {
+        let setting: Setting = toml::from_str(data)
+            .map_err(|e| ServerError::ConfigError(format!("invalid configuration: {}", e)))?;
+        setting.validate()?;
+        Ok(setting)
+    }
+
+    pub fn validate(&self) -> Result<(), ServerError> {
+        self.server.validate()?;
+        self.cache.validate()?;
+        if let Some(cache) = &self.server.cache {
+            if !self.tenant.is_empty() {
+                return Err(ServerError::ConfigError(
+                    "server.cache: cannot be used together with tenants".to_string(),
+                ));
+            }
+            self.cache
+                .config(cache)
+                .map_err(|e| field_error("server.cache", e))?;
+        }
+        for (i, tenant) in self.tenant.iter().enumerate() {
+            tenant
+                .validate(&self.cache)
+                .map_err(|e| field_error(format!("tenant[{}]", i), e))?;
+            if self.tenant[..i].iter().any(|t| t.name == tenant.name) {
+                return Err(ServerError::ConfigError(format!(
+                    "tenant[{}]: name '{}' is used more than once",
+                    i, tenant.name
+                )));
+            }
+        }
+        self.log.validate().map_err(ServerError::ConfigError)?;
+        self.runtime.validate(self)?;
+        self.ingester.validate()?;
+        let mut sockets = vec![
+            ("ingester", &self.ingester.socket),
+            ("transmitter", &self.transmitter.socket),
+        ];
+        if let Some(framed) = &self.ingester.framed {
+            sockets.push(("ingester.framed", &framed.socket));
+        }
+        if let Some(admin) = &self.admin {
+            sockets.push(("admin", &admin.socket));
+        }
+        for (listener, socket) in sockets {
+            socket
+                .validate()
+                .map_err(|e| ServerError::ConfigError(format!("{}.socket.{}", listener, e)))?;
+        }
+        self.transmitter.limits.validate()?;
+        self.transmitter.viewers.validate()?;
+        self.transmitter.cors.validate()?;
+        if let Some(access_log) = &self.ingester.access_log {
+            access_log.validate("ingester")?;
+        }
+        if let Some(access_log) = &self.transmitter.access_log {
+            access_log.validate("transmitter")?;
+        }
+        if let Some(events) = &self.transmitter.events {
+            events.validate()?;
+        }
+        if let Some(websocket) = &self.transmitter.websocket {
+            websocket.validate()?;
+        }
+        self.streams.validate()?;
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.validate()?;
+        }
+        for (name, value) in self.transmitter.headers.iter() {
+            HeaderName::from_str(name).map_err(|e| {
+                ServerError::ConfigError(format!("transmitter.headers: '{}': {}", name, e))
+            })?;
+            HeaderValue::from_str(value).map_err(|e| {
+                ServerError::ConfigError(format!("transmitter.headers.{}: {}", name, e))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the configured tenants. Without tenants in the file a single `default`
+    /// tenant serving every request is made of the cache passed on the command line or,
+    /// if none, the one set in `server.cache`.
+    pub fn tenants(&self, cache: Option<&str>) -> Result<Vec<Tenant>, ServerError> {
+        if !self.tenant.is_empty() {
+            if cache.is_some() {
+                return Err(ServerError::ConfigError(
+                    "the cache argument cannot be used together with tenants".to_string(),
+                ));
+            }
+            return Ok(self.tenant.clone());
+        }
+
+        let cache = match cache.or(self.server.cache.as_deref()) {
+            Some(cache) => cache,
+            None => {
+                return Err(ServerError::ConfigError(format!(
+                "no cache selected: pass it as an argument or set server.cache, available caches: {}",
+                self.cache.names().join(", ")
+            )))
+            }
+        };
+        self.cache.config(cache)?;
+
+        Ok(vec![Tenant {
+            name: "default".to_string(),
+            hosts: Vec::new(),
+            path_prefix: None,
+            cache: cache.to_string(),
+            retention: 0,
+            max_bytes: None,
+            max_streams: None,
+        }])
+    }
+
+    /// Compares two settings and returns the names of the changed fields split into the
+    /// ones applied to the running server and the ones that take effect after a restart.
+    pub fn changes(&self, next: &Setting) -> Changes {
+        let mut changes = Changes::default();
+        if self.server != next.server {
+            changes.restart.push("server".to_string());
+        }
+        if self.runtime != next.runtime {
+            changes.restart.push("runtime".to_string());
+        }
+        if self.admin != next.admin {
+            changes.restart.push("admin".to_string());
+        }
+        if self.ingester != next.ingester {
+            changes.restart.push("ingester".to_string());
+        }
+        if self.transmitter.addr != next.transmitter.addr {
+            changes.restart.push("transmitter.addr".to_string());
+        }
+        if self.transmitter.socket != next.transmitter.socket {
+            changes.restart.push("transmitter.socket".to_string());
+        }
+        if self.transmitter.access_log != next.transmitter.access_log {
+            changes.restart.push("transmitter.access_log".to_string());
+        }
+        if self.transmitter.events != next.transmitter.events {
+            changes.restart.push("transmitter.events".to_string());
+        }
+        if self.streams != next.streams {
+            changes.restart.push("streams".to_string());
+        }
+        if self.webhooks != next.webhooks {
+            changes.restart.push("webhooks".to_string());
+        }
+        if self.cache != next.cache {
+            changes.restart.push("cache".to_string());
+        }
+        let live = self.live(next);
+        if live.tenant != next.tenant {
+            changes.restart.push("tenant".to_string());
+        }
+        if live.tenant != self.tenant {
+            changes.applied.push("tenant.retention".to_string());
+        }
+        if self.log.level != next.log.level {
+            changes.applied.push("log.level".to_string());
+        }
+        if self.log.format != next.log.format || self.log.otlp != next.log.otlp {
+            changes.restart.push("log".to_string());
+        }
+        if self.transmitter.headers != next.transmitter.headers {
+            changes.applied.push("transmitter.headers".to_string());
+        }
+        if self.transmitter.limits != next.transmitter.limits {
+            changes.applied.push("transmitter.limits".to_string());
+        }
+        if self.transmitter.viewers != next.transmitter.viewers {
+            changes.applied.push("transmitter.viewers".to_string());
+        }
+        if self.transmitter.websocket != next.transmitter.websocket {
+            changes.applied.push("transmitter.websocket".to_string());
+        }
+        if self.transmitter.cors != next.transmitter.cors {
+            changes.applied.push("transmitter.cors".to_string());
+        }
+        if self.transmitter.sendfile != next.transmitter.sendfile {
+            changes.applied.push("transmitter.sendfile".to_string());
+        }
+
+        changes
+    }
+
+    /// Returns the running setting with the fields that can be changed without a restart
+    /// taken from the next one.
+    pub fn live(&self, next: &Setting) -> Setting {
+        let mut setting = self.clone();
+        setting.log.level = next.log.level.clone();
+        setting.transmitter.headers = next.transmitter.headers.clone();
+        setting.transmitter.limits = next.transmitter.limits.clone();
+        setting.transmitter.viewers = next.transmitter.viewers.clone();
+        setting.transmitter.websocket = next.transmitter.websocket.clone();
+        setting.transmitter.sendfile = next.transmitter.sendfile;
+        setting.transmitter.cors = next.transmitter.cors.clone();
+        for tenant in setting.tenant.iter_mut() {
+            if let Some(next) = next.tenant.iter().find(|next| next.name == tenant.name) {
+                tenant.retention = next.retention;
+            }
+        }
+        setting
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub applied: Vec<String>,
+    pub restart: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Server {
+    /// Cache used when none is passed on the command line, e.g. `list:copy`.
+    pub cache: Option<String>,
+    /// Seconds open connections get to finish on shutdown or after a reload handed the
+    /// listeners over, 30 by default.
+    pub grace_period: Option<u64>,
+    /// Seconds the process started by a reload gets to become ready, 30 by default.
+    pub reload_timeout: Option<u64>,
+    /// Seconds the listeners keep accepting on shutdown while `/readyz` fails, so load
+    /// balancers take the server out before connections are refused, 0 by default.
+    pub drain_delay: Option<u64>,
+    /// Bytes the caches of all tenants may hold before `/readyz` reports the server as
+    /// not ready.
+    pub memory_budget: Option<u64>,
 }
 
-#[derive(Debug, Default, Deserialize)]
+impl Server {
+    pub fn grace_periods(&self) -> GracePeriods {
+        GracePeriods {
+            reload: Duration::from_secs(self.reload_timeout.unwrap_or(30)),
+            shutdown: Duration::from_secs(self.grace_period.unwrap_or(30)),
+        }
+    }
+
+    pub fn drain_delay(&self) -> Duration {
+        Duration::from_secs(self.drain_delay.unwrap_or(0))
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        if self.reload_timeout == Some(0) {
+            return Err(ServerError::ConfigError(
+                "server.reload_timeout: must be greater than 0".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
 pub struct Runtime {
+    #[serde(default)]
+    pub mode: Mode,
+    /// Worker threads, or in `thread_per_core` mode the number of cores to run on.
     pub threads: Option<usize>,
+    /// Cores to run on in `thread_per_core` mode, takes precedence over `threads`.
+    #[serde(default)]
+    pub cores: Vec<usize>,
+}
+
+impl Runtime {
+    fn validate(&self, setting: &Setting) -> Result<(), ServerError> {
+        if self.threads == Some(0) {
+            return Err(ServerError::ConfigError(
+                "runtime.threads: must be greater than 0".to_string(),
+            ));
+        }
+        if self.mode != Mode::ThreadPerCore {
+            return Ok(());
+        }
+
+        let mut listeners = vec![
+            ("ingester", &setting.ingester.addr),
+            ("transmitter", &setting.transmitter.addr),
+        ];
+        if let Some(framed) = &setting.ingester.framed {
+            listeners.push(("ingester.framed", &framed.addr));
+        }
+        for (listener, addr) in listeners {
+            if addr.starts_with("unix:") {
+                return Err(ServerError::ConfigError(format!(
+                    "{}.addr: unix sockets can't be shared by the cores of the thread_per_core mode",
+                    listener
+                )));
+            }
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Admin {
+    pub addr: String,
+    #[serde(default)]
+    pub socket: SocketOptions,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Ingester {
     pub addr: String,
+    #[serde(default)]
+    pub socket: SocketOptions,
+    /// Seconds a client may take to send the request headers.
+    pub header_timeout: Option<u64>,
+    /// Seconds an upload may go without sending body data.
+    pub body_timeout: Option<u64>,
+    /// Seconds the upload of a single segment may take.
+    pub max_segment_duration: Option<u64>,
+    #[serde(default)]
+    pub limits: IngestLimits,
+    pub access_log: Option<AccessLog>,
+    pub replication: Option<Replication>,
+    pub framed: Option<Framed>,
+}
+
+/// Upload limits applied to every stream of every tenant.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct IngestLimits {
+    /// Maximum size of a media segment in bytes, larger uploads get 413.
+    pub max_segment_size: Option<u64>,
+    /// Maximum size of a manifest (`.mpd`, `.m3u8`) in bytes, larger uploads get 413.
+    pub max_manifest_size: Option<u64>,
+    /// Maximum concurrent uploads per stream, further uploads get 429.
+    pub max_uploads_per_stream: Option<usize>,
+    /// Bytes per second a stream may upload, uploads above it get 429.
+    pub max_stream_bandwidth: Option<u64>,
+}
+
+impl Ingester {
+    pub fn header_timeout(&self) -> Option<Duration> {
+        self.header_timeout.map(Duration::from_secs)
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            body: self.body_timeout.map(Duration::from_secs),
+            segment: self.max_segment_duration.map(Duration::from_secs),
+        }
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        let timeouts = [
+            ("header_timeout", self.header_timeout),
+            ("body_timeout", self.body_timeout),
+            ("max_segment_duration", self.max_segment_duration),
+        ];
+        for (name, timeout) in timeouts {
+            if timeout == Some(0) {
+                return Err(ServerError::ConfigError(format!(
+                    "ingester.{}: must be greater than 0",
+                    name
+                )));
+            }
+        }
+        if self.limits.max_stream_bandwidth == Some(0) {
+            return Err(ServerError::ConfigError(
+                "ingester.limits.max_stream_bandwidth: must be greater than 0".to_string(),
+            ));
+        }
+        if let Some(replication) = &self.replication {
+            replication.validate()?;
+        }
+
+        Ok(())
+    }
 }
 
-#[derive(Debug, Deserialize)]
+/// Listener of the framed ingest protocol, a lighter alternative to HTTP uploads for
+/// encoders running on the same host.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Framed {
+    pub addr: String,
+    #[serde(default)]
+    pub socket: SocketOptions,
+}
+
+/// Copies of every upload streamed to the ingesters of peer servers while it is received.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Replication {
+    /// `host:port` of the ingesters of the peers.
+    pub peers: Vec<String>,
+    /// Chunks buffered per peer and upload, a peer falling further behind loses the copy.
+    #[serde(default = "Replication::default_buffer")]
+    pub buffer: usize,
+    /// Seconds to connect to a peer.
+    #[serde(default = "Replication::default_connect_timeout")]
+    pub connect_timeout: u64,
+    /// Seconds a failed peer gets no copies.
+    #[serde(default = "Replication::default_retry_after")]
+    pub retry_after: u64,
+}
+
+impl Replication {
+    fn default_buffer() -> usize {
+        64
+    }
+
+    fn default_connect_timeout() -> u64 {
+        2
+    }
+
+    fn default_retry_after() -> u64 {
+        5
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        for peer in self.peers.iter() {
+            format!("http://{}/", peer)
+                .parse::<hyper::Uri>()
+                .map_err(|e| {
+                    ServerError::ConfigError(format!("ingester.replication: '{}': {}", peer, e))
+                })?;
+        }
+        if self.buffer == 0 {
+            return Err(ServerError::ConfigError(
+                "ingester.replication.buffer: must be greater than 0".to_string(),
+            ));
+        }
+        if self.connect_timeout == 0 {
+            return Err(ServerError::ConfigError(
+                "ingester.replication.connect_timeout: must be greater than 0".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Transmitter {
     pub addr: String,
+    #[serde(default)]
+    pub socket: SocketOptions,
+    /// Extra headers added to media responses, applied without a restart.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub viewers: Viewers,
+    pub access_log: Option<AccessLog>,
+    pub events: Option<Events>,
+    pub websocket: Option<WebSocket>,
+    /// Sends the segments of static caches to viewers from their file with `sendfile`
+    /// instead of copying them from memory, applied to new connections without a restart.
+    #[serde(default)]
+    pub sendfile: bool,
+    #[serde(default)]
+    pub cors: Cors,
+}
+
+/// Server-sent events of the ingested streams at `/_events` and `/<stream>/_events`,
+/// takes effect after a restart.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Events {
+    /// Events kept for a subscriber that reads slower than they arrive.
+    #[serde(default = "Events::default_buffer")]
+    pub buffer: usize,
+}
+
+impl Events {
+    fn default_buffer() -> usize {
+        1024
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        if self.buffer == 0 {
+            return Err(ServerError::ConfigError(
+                "transmitter.events.buffer: must be greater than 0".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Cross-origin access of browser players to the transmitter, applied without a restart.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Cors {
+    /// Origins allowed to read the responses, `*` for any.
+    #[serde(default = "Cors::default_allowed_origins")]
+    pub allowed_origins: Vec<String>,
+    /// Lets the allowed origins send credentials, needs origins other than `*`.
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// Request headers players may send besides the safelisted ones.
+    #[serde(default = "Cors::default_allowed_headers")]
+    pub allowed_headers: Vec<String>,
+    /// Response headers players may read besides the safelisted ones.
+    #[serde(default = "Cors::default_exposed_headers")]
+    pub exposed_headers: Vec<String>,
+    /// Seconds browsers may cache the answer to a preflight request.
+    #[serde(default = "Cors::default_max_age")]
+    pub max_age: u64,
+}
+
+impl Cors {
+    fn default_allowed_origins() -> Vec<String> {
+        vec!["*".to_string()]
+    }
+
+    fn default_allowed_headers() -> Vec<String> {
+        vec!["Range".to_string()]
+    }
+
+    fn default_exposed_headers() -> Vec<String> {
+        ["Content-Range", "Date", "ETag"].map(String::from).to_vec()
+    }
+
+    fn default_max_age() -> u64 {
+        3600
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        if self.allow_credentials && self.allowed_origins.iter().any(|origin| origin == "*") {
+            return Err(ServerError::ConfigError(
+                "transmitter.cors.allow_credentials: needs allowed_origins without '*'".to_string(),
+            ));
+        }
+        for origin in &self.allowed_origins {
+            HeaderValue::from_str(origin).map_err(|e| {
+                ServerError::ConfigError(format!(
+                    "transmitter.cors.allowed_origins: '{}': {}",
+                    origin, e
+                ))
+            })?;
+        }
+        let headers = self.allowed_headers.iter().chain(&self.exposed_headers);
+        for name in headers {
+            HeaderName::from_str(name).map_err(|e| {
+                ServerError::ConfigError(format!("transmitter.cors: header '{}': {}", name, e))
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors {
+            allowed_origins: Cors::default_allowed_origins(),
+            allow_credentials: false,
+            allowed_headers: Cors::default_allowed_headers(),
+            exposed_headers: Cors::default_exposed_headers(),
+            max_age: Cors::default_max_age(),
+        }
+    }
+}
+
+/// Chunks pushed to WebSocket subscribers at `/<stream>/<representation>/_ws`, applied
+/// without a restart. Subscribers lagging within a segment are handled by
+/// `transmitter.viewers`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct WebSocket {
+    /// Segments a subscriber may fall behind the upload before it is disconnected.
+    #[serde(default = "WebSocket::default_max_lag_segments")]
+    pub max_lag_segments: usize,
 }
 
+impl WebSocket {
+    fn default_max_lag_segments() -> usize {
+        2
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        if self.max_lag_segments == 0 {
+            return Err(ServerError::ConfigError(
+                "transmitter.websocket.max_lag_segments: must be greater than 0".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Lifecycle of the streams reported on the event feed and to webhooks, and how long
+/// the keys of an abandoned stream are kept.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Streams {
+    /// Seconds without uploads after which a stream is idle.
+    #[serde(default = "Streams::default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Seconds without uploads after which all keys of a stream are removed, even before
+    /// their retention ends.
+    pub purge_after: Option<u64>,
+}
+
+impl Streams {
+    fn default_idle_timeout() -> u64 {
+        10
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout)
+    }
+
+    pub fn purge_after(&self) -> Option<Duration> {
+        self.purge_after.map(Duration::from_secs)
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        if self.idle_timeout == 0 {
+            return Err(ServerError::ConfigError(
+                "streams.idle_timeout: must be greater than 0".to_string(),
+            ));
+        }
+        if self.purge_after == Some(0) {
+            return Err(ServerError::ConfigError(
+                "streams.purge_after: must be greater than 0".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Streams {
+    fn default() -> Self {
+        Streams {
+            idle_timeout: Streams::default_idle_timeout(),
+            purge_after: None,
+        }
+    }
+}
+
+/// HTTP callbacks about the lifecycle of the streams, takes effect after a restart.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Webhooks {
+    /// Endpoints receiving the events as JSON `POST` requests.
+    #[serde(default)]
+    pub urls: Vec<String>,
+    /// Events sent to the endpoints.
+    #[serde(default = "Webhooks::default_events")]
+    pub events: Vec<events::Kind>,
+    /// Endpoint asked before the first upload of a stream, a reply other than 2xx
+    /// rejects the upload with 403.
+    pub on_publish: Option<String>,
+    /// Key of the HMAC-SHA256 signature sent in `X-Signature-256`.
+    pub secret: Option<String>,
+    /// Seconds a request may take.
+    #[serde(default = "Webhooks::default_timeout")]
+    pub timeout: u64,
+    /// Retries of a failed notification, `on_publish` is not retried.
+    #[serde(default = "Webhooks::default_max_retries")]
+    pub max_retries: u32,
+    /// Seconds before the first retry, doubled for every further one.
+    #[serde(default = "Webhooks::default_backoff")]
+    pub backoff: u64,
+    /// Notifications in flight, further ones are dropped.
+    #[serde(default = "Webhooks::default_max_pending")]
+    pub max_pending: usize,
+}
+
+impl Webhooks {
+    fn default_events() -> Vec<events::Kind> {
+        vec![
+            events::Kind::StreamStarted,
+            events::Kind::StreamIdle,
+            events::Kind::IngestFailed,
+        ]
+    }
+
+    fn default_timeout() -> u64 {
+        5
+    }
+
+    fn default_max_retries() -> u32 {
+        5
+    }
+
+    fn default_backoff() -> u64 {
+        1
+    }
+
+    fn default_max_pending() -> usize {
+        1024
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        for url in self.urls.iter().chain(self.on_publish.iter()) {
+            if !url.starts_with("http://") {
+                return Err(ServerError::ConfigError(format!(
+                    "webhooks: '{}': only http:// endpoints are supported",
+                    url
+                )));
+            }
+            url.parse::<hyper::Uri>()
+                .map_err(|e| ServerError::ConfigError(format!("webhooks: '{}': {}", url, e)))?;
+        }
+        if self.timeout == 0 {
+            return Err(ServerError::ConfigError(
+                "webhooks.timeout: must be greater than 0".to_string(),
+            ));
+        }
+        if self.max_pending == 0 {
+            return Err(ServerError::ConfigError(
+                "webhooks.max_pending: must be greater than 0".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Access log of a listener, takes effect after a restart.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AccessLog {
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    /// File to write to, stdout when not set.
+    pub path: Option<String>,
+    /// Size in bytes after which the file is rotated.
+    #[serde(default = "AccessLog::default_max_size")]
+    pub max_size: u64,
+    /// Number of rotated files to keep.
+    #[serde(default = "AccessLog::default_max_files")]
+    pub max_files: usize,
+    /// Share of successful requests logged, errors are always logged.
+    #[serde(default = "AccessLog::default_sample")]
+    pub sample: f64,
+}
+
+impl AccessLog {
+    fn default_max_size() -> u64 {
+        100 * 1024 * 1024
+    }
+
+    fn default_max_files() -> usize {
+        5
+    }
+
+    fn default_sample() -> f64 {
+        1.0
+    }
+
+    fn validate(&self, listener: &str) -> Result<(), ServerError> {
+        if !(self.sample > 0.0 && self.sample <= 1.0) {
+            return Err(ServerError::ConfigError(format!(
+                "{}.access_log.sample: must be greater than 0 and at most 1",
+                listener
+            )));
+        }
+        if self.max_size == 0 {
+            return Err(ServerError::ConfigError(format!(
+                "{}.access_log.max_size: must be greater than 0",
+                listener
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// Combined log format with the timings appended.
+    #[default]
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Connection and request limits of the transmitter, applied without a restart.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Limits {
+    /// Maximum connections in total, new connections above it get 503.
+    pub max_connections: Option<usize>,
+    /// Maximum concurrent connections per source address, new ones above it get 429.
+    pub max_connections_per_ip: Option<usize>,
+    /// Requests per second allowed per source address.
+    pub requests_per_second: Option<f64>,
+    /// Requests a source address may send at once, defaults to `requests_per_second`.
+    pub burst: Option<f64>,
+    /// Maximum concurrent responses per stream.
+    pub max_viewers_per_stream: Option<usize>,
+    /// Seconds sent in the `Retry-After` header of rejected requests.
+    #[serde(default = "Limits::default_retry_after")]
+    pub retry_after: u64,
+}
+
+impl Limits {
+    fn default_retry_after() -> u64 {
+        1
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        if let Some(rate) = self.requests_per_second {
+            if rate <= 0.0 {
+                return Err(ServerError::ConfigError(
+                    "transmitter.limits.requests_per_second: must be greater than 0".to_string(),
+                ));
+            }
+        }
+        if let Some(burst) = self.burst {
+            if burst < 1.0 {
+                return Err(ServerError::ConfigError(
+                    "transmitter.limits.burst: must be at least 1".to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_connections: None,
+            max_connections_per_ip: None,
+            requests_per_second: None,
+            burst: None,
+            max_viewers_per_stream: None,
+            retry_after: Limits::default_retry_after(),
+        }
+    }
+}
+
+/// Handling of viewers that fall behind, applied without a restart.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Viewers {
+    /// Bytes a viewer may be behind the upload of a segment.
+    pub max_lag_bytes: Option<u64>,
+    /// Milliseconds a viewer may be behind the upload of a segment.
+    pub max_lag_ms: Option<u64>,
+    /// `cut` terminates the response of a lagging viewer, `skip` drops the chunks it is
+    /// behind and goes on with the newest chunk of the segment.
+    #[serde(default)]
+    pub lag_policy: LagAction,
+    /// Seconds a write to a viewer may stall before the connection is closed.
+    pub write_timeout: Option<u64>,
+}
+
+impl Viewers {
+    pub fn lag(&self) -> LagPolicy {
+        LagPolicy {
+            max_bytes: self.max_lag_bytes,
+            max_time: self.max_lag_ms.map(Duration::from_millis),
+            action: self.lag_policy,
+        }
+    }
+
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout.map(Duration::from_secs)
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        if self.write_timeout == Some(0) {
+            return Err(ServerError::ConfigError(
+                "transmitter.viewers.write_timeout: must be greater than 0".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Prefixes a config error with the name of the field it belongs to.
+fn field_error(field: impl std::fmt::Display, e: ServerError) -> ServerError {
+    match e {
+        ServerError::ConfigError(msg) => ServerError::ConfigError(format!("{}: {}", field, msg)),
+        e => e,
+    }
+}
+
+/// A group of streams served from its own cache, selected by the `Host` header and/or
+/// a path prefix. Tenants are matched in the order they are defined.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Tenant {
+    pub name: String,
+    /// Host names without the port, any host matches if empty.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Path prefix, e.g. `/tenant-a`, any path matches if not set.
+    pub path_prefix: Option<String>,
+    /// Cache in the form `<type>:<name>`.
+    pub cache: String,
+    /// Seconds a key stays in the cache after its upload is completed, applied without a
+    /// restart to the keys completed afterwards.
+    #[serde(default)]
+    pub retention: u64,
+    /// Maximum bytes of ingested data kept in the cache.
+    pub max_bytes: Option<u64>,
+    /// Maximum number of streams kept in the cache.
+    pub max_streams: Option<usize>,
+}
+
+impl Tenant {
+    fn validate(&self, cache: &Cache) -> Result<(), ServerError> {
+        if self.name.is_empty() {
+            return Err(ServerError::ConfigError(
+                "name: must not be empty".to_string(),
+            ));
+        }
+        cache
+            .config(&self.cache)
+            .map_err(|e| field_error("cache", e))?;
+        if let Some(prefix) = &self.path_prefix {
+            if !prefix.starts_with('/') {
+                return Err(ServerError::ConfigError(format!(
+                    "path_prefix: '{}' must start with '/'",
+                    prefix
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum CacheConfig {
-    NotFound,
     Static(StaticCache),
     Map(MapCache),
     List(ListCache),
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Cache {
     #[serde(default)]
     pub map: Vec<MapCache>,
@@ -42,42 +965,84 @@ pub struct Cache {
 }
 
 impl Cache {
-    pub fn config(&self, name: &str) -> CacheConfig {
-        let parts = name.split(':').collect::<Vec<&str>>();
-        if parts.len() != 2 {
-            return CacheConfig::NotFound;
-        }
-
-        match parts[0] {
-            "static" => {
-                for c in self.r#static.iter() {
-                    if c.name.eq(parts[1]) {
-                        return CacheConfig::Static(c.clone());
-                    }
-                }
-                CacheConfig::NotFound
+    /// Resolves a cache name in the form `<type>:<name>`, e.g. `list:copy`.
+    pub fn config(&self, name: &str) -> Result<CacheConfig, ServerError> {
+        let (kind, cache_name) = name.split_once(':').ok_or_else(|| {
+            ServerError::ConfigError(format!(
+                "cache '{}' must be in the form '<type>:<name>', available caches: {}",
+                name,
+                self.names().join(", ")
+            ))
+        })?;
+
+        let config = match kind {
+            "static" => self
+                .r#static
+                .iter()
+                .find(|c| c.name == cache_name)
+                .map(|c| CacheConfig::Static(c.clone())),
+            "list" => self
+                .list
+                .iter()
+                .find(|c| c.name == cache_name)
+                .map(|c| CacheConfig::List(c.clone())),
+            "map" => self
+                .map
+                .iter()
+                .find(|c| c.name == cache_name)
+                .map(|c| CacheConfig::Map(c.clone())),
+            _ => {
+                return Err(ServerError::ConfigError(format!(
+                    "cache '{}': unknown type '{}', expected one of: static, list, map",
+                    name, kind
+                )))
             }
-            "list" => {
-                for c in self.list.iter() {
-                    if c.name == parts[1] {
-                        return CacheConfig::List(c.clone());
-                    }
-                }
-                CacheConfig::NotFound
+        };
+
+        config.ok_or_else(|| {
+            ServerError::ConfigError(format!(
+                "cache '{}' is not defined, available caches: {}",
+                name,
+                self.names().join(", ")
+            ))
+        })
+    }
+
+    /// Returns the names of all defined caches in the form `<type>:<name>`.
+    pub fn names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        names.extend(self.r#static.iter().map(|c| format!("static:{}", c.name)));
+        names.extend(self.list.iter().map(|c| format!("list:{}", c.name)));
+        names.extend(self.map.iter().map(|c| format!("map:{}", c.name)));
+        names
+    }
+
+    pub fn validate(&self) -> Result<(), ServerError> {
+        let mut names = self.names();
+        names.sort();
+        for pair in names.windows(2) {
+            if pair[0] == pair[1] {
+                return Err(ServerError::ConfigError(format!(
+                    "cache '{}' is defined more than once",
+                    pair[0]
+                )));
             }
-            "map" => {
-                for c in self.map.iter() {
-                    if c.name == parts[1] {
-                        return CacheConfig::Map(c.clone());
-                    }
-                }
-                CacheConfig::NotFound
+        }
+
+        for (i, c) in self.r#static.iter().enumerate() {
+            if c.shards == 0 {
+                return Err(ServerError::ConfigError(format!(
+                    "cache.static[{}].shards: must be greater than 0",
+                    i
+                )));
             }
-            _ => CacheConfig::NotFound,
         }
+
+        Ok(())
     }
 }
-#[derive(Debug, Clone, Deserialize)]
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct StaticCache {
     pub name: String,
     pub file_path: String,
@@ -87,14 +1052,235 @@ pub struct StaticCache {
     pub segments: u64,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct MapCache {
     pub name: String,
     pub preallocate: usize,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct ListCache {
     pub name: String,
     pub copy: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> Cache {
+        toml::from_str(
+            r#"
+            [[static]]
+            name = "bbb/720p"
+            file_path = "./samples/segments/720p.m4s"
+            shards = 10
+            streams = 10
+            tracks = 5
+            segments = 30
+
+            [[list]]
+            name = "copy"
+            copy = true
+
+            [[map]]
+            name = "100KB"
+            preallocate = 100000
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn config_static() {
+        let config = cache().config("static:bbb/720p").unwrap();
+        match config {
+            CacheConfig::Static(c) => assert_eq!(c.shards, 10),
+            _ => panic!("expected static cache, got {:?}", config),
+        }
+    }
+
+    #[test]
+    fn config_list() {
+        let config = cache().config("list:copy").unwrap();
+        assert_eq!(
+            config,
+            CacheConfig::List(ListCache {
+                name: "copy".to_string(),
+                copy: true
+            })
+        );
+    }
+
+    #[test]
+    fn config_map() {
+        let config = cache().config("map:100KB").unwrap();
+        assert_eq!(
+            config,
+            CacheConfig::Map(MapCache {
+                name: "100KB".to_string(),
+                preallocate: 100000
+            })
+        );
+    }
+
+    #[test]
+    fn config_not_found_lists_available() {
+        let err = cache().config("list:non-copy").unwrap_err().to_string();
+        assert!(err.contains("'list:non-copy'"), "{}", err);
+        assert!(
+            err.contains("static:bbb/720p, list:copy, map:100KB"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn config_unknown_type() {
+        let err = cache().config("disk:copy").unwrap_err().to_string();
+        assert!(err.contains("unknown type 'disk'"), "{}", err);
+    }
+
+    #[test]
+    fn config_invalid_format() {
+        assert!(cache().config("copy").is_err());
+        assert!(cache().config("").is_err());
+    }
+
+    #[test]
+    fn validate_duplicate_names() {
+        let mut cache = cache();
+        cache.list.push(cache.list[0].clone());
+        let err = cache.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("'list:copy' is defined more than once"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn tenants_default() {
+        let setting = Setting::parse(
+            r#"
+            [server]
+            cache = "list:copy"
+
+            [ingester]
+            addr = ":8445"
+
+            [transmitter]
+            addr = ":8446"
+
+            [[cache.list]]
+            name = "copy"
+            copy = true
+
+            [[cache.map]]
+            name = "100KB"
+            preallocate = 100000
+            "#,
+        )
+        .unwrap();
+
+        let tenants = setting.tenants(None).unwrap();
+        assert_eq!(tenants.len(), 1);
+        assert_eq!(tenants[0].name, "default");
+        assert_eq!(tenants[0].cache, "list:copy");
+
+        let tenants = setting.tenants(Some("map:100KB")).unwrap();
+        assert_eq!(tenants[0].cache, "map:100KB");
+    }
+
+    #[test]
+    fn parse_rejects_invalid_tenant() {
+        let err = Setting::parse(
+            r#"
+            [ingester]
+            addr = ":8445"
+
+            [transmitter]
+            addr = ":8446"
+
+            [[cache.list]]
+            name = "copy"
+            copy = true
+
+            [[tenant]]
+            name = "a"
+            path_prefix = "/a"
+            cache = "list:copy"
+
+            [[tenant]]
+            name = "b"
+            cache = "map:100KB"
+            "#,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("tenant[1]: cache"), "{}", err);
+        assert!(err.contains("available caches: list:copy"), "{}", err);
+    }
+
+    #[test]
+    fn parse_rejects_unknown_server_cache() {
+        let err = Setting::parse(
+            r#"
+            [server]
+            cache = "list:missing"
+
+            [ingester]
+            addr = ":8445"
+
+            [transmitter]
+            addr = ":8446"
+
+            [[cache.list]]
+            name = "copy"
+            copy = true
+            "#,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("server.cache"), "{}", err);
+        assert!(err.contains("list:copy"), "{}", err);
+    }
+
+    #[test]
+    fn tenant_retention_applied_live() {
+        let parse = |retention: u64, hosts: &str| {
+            Setting::parse(&format!(
+                r#"
+                [ingester]
+                addr = ":8445"
+
+                [transmitter]
+                addr = ":8446"
+
+                [[cache.list]]
+                name = "copy"
+                copy = true
+
+                [[tenant]]
+                name = "a"
+                hosts = [{}]
+                cache = "list:copy"
+                retention = {}
+                "#,
+                hosts, retention
+            ))
+            .unwrap()
+        };
+
+        let current = parse(30, "");
+        let next = parse(60, "");
+        let changes = current.changes(&next);
+        assert_eq!(changes.applied, ["tenant.retention"]);
+        assert!(changes.restart.is_empty());
+        assert_eq!(current.live(&next).tenant[0].retention, 60);
+
+        let changes = current.changes(&parse(60, r#""a.example.com""#));
+        assert_eq!(changes.applied, ["tenant.retention"]);
+        assert_eq!(changes.restart, ["tenant"]);
+    }
+}
@@ -7,6 +7,13 @@ pub enum ServerError {
     NetworkError(String),
     StorageError(String),
     RequestError(String),
+    QuotaError(String),
+    TimeoutError(String),
+    /// An upload sent no data for longer than the body timeout.
+    StalledError(String),
+    SizeError(String),
+    /// A viewer fell behind the upload it reads.
+    LagError(String),
 }
 
 impl fmt::Display for ServerError {
@@ -16,6 +23,11 @@ impl fmt::Display for ServerError {
             ServerError::NetworkError(msg) => write!(f, "Network error: {}", msg),
             ServerError::StorageError(msg) => write!(f, "Storage error: {}", msg),
             ServerError::RequestError(msg) => write!(f, "Request error: {}", msg),
+            ServerError::QuotaError(msg) => write!(f, "Quota error: {}", msg),
+            ServerError::TimeoutError(msg) => write!(f, "Timeout error: {}", msg),
+            ServerError::StalledError(msg) => write!(f, "Stalled error: {}", msg),
+            ServerError::SizeError(msg) => write!(f, "Size error: {}", msg),
+            ServerError::LagError(msg) => write!(f, "Lag error: {}", msg),
         }
     }
 }
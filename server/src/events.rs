@@ -0,0 +1,277 @@
+use crate::cache::manifest::is_manifest;
+use crate::errors::ServerError;
+use bytes::Bytes;
+use hyper::body::{Body, Frame};
+use serde::{Deserialize, Serialize};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+
+/// Path segment of the event feed: `/_events` for all streams of a tenant,
+/// `/<stream>/_events` for a single stream.
+pub const PATH: &str = "_events";
+
+/// Interval of comments sent to keep idle feeds open through proxies.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    StreamStarted,
+    InitSegment,
+    SegmentStarted,
+    SegmentCompleted,
+    /// An upload failed or was aborted.
+    IngestFailed,
+    StreamIdle,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::StreamStarted => "stream_started",
+            Kind::InitSegment => "init_segment",
+            Kind::SegmentStarted => "segment_started",
+            Kind::SegmentCompleted => "segment_completed",
+            Kind::IngestFailed => "ingest_failed",
+            Kind::StreamIdle => "stream_idle",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Event {
+    pub event: Kind,
+    pub tenant: String,
+    pub stream: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub representation: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Size of a completed segment.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    /// Time the upload of a completed segment took.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    /// Reason of a failed upload.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// RFC 3339 time of the event.
+    pub time: String,
+}
+
+impl Event {
+    fn new(event: Kind, tenant: &str, stream: &str) -> Self {
+        Event {
+            event,
+            tenant: tenant.to_string(),
+            stream: stream.to_string(),
+            representation: None,
+            key: None,
+            bytes: None,
+            duration_ms: None,
+            error: None,
+            time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        }
+    }
+
+    fn segment(event: Kind, tenant: &str, stream: &str, path: &str, key: &str) -> Self {
+        let mut e = Event::new(event, tenant, stream);
+        e.representation = path.rsplit_once('/').map(|(r, _)| r.to_string());
+        e.key = Some(key.to_string());
+        e
+    }
+}
+
+/// Stream and segment events of the ingester, published to the subscribers of the
+/// event feed of the transmitter. The streams of the tenants report when they start and
+/// go idle.
+#[derive(Debug)]
+pub struct Events {
+    sender: broadcast::Sender<Arc<Event>>,
+}
+
+impl Events {
+    /// Creates the events, keeping `buffer` events for slow subscribers.
+    pub fn new(buffer: usize) -> Self {
+        let (sender, _) = broadcast::channel(buffer);
+        Events { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Event>> {
+        self.sender.subscribe()
+    }
+
+    /// Records the first upload of a stream, or the first after it was idle.
+    pub fn stream_started(&self, tenant: &str, stream: &str) {
+        self.send(Event::new(Kind::StreamStarted, tenant, stream));
+    }
+
+    /// Records a stream without uploads for the idle timeout.
+    pub fn stream_idle(&self, tenant: &str, stream: &str) {
+        self.send(Event::new(Kind::StreamIdle, tenant, stream));
+    }
+
+    /// Records the start of an upload. `path` is the key within the stream.
+    pub fn ingest_started(&self, tenant: &str, stream: &str, path: &str, key: &str) {
+        if !is_manifest(key) && !is_init(path) {
+            self.send(Event::segment(
+                Kind::SegmentStarted,
+                tenant,
+                stream,
+                path,
+                key,
+            ));
+        }
+    }
+
+    /// Records a successful upload of `bytes` that took `duration`.
+    pub fn ingest_completed(
+        &self,
+        tenant: &str,
+        stream: &str,
+        path: &str,
+        key: &str,
+        bytes: u64,
+        duration: Duration,
+    ) {
+        if is_manifest(key) {
+            return;
+        }
+
+        let kind = if is_init(path) {
+            Kind::InitSegment
+        } else {
+            Kind::SegmentCompleted
+        };
+        let mut event = Event::segment(kind, tenant, stream, path, key);
+        event.bytes = Some(bytes);
+        event.duration_ms = Some(duration.as_millis() as u64);
+        self.send(event);
+    }
+
+    /// Records a failed upload.
+    pub fn ingest_failed(&self, tenant: &str, stream: &str, path: &str, key: &str, error: String) {
+        let mut event = Event::segment(Kind::IngestFailed, tenant, stream, path, key);
+        event.error = Some(error);
+        self.send(event);
+    }
+
+    fn send(&self, event: Event) {
+        // fails only without subscribers
+        let _ = self.sender.send(Arc::new(event));
+    }
+
+    /// Returns a server-sent events body with the events of the tenant, or of one of its
+    /// streams.
+    pub fn feed(&self, tenant: &str, stream: Option<&str>) -> Feed {
+        let (sender, receiver) = mpsc::channel(16);
+        let mut events = self.subscribe();
+        let tenant = tenant.to_string();
+        let stream = stream.map(|stream| stream.to_string());
+        tokio::spawn(async move {
+            let mut keepalive = tokio::time::interval(KEEPALIVE);
+            keepalive.tick().await;
+            loop {
+                let frame = tokio::select! {
+                    event = events.recv() => match event {
+                        Ok(event) => {
+                            if event.tenant != tenant
+                                || stream.as_ref().is_some_and(|s| *s != event.stream)
+                            {
+                                continue;
+                            }
+                            format_event(&event)
+                        }
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            format!(": {} events missed\n\n", missed)
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    _ = keepalive.tick() => ": keepalive\n\n".to_string(),
+                };
+                if sender.send(Bytes::from(frame)).await.is_err() {
+                    // the subscriber left
+                    break;
+                }
+            }
+        });
+
+        Feed { receiver }
+    }
+}
+
+/// Returns true for init segments, named `init*`.
+pub fn is_init(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    name.starts_with("init")
+}
+
+fn format_event(event: &Event) -> String {
+    let data = serde_json::to_string(event).unwrap_or_default();
+    format!("event: {}\ndata: {}\n\n", event.event.as_str(), data)
+}
+
+/// Body of the event feed, open until the subscriber leaves.
+pub struct Feed {
+    receiver: mpsc::Receiver<Bytes>,
+}
+
+impl Body for Feed {
+    type Data = Bytes;
+    type Error = ServerError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        self.receiver
+            .poll_recv(cx)
+            .map(|frame| frame.map(|data| Ok(Frame::data(data))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ingest_events_of_a_stream() {
+        let events = Events::new(16);
+        let mut receiver = events.sender.subscribe();
+        let mut next = || receiver.try_recv().unwrap().event;
+
+        events.ingest_started("t", "s1", "0/init.m4s", "/s1/0/init.m4s");
+        events.ingest_completed(
+            "t",
+            "s1",
+            "0/init.m4s",
+            "/s1/0/init.m4s",
+            10,
+            Duration::ZERO,
+        );
+        events.ingest_started("t", "s1", "0/1.m4s", "/s1/0/1.m4s");
+        events.ingest_completed("t", "s1", "0/1.m4s", "/s1/0/1.m4s", 20, Duration::ZERO);
+        events.ingest_started("t", "s1", "index.mpd", "/s1/index.mpd");
+        assert_eq!(next(), Kind::InitSegment);
+        assert_eq!(next(), Kind::SegmentStarted);
+        assert_eq!(next(), Kind::SegmentCompleted);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn format_server_sent_event() {
+        let mut event = Event::segment(Kind::SegmentCompleted, "t", "s1", "0/1.m4s", "/s1/0/1.m4s");
+        event.bytes = Some(20);
+        event.time = "2025-01-01T00:00:00.000Z".to_string();
+        assert_eq!(
+            format_event(&event),
+            "event: segment_completed\ndata: {\"event\":\"segment_completed\",\"tenant\":\"t\",\
+             \"stream\":\"s1\",\"representation\":\"0\",\"key\":\"/s1/0/1.m4s\",\"bytes\":20,\
+             \"time\":\"2025-01-01T00:00:00.000Z\"}\n\n"
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use crate::metrics;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// State behind the liveness and readiness probes of the admin server.
+#[derive(Debug)]
+pub struct Health {
+    loaded: AtomicBool,
+    draining: AtomicBool,
+    memory_budget: Option<u64>,
+}
+
+impl Health {
+    pub fn new(memory_budget: Option<u64>) -> Self {
+        Health {
+            loaded: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
+            memory_budget,
+        }
+    }
+
+    /// Marks the caches of all tenants as loaded.
+    pub fn loaded(&self) {
+        self.loaded.store(true, Ordering::Release);
+    }
+
+    /// Marks the server as shutting down, it only finishes the open connections.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Release);
+    }
+
+    pub fn draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    /// Returns why the server should get no traffic, `None` if it is ready.
+    pub fn not_ready(&self) -> Option<String> {
+        if self.draining() {
+            return Some("draining".to_string());
+        }
+        if !self.loaded.load(Ordering::Acquire) {
+            return Some("loading caches".to_string());
+        }
+        if let Some(budget) = self.memory_budget {
+            let stored = metrics::sum("server_stored_bytes").max(0) as u64;
+            if stored >= budget {
+                return Some(format!(
+                    "memory budget exhausted: {} of {} bytes",
+                    stored, budget
+                ));
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_after_load_until_drain() {
+        let health = Health::new(None);
+        assert_eq!(health.not_ready().as_deref(), Some("loading caches"));
+        health.loaded();
+        assert_eq!(health.not_ready(), None);
+        health.drain();
+        assert_eq!(health.not_ready().as_deref(), Some("draining"));
+    }
+}
@@ -1,54 +1,99 @@
 use crate::cache::list_cache::ListCache;
-use crate::ingester::Ingester;
+use crate::cache::manifest::{is_manifest, Manifest};
+use crate::errors::ServerError;
+use crate::ingester::quota::Quota;
+use crate::ingester::{self, content_length, Ingester, Timeouts, Upload, UploadBody};
+use crate::streams::StreamRegistry;
 use async_trait::async_trait;
-use bytes::Bytes;
-use http_body_util::BodyExt;
-use hyper::body::Incoming;
+use bytes::{Bytes, BytesMut};
 use hyper::{Method, Request};
 use std::sync::Arc;
-use tracing::error;
+use std::time::Duration;
+use tokio::sync::watch;
 
 #[derive(Debug, Clone)]
 pub struct ListIngester {
     cache: Arc<ListCache>,
+    quota: Arc<Quota>,
+    streams: Arc<StreamRegistry>,
+    retention: watch::Receiver<Duration>,
+    timeouts: Timeouts,
 }
 
 impl ListIngester {
-    pub fn new(cache: Arc<ListCache>) -> Self {
-        ListIngester { cache }
+    pub fn new(
+        cache: Arc<ListCache>,
+        quota: Arc<Quota>,
+        streams: Arc<StreamRegistry>,
+        retention: watch::Receiver<Duration>,
+        timeouts: Timeouts,
+    ) -> Self {
+        ListIngester {
+            cache,
+            quota,
+            streams,
+            retention,
+            timeouts,
+        }
     }
 }
 
 #[async_trait]
 impl Ingester for ListIngester {
-    async fn ingest(&self, mut req: Request<Incoming>) {
+    async fn ingest(&self, req: Request<UploadBody>) -> Result<u64, ServerError> {
         if req.method() != Method::PUT {
-            return;
+            return Ok(0);
         }
 
         let key = req.uri().path().to_string();
+        if let Some(length) = content_length(&req) {
+            self.quota.check_size(&key, length)?;
+        }
+        let _upload = self.quota.start(&key)?;
+        self.quota.open(&key)?;
+        let ingest = self.streams.ingest(&key);
+        // the version being replaced, for the validators of the new one
+        let previous = if is_manifest(&key) {
+            self.cache.manifests.take(&key)
+        } else {
+            None
+        };
         let cell = self.cache.cell(&key).await;
-        while let Some(next) = req.frame().await {
-            if next.is_err() {
-                if let Err(e) = next {
-                    error!("req body: read: {:?}", e);
-                    break;
-                }
-            }
+        ingest.started();
+        ingest.announce();
+        // manifests are kept whole besides their chunks
+        let mut manifest = is_manifest(&key).then(BytesMut::new);
+        let body = Upload::from_request(req, self.timeouts);
+        let (size, result) = ingester::receive(body, &key, &self.quota, |data| {
+            let data = if self.cache.copy_before_insert {
+                Bytes::copy_from_slice(&data)
+            } else {
+                data
+            };
 
-            let frame = next.unwrap();
-            if frame.is_data() {
-                let data = frame.into_data().unwrap();
-                let data = if self.cache.copy_before_insert {
-                    Bytes::copy_from_slice(&data)
-                } else {
-                    data
-                };
-
-                cell.append(Some(data));
+            if let Some(manifest) = &mut manifest {
+                manifest.extend_from_slice(&data);
+            }
+            cell.append(Some(data));
+        })
+        .await;
+        if result.is_ok() {
+            if let Some(manifest) = manifest {
+                self.cache
+                    .manifests
+                    .set(&key, Manifest::new(manifest.freeze(), previous.as_deref()));
             }
+            cell.append(None); // close cell
+        } else {
+            cell.abort();
         }
-        cell.append(None); // close cell
-        self.cache.remove(key.as_str()).await;
+        let retention = *self.retention.borrow();
+        let (cache, quota) = (Arc::clone(&self.cache), Arc::clone(&self.quota));
+        ingester::expire(retention, ingest.expiry(), async move {
+            cache.remove(&key, &cell).await;
+            quota.release(&key, size);
+        })
+        .await;
+        result.map(|_| size as u64)
     }
 }
@@ -1,57 +1,89 @@
-use crate::cache::map_cache::MapCache;
-use crate::ingester::Ingester;
+use crate::cache::manifest::{is_manifest, Manifest};
+use crate::cache::map_cache::{Content, MapCache};
+use crate::errors::ServerError;
+use crate::ingester::quota::Quota;
+use crate::ingester::{self, content_length, Ingester, Timeouts, Upload, UploadBody};
+use crate::streams::StreamRegistry;
 use async_trait::async_trait;
-use bytes::{Buf, BufMut, BytesMut};
-use http_body_util::BodyExt;
-use hyper::body::Incoming;
 use hyper::{Method, Request};
 use std::sync::Arc;
-use tracing::error;
+use std::time::Duration;
+use tokio::sync::watch;
 
 #[derive(Debug, Clone)]
 pub struct MapIngester {
     cache: Arc<MapCache>,
+    quota: Arc<Quota>,
+    streams: Arc<StreamRegistry>,
+    retention: watch::Receiver<Duration>,
+    timeouts: Timeouts,
 }
 
 impl MapIngester {
-    pub fn new(cache: Arc<MapCache>) -> Self {
-        MapIngester { cache }
+    pub fn new(
+        cache: Arc<MapCache>,
+        quota: Arc<Quota>,
+        streams: Arc<StreamRegistry>,
+        retention: watch::Receiver<Duration>,
+        timeouts: Timeouts,
+    ) -> Self {
+        MapIngester {
+            cache,
+            quota,
+            streams,
+            retention,
+            timeouts,
+        }
     }
 }
 
 #[async_trait]
 impl Ingester for MapIngester {
-    async fn ingest(&self, mut req: Request<Incoming>) {
+    async fn ingest(&self, req: Request<UploadBody>) -> Result<u64, ServerError> {
         if req.method() != Method::PUT {
-            return;
+            return Ok(0);
         }
 
         let key = req.uri().path().to_string();
-        let mut buffer: BytesMut = if self.cache.preallocate > 0 {
-            BytesMut::with_capacity(self.cache.preallocate)
+        if let Some(length) = content_length(&req) {
+            self.quota.check_size(&key, length)?;
+        }
+        let _upload = self.quota.start(&key)?;
+        self.quota.open(&key)?;
+        let ingest = self.streams.ingest(&key);
+        // the version being replaced, for the validators of the new one
+        let previous = if is_manifest(&key) {
+            self.cache.manifests.take(&key)
         } else {
-            BytesMut::new()
+            None
         };
-
-        while let Some(next) = req.frame().await {
-            if next.is_err() {
-                if let Err(e) = next {
-                    error!("req body: read: {:?}", e);
-                    break;
-                }
-            }
-
-            let frame = next.unwrap();
-            if frame.is_data() {
-                let data = frame.into_data().unwrap();
-                buffer.put(data);
-                let data = Arc::new(buffer.copy_to_bytes(buffer.len()));
-                self.cache.insert(key.as_str(), data, false).await;
+        let cell = self.cache.cell(&key).await;
+        ingest.started();
+        ingest.announce();
+        let mut content = Content::with_capacity(self.cache.preallocate);
+        let body = Upload::from_request(req, self.timeouts);
+        let (size, result) = ingester::receive(body, &key, &self.quota, |data| {
+            cell.set_data(content.append(&data), false);
+        })
+        .await;
+        if result.is_ok() {
+            let data = content.bytes();
+            if is_manifest(&key) {
+                self.cache
+                    .manifests
+                    .set(&key, Manifest::new(data.clone(), previous.as_deref()));
             }
+            cell.set_data(data, true);
+        } else {
+            cell.abort();
         }
-        self.cache
-            .insert(key.as_str(), Arc::new(buffer.freeze()), true)
-            .await;
-        self.cache.remove(key.as_str()).await;
+        let retention = *self.retention.borrow();
+        let (cache, quota) = (Arc::clone(&self.cache), Arc::clone(&self.quota));
+        ingester::expire(retention, ingest.expiry(), async move {
+            cache.remove(&key, &cell).await;
+            quota.release(&key, size);
+        })
+        .await;
+        result.map(|_| size as u64)
     }
 }
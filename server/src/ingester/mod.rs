@@ -1,12 +1,218 @@
+use crate::errors::ServerError;
+use crate::ingester::quota::Quota;
+use crate::replication::{Copies, Tee};
+use crate::streams::Expiry;
 use async_trait::async_trait;
-use hyper::body::Incoming;
-use hyper::Request;
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::body::Frame;
+use hyper::{header, Request};
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
 
 pub mod list_ingester;
 pub mod map_ingester;
+pub mod quota;
 pub mod simple_ingester;
 
+/// Body of an upload, received over HTTP or the framed protocol.
+pub type UploadBody = BoxBody<Bytes, ServerError>;
+
 #[async_trait]
 pub trait Ingester {
-    async fn ingest(&self, req: Request<Incoming>);
+    /// Stores the body of the request, returns the number of bytes received.
+    async fn ingest(&self, req: Request<UploadBody>) -> Result<u64, ServerError>;
+}
+
+/// Returns the size of the body announced in the `Content-Length` header.
+pub fn content_length<B>(req: &Request<B>) -> Option<u64> {
+    req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse().ok())
+}
+
+/// Limits on how long an upload may take, `None` disables a limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timeouts {
+    /// Longest pause between two frames of the body.
+    pub body: Option<Duration>,
+    /// Longest time the upload of a segment may take.
+    pub segment: Option<Duration>,
+}
+
+/// Body of an upload that fails when it stalls or takes longer than the timeouts allow.
+/// The frames are passed on to the copies of the upload on the peers.
+pub struct Upload {
+    body: UploadBody,
+    timeouts: Timeouts,
+    deadline: Option<Instant>,
+    copies: Option<Copies>,
+}
+
+impl Upload {
+    pub fn new(body: UploadBody, timeouts: Timeouts) -> Self {
+        let deadline = timeouts.segment.map(|segment| Instant::now() + segment);
+        Upload {
+            body,
+            timeouts,
+            deadline,
+            copies: None,
+        }
+    }
+
+    /// Returns the body of the request together with the copies attached to it.
+    pub fn from_request(req: Request<UploadBody>, timeouts: Timeouts) -> Self {
+        let (parts, body) = req.into_parts();
+        let mut upload = Upload::new(body, timeouts);
+        upload.copies = parts.extensions.get::<Tee>().and_then(Tee::take);
+        upload
+    }
+
+    /// Returns the next frame of the body, `None` once the body is complete.
+    pub async fn frame(&mut self) -> Option<Result<Frame<Bytes>, ServerError>> {
+        let idle = self.timeouts.body.map(|body| Instant::now() + body);
+        let limit = match (idle, self.deadline) {
+            (Some(idle), Some(deadline)) if deadline <= idle => Some((deadline, Limit::Segment)),
+            (Some(idle), _) => Some((idle, Limit::Body)),
+            (None, Some(deadline)) => Some((deadline, Limit::Segment)),
+            (None, None) => None,
+        };
+
+        let frame = self.body.frame();
+        let next = match limit {
+            Some((at, limit)) => match tokio::time::timeout_at(at, frame).await {
+                Ok(next) => next,
+                Err(_) => return Some(Err(self.timeout(limit))),
+            },
+            None => frame.await,
+        };
+
+        match (&next, &mut self.copies) {
+            (Some(Ok(frame)), Some(copies)) => {
+                if let Some(data) = frame.data_ref() {
+                    copies.send(data);
+                }
+            }
+            (None, copies) => {
+                if let Some(copies) = copies.take() {
+                    copies.complete();
+                }
+            }
+            _ => {}
+        }
+
+        next
+    }
+
+    fn timeout(&self, limit: Limit) -> ServerError {
+        match limit {
+            Limit::Body => ServerError::StalledError(format!(
+                "no data received for {:?}",
+                self.timeouts.body.unwrap_or_default()
+            )),
+            Limit::Segment => ServerError::TimeoutError(format!(
+                "upload takes longer than {:?}",
+                self.timeouts.segment.unwrap_or_default()
+            )),
+        }
+    }
+}
+
+enum Limit {
+    Body,
+    Segment,
+}
+
+/// Reads the body of the upload of the key and passes each chunk on once the quota
+/// accepted it. Returns the bytes received, and the error that ended the upload early.
+pub async fn receive(
+    mut body: Upload,
+    key: &str,
+    quota: &Quota,
+    mut chunk: impl FnMut(Bytes),
+) -> (usize, Result<(), ServerError>) {
+    let mut size = 0;
+    while let Some(next) = body.frame().await {
+        let frame = match next {
+            Ok(frame) => frame,
+            Err(e) => return (size, Err(e)),
+        };
+        if let Ok(data) = frame.into_data() {
+            let checked = quota
+                .check_size(key, (size + data.len()) as u64)
+                .and_then(|_| quota.throttle(key, data.len()))
+                .and_then(|_| quota.reserve(data.len()));
+            if let Err(e) = checked {
+                return (size, Err(e));
+            }
+            size += data.len();
+            chunk(data);
+        }
+    }
+
+    (size, Ok(()))
+}
+
+/// Runs `remove` once the retention of a completed key is over or its stream is purged,
+/// right away without a retention.
+pub async fn expire(
+    retention: Duration,
+    mut expiry: Expiry,
+    remove: impl Future<Output = ()> + Send + 'static,
+) {
+    if retention.is_zero() {
+        remove.await;
+        return;
+    }
+
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tokio::time::sleep(retention) => {}
+            _ = expiry.purged() => {}
+        }
+        remove.await;
+        drop(expiry);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+    use http_body_util::StreamBody;
+
+    fn upload(timeouts: Timeouts) -> Upload {
+        // sends a frame every 100ms
+        let frames = stream::unfold((), |_| async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Some((Ok(Frame::data(Bytes::from_static(b"a"))), ()))
+        });
+        Upload::new(BoxBody::new(StreamBody::new(frames)), timeouts)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_or_too_long() {
+        let mut stalled = upload(Timeouts {
+            body: Some(Duration::from_millis(50)),
+            segment: None,
+        });
+        assert!(matches!(
+            stalled.frame().await,
+            Some(Err(ServerError::StalledError(_)))
+        ));
+
+        let mut slow = upload(Timeouts {
+            body: Some(Duration::from_millis(150)),
+            segment: Some(Duration::from_millis(250)),
+        });
+        assert!(matches!(slow.frame().await, Some(Ok(_))));
+        assert!(matches!(slow.frame().await, Some(Ok(_))));
+        assert!(matches!(
+            slow.frame().await,
+            Some(Err(ServerError::TimeoutError(_)))
+        ));
+    }
 }
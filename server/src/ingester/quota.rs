@@ -0,0 +1,254 @@
+use crate::cache::manifest::is_manifest;
+use crate::config::IngestLimits;
+use crate::errors::ServerError;
+use crate::limits::{Buckets, SlotGuard, Slots};
+use crate::metrics::{self, Counter, Gauge};
+use crate::tenant::stream_name;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Tracks the bytes and streams a tenant keeps in its cache and enforces its limits
+/// together with the upload limits of its streams.
+/// A stream is the first path segment after the tenant prefix.
+#[derive(Debug)]
+pub struct Quota {
+    prefix: String,
+    max_bytes: Option<u64>,
+    max_streams: Option<usize>,
+    limits: IngestLimits,
+    bytes: AtomicU64,
+    streams: Mutex<HashMap<String, usize>>,
+    uploads: Arc<Slots<String>>,
+    bandwidth: Buckets<String>,
+    stored_bytes: Gauge,
+    stored_streams: Gauge,
+    rejected: Counter,
+    too_large: Counter,
+}
+
+impl Quota {
+    pub fn new(
+        tenant: &str,
+        prefix: &str,
+        max_bytes: Option<u64>,
+        max_streams: Option<usize>,
+        limits: IngestLimits,
+    ) -> Self {
+        let labels = [("tenant", tenant)];
+        Quota {
+            prefix: prefix.to_string(),
+            max_bytes,
+            max_streams,
+            limits,
+            bytes: AtomicU64::new(0),
+            streams: Mutex::new(HashMap::new()),
+            uploads: Arc::new(Slots::new()),
+            bandwidth: Buckets::new(),
+            stored_bytes: metrics::gauge(
+                "server_stored_bytes",
+                "Bytes of ingested data kept in the cache.",
+                &labels,
+            ),
+            stored_streams: metrics::gauge(
+                "server_streams",
+                "Streams with at least one key in the cache.",
+                &labels,
+            ),
+            rejected: metrics::counter(
+                "server_ingest_quota_rejected_total",
+                "Uploads rejected because a tenant quota is exhausted.",
+                &labels,
+            ),
+            too_large: metrics::counter(
+                "server_ingest_too_large_total",
+                "Uploads rejected because they exceed the maximum size.",
+                &labels,
+            ),
+        }
+    }
+
+    /// Starts an upload of the key, rejecting it above the concurrent uploads of its
+    /// stream. The upload ends when the guard is dropped.
+    pub fn start(&self, key: &str) -> Result<SlotGuard<String>, ServerError> {
+        let stream = self.stream(key);
+        let max = self.limits.max_uploads_per_stream;
+        match self.uploads.acquire(stream.to_string(), max) {
+            Some(guard) => Ok(guard),
+            None => {
+                self.rejected.inc();
+                Err(ServerError::QuotaError(format!(
+                    "stream '{}': limit of {} concurrent uploads reached",
+                    stream,
+                    max.unwrap_or_default()
+                )))
+            }
+        }
+    }
+
+    /// Fails if an upload of `size` bytes is larger than allowed for the key.
+    pub fn check_size(&self, key: &str, size: u64) -> Result<(), ServerError> {
+        let max_size = if is_manifest(key) {
+            self.limits.max_manifest_size
+        } else {
+            self.limits.max_segment_size
+        };
+
+        if let Some(max_size) = max_size {
+            if size > max_size {
+                self.too_large.inc();
+                return Err(ServerError::SizeError(format!(
+                    "upload is larger than {} bytes",
+                    max_size
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Accounts for `size` bytes received for the key against the bandwidth of its stream.
+    pub fn throttle(&self, key: &str, size: usize) -> Result<(), ServerError> {
+        if let Some(bandwidth) = self.limits.max_stream_bandwidth {
+            let stream = self.stream(key);
+            let rate = bandwidth as f64;
+            if !self
+                .bandwidth
+                .take(stream.to_string(), size as f64, rate, rate)
+            {
+                self.rejected.inc();
+                return Err(ServerError::QuotaError(format!(
+                    "stream '{}': bandwidth of {} bytes/s exceeded",
+                    stream, bandwidth
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Registers a new key, rejecting it if it starts a stream above the stream limit.
+    pub fn open(&self, key: &str) -> Result<(), ServerError> {
+        let stream = self.stream(key);
+        let mut streams = self.streams.lock();
+        if let Some(keys) = streams.get_mut(stream) {
+            *keys += 1;
+            return Ok(());
+        }
+
+        if let Some(max_streams) = self.max_streams {
+            if streams.len() >= max_streams {
+                self.rejected.inc();
+                return Err(ServerError::QuotaError(format!(
+                    "stream '{}': limit of {} streams reached",
+                    stream, max_streams
+                )));
+            }
+        }
+
+        streams.insert(stream.to_string(), 1);
+        self.stored_streams.set(streams.len() as i64);
+        Ok(())
+    }
+
+    /// Accounts for `size` more bytes, failing if the byte quota would be exceeded.
+    pub fn reserve(&self, size: usize) -> Result<(), ServerError> {
+        let size = size as u64;
+        let total = self.bytes.fetch_add(size, Ordering::AcqRel) + size;
+        if let Some(max_bytes) = self.max_bytes {
+            if total > max_bytes {
+                self.bytes.fetch_sub(size, Ordering::AcqRel);
+                self.rejected.inc();
+                return Err(ServerError::QuotaError(format!(
+                    "byte quota of {} exceeded",
+                    max_bytes
+                )));
+            }
+        }
+
+        self.stored_bytes.add(size as i64);
+        Ok(())
+    }
+
+    /// Releases a key registered by `open` together with the bytes reserved for it.
+    pub fn release(&self, key: &str, size: usize) {
+        self.bytes.fetch_sub(size as u64, Ordering::AcqRel);
+        self.stored_bytes.sub(size as i64);
+
+        let stream = self.stream(key);
+        let mut streams = self.streams.lock();
+        if let Some(keys) = streams.get_mut(stream) {
+            *keys -= 1;
+            if *keys == 0 {
+                streams.remove(stream);
+            }
+        }
+        self.stored_streams.set(streams.len() as i64);
+    }
+
+    fn stream<'a>(&self, key: &'a str) -> &'a str {
+        stream_name(&self.prefix, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_limit() {
+        let quota = Quota::new(
+            "test_stream_limit",
+            "/a/",
+            None,
+            Some(1),
+            IngestLimits::default(),
+        );
+        quota.open("/a/s1/0/init.m4s").unwrap();
+        quota.open("/a/s1/1/init.m4s").unwrap();
+        assert!(quota.open("/a/s2/0/init.m4s").is_err());
+
+        quota.release("/a/s1/0/init.m4s", 0);
+        assert!(quota.open("/a/s2/0/init.m4s").is_err());
+        quota.release("/a/s1/1/init.m4s", 0);
+        quota.open("/a/s2/0/init.m4s").unwrap();
+    }
+
+    #[test]
+    fn byte_limit() {
+        let quota = Quota::new(
+            "test_byte_limit",
+            "",
+            Some(100),
+            None,
+            IngestLimits::default(),
+        );
+        quota.reserve(60).unwrap();
+        assert!(quota.reserve(41).is_err());
+        quota.reserve(40).unwrap();
+
+        quota.release("/s1/0/1.m4s", 60);
+        quota.reserve(60).unwrap();
+    }
+
+    #[test]
+    fn upload_limits() {
+        let limits = IngestLimits {
+            max_segment_size: Some(100),
+            max_manifest_size: Some(10),
+            max_uploads_per_stream: Some(1),
+            max_stream_bandwidth: None,
+        };
+        let quota = Quota::new("test_upload_limits", "", None, None, limits);
+        quota.check_size("/s1/0/1.m4s", 100).unwrap();
+        assert!(quota.check_size("/s1/0/1.m4s", 101).is_err());
+        assert!(quota.check_size("/s1/index.mpd", 11).is_err());
+
+        let upload = quota.start("/s1/0/1.m4s").unwrap();
+        assert!(quota.start("/s1/0/2.m4s").is_err());
+        quota.start("/s2/0/1.m4s").unwrap();
+        drop(upload);
+        quota.start("/s1/0/2.m4s").unwrap();
+    }
+}
@@ -1,7 +1,7 @@
-use crate::ingester::Ingester;
+use crate::errors::ServerError;
+use crate::ingester::{Ingester, UploadBody};
 use async_trait::async_trait;
 use http_body_util::BodyExt;
-use hyper::body::Incoming;
 use hyper::{Method, Request};
 use tracing::error;
 
@@ -16,11 +16,12 @@ impl SimpleIngester {
 
 #[async_trait]
 impl Ingester for SimpleIngester {
-    async fn ingest(&self, mut req: Request<Incoming>) {
+    async fn ingest(&self, mut req: Request<UploadBody>) -> Result<u64, ServerError> {
         if req.method() != Method::PUT {
-            return;
+            return Ok(0);
         }
 
+        let mut size = 0;
         while let Some(next) = req.frame().await {
             if next.is_err() {
                 if let Err(e) = next {
@@ -31,8 +32,9 @@ impl Ingester for SimpleIngester {
 
             let frame = next.unwrap();
             if frame.is_data() {
-                let _ = frame.into_data().unwrap();
+                size += frame.into_data().unwrap().len() as u64;
             }
         }
+        Ok(size)
     }
 }
@@ -0,0 +1,369 @@
+use crate::metrics::{self, Counter};
+use bytes::Bytes;
+use hyper::body::{Body, Frame, SizeHint};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Counts holders per key and refuses new ones above a limit.
+#[derive(Debug)]
+pub struct Slots<K: Hash + Eq + Clone> {
+    used: Mutex<HashMap<K, usize>>,
+}
+
+impl<K: Hash + Eq + Clone> Slots<K> {
+    pub fn new() -> Self {
+        Slots {
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a slot for the key, the slot is freed when the guard is dropped.
+    /// Always succeeds without a limit.
+    pub fn acquire(self: &Arc<Self>, key: K, max: Option<usize>) -> Option<SlotGuard<K>> {
+        let mut used = self.used.lock();
+        let count = used.entry(key.clone()).or_insert(0);
+        if let Some(max) = max {
+            if *count >= max {
+                if *count == 0 {
+                    used.remove(&key);
+                }
+                return None;
+            }
+        }
+
+        *count += 1;
+        Some(SlotGuard {
+            slots: Arc::clone(self),
+            key,
+        })
+    }
+
+    fn release(&self, key: &K) {
+        let mut used = self.used.lock();
+        if let Some(count) = used.get_mut(key) {
+            *count -= 1;
+            if *count == 0 {
+                used.remove(key);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SlotGuard<K: Hash + Eq + Clone> {
+    slots: Arc<Slots<K>>,
+    key: K,
+}
+
+impl<K: Hash + Eq + Clone> Drop for SlotGuard<K> {
+    fn drop(&mut self) {
+        self.slots.release(&self.key);
+    }
+}
+
+/// Limits connections in total and per source address.
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    total: Arc<Slots<()>>,
+    per_ip: Arc<Slots<IpAddr>>,
+    rejected_total: Counter,
+    rejected_per_ip: Counter,
+}
+
+pub struct ConnectionGuard {
+    _total: SlotGuard<()>,
+    _per_ip: Option<SlotGuard<IpAddr>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(listener: &str) -> Self {
+        ConnectionLimiter {
+            total: Arc::new(Slots::new()),
+            per_ip: Arc::new(Slots::new()),
+            rejected_total: rejected(listener, "connections"),
+            rejected_per_ip: rejected(listener, "connections_per_ip"),
+        }
+    }
+
+    pub fn acquire(
+        &self,
+        ip: Option<IpAddr>,
+        max_total: Option<usize>,
+        max_per_ip: Option<usize>,
+    ) -> Result<ConnectionGuard, Rejection> {
+        let total = match self.total.acquire((), max_total) {
+            Some(total) => total,
+            None => {
+                self.rejected_total.inc();
+                return Err(Rejection::Overloaded);
+            }
+        };
+
+        // connections over a Unix-domain socket have no address to limit
+        let per_ip = match ip {
+            Some(ip) => match self.per_ip.acquire(ip, max_per_ip) {
+                Some(per_ip) => Some(per_ip),
+                None => {
+                    self.rejected_per_ip.inc();
+                    return Err(Rejection::TooManyRequests);
+                }
+            },
+            None => None,
+        };
+
+        Ok(ConnectionGuard {
+            _total: total,
+            _per_ip: per_ip,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+    /// The server as a whole is at capacity, answered with 503.
+    Overloaded,
+    /// The client exceeded its own limit, answered with 429.
+    TooManyRequests,
+}
+
+/// Token bucket rate limiter per source address.
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: Buckets<IpAddr>,
+    rejected: Counter,
+}
+
+impl RateLimiter {
+    pub fn new(listener: &str) -> Self {
+        RateLimiter {
+            buckets: Buckets::new(),
+            rejected: rejected(listener, "rate"),
+        }
+    }
+
+    /// Takes a token for the address, returns false if its bucket is empty.
+    pub fn check(&self, ip: IpAddr, rate: f64, burst: f64) -> bool {
+        if !self.buckets.take(ip, 1.0, rate, burst) {
+            self.rejected.inc();
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Token buckets per key refilled at `rate` tokens per second up to `burst`.
+#[derive(Debug)]
+pub struct Buckets<K: Hash + Eq> {
+    state: Mutex<BucketState<K>>,
+}
+
+#[derive(Debug)]
+struct BucketState<K> {
+    buckets: HashMap<K, Bucket>,
+    pruned: Instant,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket is refilled to its burst and can be dropped.
+    full: Instant,
+}
+
+/// How often buckets that refilled are dropped.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+impl<K: Hash + Eq> Buckets<K> {
+    pub fn new() -> Self {
+        Buckets {
+            state: Mutex::new(BucketState {
+                buckets: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `amount` tokens from the bucket of the key, returns false if it holds fewer.
+    /// An amount above the burst is taken from a full bucket, leaving it in debt.
+    pub fn take(&self, key: K, amount: f64, rate: f64, burst: f64) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock();
+        if now.duration_since(state.pruned) >= PRUNE_INTERVAL {
+            state.buckets.retain(|_, b| b.full > now);
+            state.pruned = now;
+        }
+
+        let bucket = state.buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            full: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens < amount.min(burst) {
+            return false;
+        }
+
+        bucket.tokens -= amount;
+        bucket.full = now + Duration::from_secs_f64((burst - bucket.tokens) / rate);
+        true
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.state.lock().buckets.len()
+    }
+}
+
+/// Limits concurrent responses per stream.
+#[derive(Debug)]
+pub struct ViewerLimiter {
+    viewers: Arc<Slots<String>>,
+    rejected: Counter,
+}
+
+impl ViewerLimiter {
+    pub fn new(listener: &str) -> Self {
+        ViewerLimiter {
+            viewers: Arc::new(Slots::new()),
+            rejected: rejected(listener, "viewers"),
+        }
+    }
+
+    pub fn acquire(&self, stream: String, max: Option<usize>) -> Option<SlotGuard<String>> {
+        let guard = self.viewers.acquire(stream, max);
+        if guard.is_none() {
+            self.rejected.inc();
+        }
+        guard
+    }
+}
+
+fn rejected(listener: &str, reason: &str) -> Counter {
+    metrics::counter(
+        "server_limit_rejected_total",
+        "Connections and requests rejected by limits.",
+        &[("listener", listener), ("reason", reason)],
+    )
+}
+
+/// Response body holding a guard until the body is completed or dropped.
+pub struct Guarded<B, G> {
+    inner: B,
+    _guard: G,
+}
+
+impl<B, G> Guarded<B, G> {
+    pub fn new(inner: B, guard: G) -> Self {
+        Guarded {
+            inner,
+            _guard: guard,
+        }
+    }
+}
+
+impl<B, G> Body for Guarded<B, G>
+where
+    B: Body<Data = Bytes> + Unpin,
+    G: Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn slots_limit_and_release() {
+        let slots = Arc::new(Slots::new());
+        let a = slots.acquire("s1", Some(2)).unwrap();
+        let _b = slots.acquire("s1", Some(2)).unwrap();
+        assert!(slots.acquire("s1", Some(2)).is_none());
+        assert!(slots.acquire("s2", Some(2)).is_some());
+
+        drop(a);
+        assert!(slots.acquire("s1", Some(2)).is_some());
+        assert!(slots.acquire("s1", None).is_some());
+    }
+
+    #[test]
+    fn connections_total_and_per_ip() {
+        let limiter = ConnectionLimiter::new("test");
+        let a = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let b = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+
+        let _first = limiter.acquire(a, Some(2), Some(1)).unwrap();
+        assert_eq!(
+            limiter.acquire(a, Some(2), Some(1)).err(),
+            Some(Rejection::TooManyRequests)
+        );
+        let _second = limiter.acquire(b, Some(2), Some(1)).unwrap();
+        assert_eq!(
+            limiter.acquire(b, Some(2), None).err(),
+            Some(Rejection::Overloaded)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_refills_over_time() {
+        let limiter = RateLimiter::new("test");
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert!(limiter.check(ip, 2.0, 2.0));
+        assert!(limiter.check(ip, 2.0, 2.0));
+        assert!(!limiter.check(ip, 2.0, 2.0));
+
+        tokio::time::advance(std::time::Duration::from_millis(500)).await;
+        assert!(limiter.check(ip, 2.0, 2.0));
+        assert!(!limiter.check(ip, 2.0, 2.0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn buckets_take_amount_into_debt() {
+        let buckets = Buckets::new();
+        assert!(buckets.take("s1", 150.0, 100.0, 100.0));
+        tokio::time::advance(std::time::Duration::from_millis(1000)).await;
+        assert!(!buckets.take("s1", 60.0, 100.0, 100.0));
+        assert!(buckets.take("s1", 50.0, 100.0, 100.0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn buckets_pruned_once_refilled() {
+        let buckets = Buckets::new();
+        assert!(buckets.take("s1", 1.0, 1.0, 1.0));
+        assert!(buckets.take("s2", 1.0, 0.01, 1.0));
+        tokio::time::advance(PRUNE_INTERVAL).await;
+        assert!(buckets.take("s3", 1.0, 1.0, 1.0));
+        // s1 refilled after a second, s2 still waits for its token
+        assert_eq!(buckets.len(), 2);
+        assert!(!buckets.take("s2", 1.0, 0.01, 1.0));
+    }
+}
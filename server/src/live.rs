@@ -0,0 +1,58 @@
+use crate::config::{Changes, Setting};
+use crate::errors::ServerError;
+use common::logging::{LogArgs, Logging};
+use std::fs;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Holds the running configuration and applies changes of the config file to it.
+pub struct LiveSetting {
+    path: String,
+    log: Logging,
+    /// Logging flags of the command line, they keep precedence over the reloaded file.
+    log_args: LogArgs,
+    sender: watch::Sender<Arc<Setting>>,
+}
+
+impl LiveSetting {
+    pub fn new(path: String, setting: Setting, log: Logging, log_args: LogArgs) -> Self {
+        let (sender, _) = watch::channel(Arc::new(setting));
+        LiveSetting {
+            path,
+            log,
+            log_args,
+            sender,
+        }
+    }
+
+    pub fn current(&self) -> Arc<Setting> {
+        self.sender.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Setting>> {
+        self.sender.subscribe()
+    }
+
+    /// Re-reads the config file. An invalid file is rejected and the running
+    /// configuration stays untouched.
+    pub fn reload(&self) -> Result<Changes, ServerError> {
+        let data = fs::read_to_string(&self.path).map_err(|e| {
+            ServerError::ConfigError(format!("read config file '{}': {}", self.path, e))
+        })?;
+        let mut next = Setting::parse(&data)?;
+        next.log = self.log_args.apply(&next.log);
+        next.log.validate().map_err(ServerError::ConfigError)?;
+
+        let current = self.current();
+        let changes = current.changes(&next);
+        let setting = current.live(&next);
+
+        let level = setting.log.level().map_err(ServerError::ConfigError)?;
+        self.log
+            .set_level(level)
+            .map_err(ServerError::ConfigError)?;
+
+        self.sender.send_replace(Arc::new(setting));
+        Ok(changes)
+    }
+}
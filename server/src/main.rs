@@ -1,161 +1,456 @@
+mod access_log;
 mod api;
 mod cache;
+mod clock;
 mod config;
 mod errors;
+mod events;
+mod health;
 mod ingester;
+mod limits;
+mod live;
+mod metrics;
+mod replication;
+mod sendfile;
+mod streams;
+mod tenant;
+mod webhooks;
+mod websocket;
 
-use crate::cache::list_cache::ListCache;
-use crate::cache::map_cache::MapCache;
-use crate::cache::static_cache::ShardedStaticCache;
-use crate::cache::Cache;
+use crate::access_log::AccessLog;
 use crate::config::{CacheConfig, Setting};
 use crate::errors::ServerError;
-use crate::ingester::list_ingester::ListIngester;
-use crate::ingester::map_ingester::MapIngester;
-use crate::ingester::simple_ingester::SimpleIngester;
-use crate::ingester::Ingester;
-use api::http::server::{start_ingester, start_transmitter};
-use bytes::Bytes;
-use clap::Parser as ClapParser;
+use crate::events::Events;
+use crate::health::Health;
+use crate::limits::ConnectionLimiter;
+use crate::live::LiveSetting;
+use crate::replication::Replication;
+use crate::tenant::{Router, Tenant};
+use crate::webhooks::Webhooks;
+use api::framed::start_framed_ingester;
+use api::http::server::{start_admin, start_ingester, start_transmitter, Lifecycle};
+use api::http::service::{IngesterService, TransmitterService};
+use clap::{Parser as ClapParser, Subcommand};
+use common::logging::LogArgs;
+use common::runtime::Mode;
+use common::systemd::Readiness;
 use std::fs;
 use std::process;
 use std::sync::Arc;
-use tokio::sync::Notify;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Notify};
 use tokio::task::JoinSet;
 use tracing::{error, info};
-use tracing_subscriber::{fmt, layer::SubscriberExt};
+
+/// How often the status line reported to systemd is refreshed.
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(ClapParser, Debug)]
 #[command(version)]
 struct Cli {
-    #[arg(short, long, default_value = "config.toml")]
+    #[arg(short, long, default_value = "config.toml", global = true)]
     config: String,
 
     #[arg(short, long)]
     buffer: Option<usize>,
 
-    cache: String,
+    /// Cache to serve in the form `<type>:<name>`, overrides `server.cache`
+    cache: Option<String>,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn main() {
-    // Initialize tracing
-    let subscriber = tracing_subscriber::registry().with(
-        fmt::Layer::default()
-            .with_target(false)
-            .with_thread_names(false)
-            .with_ansi(true)
-            .with_line_number(false)
-            .with_file(false)
-            .with_thread_ids(false),
-    );
-    tracing::subscriber::set_global_default(subscriber)
-        .expect("Unable to set a global logger instance");
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Validate the config file and the selected cache, then exit
+    CheckConfig,
+}
 
+fn main() {
     let args = Cli::parse();
-    let setting = setting(&args);
+    let setting = setting(&args.config);
+
+    // the logger is set up from the file when it parses, so that its errors are logged
+    let log_config = match &setting {
+        Ok(setting) => args.log.apply(&setting.log),
+        Err(_) => args.log.apply(&Default::default()),
+    };
+    let log = common::logging::init("server", &log_config);
+    // before the runtime threads, the variables are removed from the environment
+    common::listenfd::adopt();
+    let mut setting = match setting {
+        Ok(setting) => setting,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    if log_config.validate().is_err() {
+        // already reported by the logger
+        process::exit(1);
+    }
+    setting.log = log_config;
+
+    let tenants = setting.tenants(args.cache.as_deref());
+    if let Some(Command::CheckConfig) = args.command {
+        check_config(&args, &setting, tenants);
+    }
+
+    let tenants = match tenants {
+        Ok(tenants) => tenants,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    let setting = Arc::new(LiveSetting::new(
+        args.config.clone(),
+        setting,
+        log.clone(),
+        args.log.clone(),
+    ));
 
-    let runtime = common::runtime::build(setting.runtime.threads);
+    // in thread per core mode the runtime of the main thread only runs the admin server
+    // and signal handling
+    let config = setting.current().runtime.clone();
+    let (runtime, cores) = match config.mode {
+        Mode::MultiThread => (common::runtime::build(config.threads), None),
+        Mode::ThreadPerCore => match common::runtime::cores(config.threads, &config.cores) {
+            Ok(cores) => (common::runtime::build_current_thread(), Some(cores)),
+            Err(e) => {
+                error!("runtime: {}", e);
+                process::exit(1);
+            }
+        },
+    };
     if let Err(e) = runtime {
         error!("failed to create runtime: {}", e);
         process::exit(1);
     }
 
     let runtime = runtime.unwrap();
-    let result = runtime.block_on(start(setting, &args.cache, args.buffer.clone()));
+    let result = runtime.block_on(start(setting, tenants, args.buffer, cores));
     if let Err(e) = result {
         error!("{}", e);
+        log.flush();
         process::exit(1);
     }
 
     info!("done");
+    log.flush();
 }
 
-fn setting(args: &Cli) -> Setting {
-    let data = fs::read_to_string(args.config.as_str());
-    if data.is_err() {
-        error!("config file '{}' does not exist", args.config);
-        process::exit(1);
+fn setting(path: &str) -> Result<Setting, ServerError> {
+    let data = fs::read_to_string(path)
+        .map_err(|_| ServerError::ConfigError(format!("config file '{}' does not exist", path)))?;
+    Setting::parse(data.as_str())
+}
+
+fn check_config(
+    args: &Cli,
+    setting: &Setting,
+    tenants: Result<Vec<config::Tenant>, ServerError>,
+) -> ! {
+    let has_cache = args.cache.is_some() || setting.server.cache.is_some();
+    if has_cache || !setting.tenant.is_empty() {
+        let tenants = match tenants {
+            Ok(tenants) => tenants,
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        };
+
+        for tenant in tenants.iter() {
+            if let Ok(CacheConfig::Static(config)) = setting.cache.config(&tenant.cache) {
+                if let Err(e) = fs::metadata(&config.file_path) {
+                    error!(
+                        "cache.static '{}': '{}': {}",
+                        config.name, config.file_path, e
+                    );
+                    process::exit(1);
+                }
+            }
+        }
     }
 
-    let data = data.unwrap();
-    let setting: Setting = toml::from_str(data.as_str()).unwrap();
-    setting
+    info!(
+        "config file '{}' is valid, available caches: {}",
+        args.config,
+        setting.cache.names().join(", ")
+    );
+    process::exit(0);
 }
 
 async fn start(
-    setting: Setting,
-    cache_name: &str,
+    live: Arc<LiveSetting>,
+    tenants: Vec<config::Tenant>,
     buffer: Option<usize>,
+    cores: Option<Vec<usize>>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let cache_config = setting.cache.config(cache_name);
-    let (cache, ingester) = match cache_config {
-        CacheConfig::Static(config) => {
-            info!("cache: {:?}", config);
-            let file_content = tokio::fs::read(config.file_path)
-                .await
-                .map_err(|e| ServerError::StorageError(format!("Failed to read file: {}", e)))?;
-            let data = Bytes::copy_from_slice(&file_content);
-            let cache = Arc::new(ShardedStaticCache::new(
-                config.shards,
-                config.streams,
-                config.tracks,
-                config.segments,
-                data,
-            )) as Arc<dyn Cache + Send + Sync>;
-            let ingester = Arc::new(SimpleIngester::new()) as Arc<dyn Ingester + Send + Sync>;
-            (cache, ingester)
+    let setting = live.current();
+    let shards = cores.as_ref().map_or(1, |cores| cores.len());
+
+    let notifier = Arc::new(Notify::new());
+    let grace = setting.server.grace_periods();
+    common::systemd::run(notifier.clone(), grace);
+    tokio::spawn(reload_on_signal(Arc::clone(&live)));
+    tokio::spawn(report_status(STATUS_INTERVAL));
+
+    // ready once the admin, ingester, framed ingester and transmitter listeners of every
+    // core accept
+    let per_core = 2 + usize::from(setting.ingester.framed.is_some());
+    let listeners = usize::from(setting.admin.is_some()) + per_core * shards;
+    let lifecycle = Lifecycle {
+        notifier,
+        readiness: Readiness::new(listeners),
+        grace_period: grace.shutdown,
+        drain_delay: setting.server.drain_delay(),
+    };
+
+    // the admin server answers the probes while the caches load and the listeners drain
+    let health = Arc::new(Health::new(setting.server.memory_budget));
+    let stop_admin = Arc::new(Notify::new());
+    let admin = setting.admin.as_ref().map(|admin| {
+        let addr = admin.addr.clone();
+        let socket = admin.socket.clone();
+        let live = Arc::clone(&live);
+        let lifecycle = lifecycle.clone();
+        let stop = Arc::clone(&stop_admin);
+        let health = Arc::clone(&health);
+        tokio::spawn(async move {
+            let notifier = lifecycle.notifier.clone();
+            let result = start_admin(lifecycle, stop, addr, socket, live, health).await;
+            if let Err(e) = result {
+                notifier.notify_waiters();
+                error!("admin server: {}", e);
+            }
+        })
+    });
+
+    // stream events of the ingesters feed the event endpoint of the transmitter and the
+    // webhooks
+    let events = match (&setting.transmitter.events, &setting.webhooks) {
+        (None, None) => None,
+        (feed, _) => {
+            let buffer = feed.as_ref().map_or(1024, |feed| feed.buffer);
+            Some(Arc::new(Events::new(buffer)))
         }
-        CacheConfig::List(config) => {
-            info!("cache: {:?}", config);
-            let cache = Arc::new(ListCache::new(config.copy));
-            let ingester =
-                Arc::new(ListIngester::new(Arc::clone(&cache))) as Arc<dyn Ingester + Send + Sync>;
-            let cache = Arc::clone(&cache) as Arc<dyn Cache + Send + Sync>;
-            (cache, ingester)
+    };
+    let mut routes = Vec::with_capacity(tenants.len());
+    for tenant in tenants.iter() {
+        info!("tenant: {}", tenant.name);
+        let cache_config = setting.cache.config(&tenant.cache)?;
+        let tenant = Tenant::new(
+            tenant,
+            cache_config,
+            &setting.ingester,
+            &setting.streams,
+            shards,
+            events.clone(),
+        )
+        .await?;
+        tokio::spawn(Arc::clone(&tenant.streams).watch_idle());
+        let tenant = Arc::new(tenant);
+        tokio::spawn(Arc::clone(&tenant).follow(live.subscribe()));
+        routes.push(tenant);
+    }
+    let router = Arc::new(Router::new(routes));
+    let ingester_log = access_log("ingester", &setting.ingester.access_log)?;
+    let transmitter_log = access_log("transmitter", &setting.transmitter.access_log)?;
+    health.loaded();
+    let webhooks = setting.webhooks.clone().map(|config| {
+        let webhooks = Arc::new(Webhooks::new(config));
+        if let Some(events) = &events {
+            tokio::spawn(Arc::clone(&webhooks).run(events.subscribe()));
         }
-        CacheConfig::Map(config) => {
-            info!("cache: {:?}", config);
-            let cache = Arc::new(MapCache::new(config.preallocate));
-            let ingester =
-                Arc::new(MapIngester::new(Arc::clone(&cache))) as Arc<dyn Ingester + Send + Sync>;
-            let cache = Arc::clone(&cache) as Arc<dyn Cache + Send + Sync>;
-            (cache, ingester)
+        webhooks
+    });
+    let replication = setting
+        .ingester
+        .replication
+        .as_ref()
+        .map(|config| Arc::new(Replication::new(config)));
+
+    let mut set = JoinSet::new();
+    let listeners = Listeners {
+        lifecycle,
+        setting: Arc::clone(&setting),
+        buffer,
+        ingester: IngesterService::new(
+            Arc::clone(&router),
+            ingester_log,
+            events.clone(),
+            webhooks,
+            replication,
+        ),
+        transmitter: TransmitterService::new(router, live.subscribe(), transmitter_log, events),
+        connections: Arc::new(ConnectionLimiter::new("transmitter")),
+        receiver: live.subscribe(),
+    };
+    match cores {
+        None => {
+            set.spawn(serve(listeners, None));
         }
-        _ => {
-            panic!("Invalid cache config");
+        Some(cores) => {
+            let handles = common::runtime::spawn_per_core(&cores, move |core| {
+                serve(listeners.clone(), Some(core))
+            })?;
+            set.spawn(async move {
+                let _ = tokio::task::spawn_blocking(move || {
+                    for handle in handles {
+                        let _ = handle.join();
+                    }
+                })
+                .await;
+            });
         }
-    };
+    }
 
-    let notifier = Arc::new(Notify::new());
-    common::systemd::run(notifier.clone());
+    set.join_all().await;
+    if let Some(admin) = admin {
+        stop_admin.notify_one();
+        let _ = admin.await;
+    }
 
-    let mut set = JoinSet::new();
+    Ok(())
+}
+
+/// Everything the ingester and transmitter listeners of a core share with the others.
+#[derive(Clone)]
+struct Listeners {
+    lifecycle: Lifecycle,
+    setting: Arc<Setting>,
+    buffer: Option<usize>,
+    ingester: IngesterService,
+    transmitter: TransmitterService,
+    connections: Arc<ConnectionLimiter>,
+    receiver: watch::Receiver<Arc<Setting>>,
+}
+
+/// Runs the ingesters and the transmitter, on their own listeners when `core` is set.
+async fn serve(listeners: Listeners, core: Option<usize>) {
+    let setting = &listeners.setting;
+    if let Some(core) = core {
+        common::systemd::heartbeat(format!("core-{}", core));
+    }
 
-    let addr = setting.ingester.addr;
-    let max_buffer_size = buffer.clone();
-    let notifier_clone = notifier.clone();
-    set.spawn(async move {
-        let ingester = Arc::clone(&ingester);
-        let result = start_ingester(notifier_clone.clone(), addr, max_buffer_size, ingester).await;
+    let addr = setting.ingester.addr.clone();
+    let socket = setting.ingester.socket.clone();
+    let header_timeout = setting.ingester.header_timeout();
+    let lifecycle = listeners.lifecycle.clone();
+    let notifier = lifecycle.notifier.clone();
+    let service = listeners.ingester.clone();
+    let buffer = listeners.buffer;
+    let ingester = tokio::spawn(async move {
+        let result = start_ingester(
+            lifecycle,
+            addr,
+            socket,
+            core,
+            buffer,
+            header_timeout,
+            service,
+        )
+        .await;
         if let Err(e) = result {
-            notifier_clone.notify_waiters();
+            notifier.notify_waiters();
             error!("ingester server: {}", e);
         }
     });
 
-    let addr = setting.transmitter.addr;
-    let max_buffer_size = buffer.clone();
-    let notifier_clone = notifier.clone();
-    set.spawn(async move {
-        let cache = Arc::clone(&cache);
-        let result = start_transmitter(notifier_clone.clone(), addr, max_buffer_size, cache).await;
+    let framed = setting.ingester.framed.clone().map(|framed| {
+        let lifecycle = listeners.lifecycle.clone();
+        let notifier = lifecycle.notifier.clone();
+        let service = listeners.ingester.clone();
+        tokio::spawn(async move {
+            let result =
+                start_framed_ingester(lifecycle, framed.addr, framed.socket, core, service).await;
+            if let Err(e) = result {
+                notifier.notify_waiters();
+                error!("framed ingester server: {}", e);
+            }
+        })
+    });
+
+    let notifier = listeners.lifecycle.notifier.clone();
+    let transmitter = tokio::spawn(async move {
+        let result = start_transmitter(
+            listeners.lifecycle,
+            core,
+            listeners.buffer,
+            listeners.transmitter,
+            listeners.connections,
+            listeners.receiver,
+        )
+        .await;
         if let Err(e) = result {
-            notifier_clone.notify_waiters();
+            notifier.notify_waiters();
             error!("transmitter server: {}", e);
         }
     });
 
-    set.join_all().await;
+    let _ = tokio::join!(ingester, transmitter);
+    if let Some(framed) = framed {
+        let _ = framed.await;
+    }
+}
 
-    Ok(())
+fn access_log(
+    listener: &str,
+    config: &Option<config::AccessLog>,
+) -> Result<Option<Arc<AccessLog>>, ServerError> {
+    match config {
+        Some(config) => Ok(Some(Arc::new(AccessLog::new(listener, config)?))),
+        None => Ok(None),
+    }
+}
+
+/// Publishes the streams, viewers and delivered bytes per second as the systemd status.
+async fn report_status(period: Duration) {
+    let mut ticker = tokio::time::interval(period);
+    let mut delivered = metrics::sum("server_delivered_bytes_total");
+    loop {
+        ticker.tick().await;
+        let total = metrics::sum("server_delivered_bytes_total");
+        let rate = (total - delivered) as f64 / period.as_secs_f64();
+        delivered = total;
+        common::systemd::status(&format!(
+            "streams: {}, viewers: {}, delivering {}/s",
+            metrics::sum("server_streams"),
+            metrics::sum("server_viewers"),
+            human_bytes(rate)
+        ));
+    }
+}
+
+fn human_bytes(bytes: f64) -> String {
+    let units = ["B", "KB", "MB", "GB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < units.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, units[unit])
+}
+
+/// Re-reads the config file on SIGUSR1.
+async fn reload_on_signal(live: Arc<LiveSetting>) {
+    let mut usr1 = signal(SignalKind::user_defined1()).unwrap();
+    while usr1.recv().await.is_some() {
+        info!("received usr1 signal, reloading config");
+        match live.reload() {
+            Ok(changes) => info!(
+                "config reload: applied: {:?}, restart required: {:?}",
+                changes.applied, changes.restart
+            ),
+            Err(e) => error!("config reload: {}", e),
+        }
+    }
 }
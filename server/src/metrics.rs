@@ -0,0 +1,263 @@
+use bytes::Bytes;
+use hyper::body::{Body, Frame, SizeHint};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+
+static REGISTRY: OnceLock<Mutex<BTreeMap<&'static str, Family>>> = OnceLock::new();
+
+enum Series {
+    Counter(Arc<AtomicU64>),
+    Gauge(Arc<AtomicI64>),
+}
+
+struct Family {
+    help: &'static str,
+    kind: &'static str,
+    series: BTreeMap<String, Series>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Gauge(Arc<AtomicI64>);
+
+impl Gauge {
+    pub fn add(&self, value: i64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, value: i64) {
+        self.0.fetch_sub(value, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    /// Increments the gauge until the returned guard is dropped.
+    pub fn hold(&self) -> GaugeGuard {
+        self.add(1);
+        GaugeGuard(self.clone())
+    }
+}
+
+#[derive(Debug)]
+pub struct GaugeGuard(Gauge);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.sub(1);
+    }
+}
+
+/// Returns the counter with the given name and labels, registering it on first use.
+/// Lookups take a global lock, so hot paths should keep the returned handle.
+pub fn counter(name: &'static str, help: &'static str, labels: &[(&str, &str)]) -> Counter {
+    let series = register(name, help, "counter", labels, || {
+        Series::Counter(Arc::new(AtomicU64::new(0)))
+    });
+    match series {
+        Series::Counter(value) => Counter(value),
+        Series::Gauge(_) => panic!("metric {} is registered as a gauge", name),
+    }
+}
+
+/// Returns the gauge with the given name and labels, registering it on first use.
+pub fn gauge(name: &'static str, help: &'static str, labels: &[(&str, &str)]) -> Gauge {
+    let series = register(name, help, "gauge", labels, || {
+        Series::Gauge(Arc::new(AtomicI64::new(0)))
+    });
+    match series {
+        Series::Gauge(value) => Gauge(value),
+        Series::Counter(_) => panic!("metric {} is registered as a counter", name),
+    }
+}
+
+fn register(
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    labels: &[(&str, &str)],
+    new: impl FnOnce() -> Series,
+) -> Series {
+    let registry = REGISTRY.get_or_init(|| Mutex::new(BTreeMap::new()));
+    let mut registry = registry.lock().unwrap();
+    let family = registry.entry(name).or_insert_with(|| Family {
+        help,
+        kind,
+        series: BTreeMap::new(),
+    });
+
+    let series = family
+        .series
+        .entry(format_labels(labels))
+        .or_insert_with(new);
+    match series {
+        Series::Counter(value) => Series::Counter(Arc::clone(value)),
+        Series::Gauge(value) => Series::Gauge(Arc::clone(value)),
+    }
+}
+
+/// Returns the sum of all series of the metric, 0 if it is not registered.
+pub fn sum(name: &str) -> i64 {
+    let registry = match REGISTRY.get() {
+        Some(registry) => registry.lock().unwrap(),
+        None => return 0,
+    };
+
+    match registry.get(name) {
+        Some(family) => family
+            .series
+            .values()
+            .map(|series| match series {
+                Series::Counter(value) => value.load(Ordering::Relaxed) as i64,
+                Series::Gauge(value) => value.load(Ordering::Relaxed),
+            })
+            .sum(),
+        None => 0,
+    }
+}
+
+/// Renders all registered metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut out = String::new();
+    let registry = match REGISTRY.get() {
+        Some(registry) => registry.lock().unwrap(),
+        None => return out,
+    };
+
+    for (name, family) in registry.iter() {
+        let _ = writeln!(out, "# HELP {} {}", name, family.help);
+        let _ = writeln!(out, "# TYPE {} {}", name, family.kind);
+        for (labels, series) in family.series.iter() {
+            let _ = match series {
+                Series::Counter(value) => {
+                    writeln!(out, "{}{} {}", name, labels, value.load(Ordering::Relaxed))
+                }
+                Series::Gauge(value) => {
+                    writeln!(out, "{}{} {}", name, labels, value.load(Ordering::Relaxed))
+                }
+            };
+        }
+    }
+
+    out
+}
+
+/// Response body adding the bytes of its data frames to a counter.
+pub struct Counted<B> {
+    inner: B,
+    bytes: Counter,
+}
+
+impl<B> Counted<B> {
+    pub fn new(inner: B, bytes: Counter) -> Self {
+        Counted { inner, bytes }
+    }
+}
+
+impl<B> Body for Counted<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                self.bytes.add(data.len() as u64);
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let labels = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect::<Vec<String>>();
+    format!("{{{}}}", labels.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_labeled_series() {
+        counter("test_render_total", "Test counter.", &[("tenant", "a")]).add(3);
+        counter("test_render_total", "Test counter.", &[("tenant", "b\"")]).inc();
+        gauge("test_render_bytes", "Test gauge.", &[]).set(-2);
+
+        let out = render();
+        assert!(
+            out.contains("# TYPE test_render_total counter\n"),
+            "{}",
+            out
+        );
+        assert!(
+            out.contains("test_render_total{tenant=\"a\"} 3\n"),
+            "{}",
+            out
+        );
+        assert!(
+            out.contains("test_render_total{tenant=\"b\\\"\"} 1\n"),
+            "{}",
+            out
+        );
+        assert!(out.contains("test_render_bytes -2\n"), "{}", out);
+    }
+
+    #[test]
+    fn sum_series_and_hold_gauge() {
+        counter("test_sum_total", "Test counter.", &[("tenant", "a")]).add(3);
+        counter("test_sum_total", "Test counter.", &[("tenant", "b")]).add(4);
+        let gauge = gauge("test_sum_viewers", "Test gauge.", &[]);
+
+        let guard = gauge.hold();
+        assert_eq!(sum("test_sum_total"), 7);
+        assert_eq!(sum("test_sum_viewers"), 1);
+        drop(guard);
+        assert_eq!(sum("test_sum_viewers"), 0);
+        assert_eq!(sum("test_sum_missing"), 0);
+    }
+}
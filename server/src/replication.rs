@@ -0,0 +1,482 @@
+use crate::config;
+use crate::errors::ServerError;
+use crate::metrics::{self, Counter, Gauge};
+use bytes::Bytes;
+use hyper::body::{Body, Frame};
+use hyper::header;
+use hyper::{Method, Request};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use parking_lot::Mutex;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// Header marking a copy, peers do not replicate it any further.
+pub const REPLICA: &str = "X-Replica";
+
+/// Headers of an upload passed on to the peers.
+const FORWARDED: [header::HeaderName; 3] =
+    [header::HOST, header::CONTENT_LENGTH, header::CONTENT_TYPE];
+
+/// Streams the uploads to the ingesters of the peer servers as they are received.
+pub struct Replication {
+    peers: Vec<Arc<Peer>>,
+    /// Addresses of the peers, resolved at the start.
+    addresses: Vec<IpAddr>,
+    client: Client<HttpConnector, Replica>,
+    buffer: usize,
+}
+
+impl Replication {
+    pub fn new(config: &config::Replication) -> Self {
+        let mut connector = HttpConnector::new();
+        connector.set_connect_timeout(Some(Duration::from_secs(config.connect_timeout)));
+        connector.set_nodelay(true);
+        let retry_after = Duration::from_secs(config.retry_after);
+        let mut addresses = Vec::new();
+        for peer in config.peers.iter() {
+            match peer.to_socket_addrs() {
+                Ok(resolved) => addresses.extend(resolved.map(|addr| addr.ip())),
+                Err(e) => warn!("replication: {}: {}", peer, e),
+            }
+        }
+        Replication {
+            peers: config
+                .peers
+                .iter()
+                .map(|addr| Arc::new(Peer::new(addr, retry_after)))
+                .collect(),
+            addresses,
+            client: Client::builder(TokioExecutor::new()).build(connector),
+            buffer: config.buffer,
+        }
+    }
+
+    /// Whether the request is a copy sent by one of the peers, the header alone is
+    /// not trusted.
+    pub fn is_replica<B>(&self, req: &Request<B>, remote: Option<IpAddr>) -> bool {
+        req.headers().contains_key(REPLICA)
+            && remote.is_some_and(|remote| self.addresses.contains(&remote))
+    }
+
+    /// Opens a copy of the upload on every healthy peer, `None` for requests that are
+    /// not replicated.
+    pub fn tee<B>(&self, req: &Request<B>) -> Option<Tee> {
+        if req.method() != Method::PUT || req.headers().contains_key(REPLICA) {
+            return None;
+        }
+
+        let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+        let mut copies = Vec::with_capacity(self.peers.len());
+        for peer in self.peers.iter() {
+            if peer.is_down() {
+                peer.skipped.inc();
+                continue;
+            }
+
+            let mut copy = Request::builder()
+                .method(Method::PUT)
+                .uri(format!("http://{}{}", peer.addr, path))
+                .header(REPLICA, "1");
+            for name in FORWARDED.iter() {
+                if let Some(value) = req.headers().get(name) {
+                    copy = copy.header(name, value);
+                }
+            }
+
+            let (sender, receiver) = mpsc::channel(self.buffer);
+            let state = Arc::new(State::default());
+            let body = Replica {
+                receiver,
+                state: Arc::clone(&state),
+                pending: peer.pending.clone(),
+            };
+            let copy = match copy.body(body) {
+                Ok(copy) => copy,
+                Err(e) => {
+                    warn!("replication: {}: {}: {}", peer.addr, path, e);
+                    continue;
+                }
+            };
+
+            let response = self.client.request(copy);
+            let task = (Arc::clone(peer), path.to_string(), Arc::clone(&state));
+            tokio::spawn(async move {
+                let (peer, path, state) = task;
+                let result = match response.await {
+                    Ok(response) if response.status().is_success() => Ok(()),
+                    Ok(response) => Err(format!("replied {}", response.status())),
+                    Err(e) => Err(e.to_string()),
+                };
+                peer.finished(&path, &state, result);
+            });
+
+            copies.push(Copy {
+                peer: Arc::clone(peer),
+                sender: Some(sender),
+                state,
+            });
+        }
+
+        if copies.is_empty() {
+            return None;
+        }
+        Some(Tee(Arc::new(Mutex::new(Some(Copies(copies))))))
+    }
+}
+
+/// Copies of an upload, handed to the ingester as a request extension.
+#[derive(Clone)]
+pub struct Tee(Arc<Mutex<Option<Copies>>>);
+
+impl Tee {
+    pub fn take(&self) -> Option<Copies> {
+        self.0.lock().take()
+    }
+}
+
+/// Open copies of an upload, dropping them before [`Copies::complete`] aborts them on
+/// the peers.
+pub struct Copies(Vec<Copy>);
+
+struct Copy {
+    peer: Arc<Peer>,
+    /// `None` once the copy is dropped.
+    sender: Option<mpsc::Sender<Bytes>>,
+    state: Arc<State>,
+}
+
+impl Copies {
+    /// Passes a chunk of the upload on to the peers.
+    pub fn send(&mut self, data: &Bytes) {
+        for copy in self.0.iter_mut() {
+            let sender = match &copy.sender {
+                Some(sender) => sender,
+                None => continue,
+            };
+            match sender.try_send(data.clone()) {
+                Ok(()) => copy.peer.pending.add(data.len() as i64),
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    // the peer falls behind, it must not hold back the upload
+                    copy.state.lagging.store(true, Ordering::Release);
+                    copy.sender = None;
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => copy.sender = None,
+            }
+        }
+    }
+
+    /// Ends the copies after the upload completed.
+    pub fn complete(self) {
+        let now = Instant::now();
+        for copy in self.0.iter() {
+            let _ = copy.state.completed.set(now);
+        }
+    }
+}
+
+impl Drop for Copies {
+    fn drop(&mut self) {
+        for copy in self.0.iter() {
+            if copy.state.completed.get().is_none() {
+                copy.state.aborted.store(true, Ordering::Release);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// End of the upload, not set for failed uploads.
+    completed: OnceLock<Instant>,
+    /// Set when the upload failed.
+    aborted: AtomicBool,
+    /// Set when the copy was dropped because the peer fell behind.
+    lagging: AtomicBool,
+}
+
+/// Ingester of a peer and its health.
+struct Peer {
+    addr: String,
+    retry_after: Duration,
+    /// Time the peer gets copies again after a failure.
+    down_until: Mutex<Option<Instant>>,
+    up: Gauge,
+    pending: Gauge,
+    lag: Gauge,
+    replicated: Counter,
+    failed: Counter,
+    dropped: Counter,
+    skipped: Counter,
+    aborted: Counter,
+}
+
+impl Peer {
+    fn new(addr: &str, retry_after: Duration) -> Self {
+        let result = |result| {
+            metrics::counter(
+                "server_replication_uploads_total",
+                "Copies of uploads sent to a peer by result.",
+                &[("peer", addr), ("result", result)],
+            )
+        };
+        let up = metrics::gauge(
+            "server_replication_peer_up",
+            "Whether a peer receives copies of the uploads.",
+            &[("peer", addr)],
+        );
+        up.set(1);
+        Peer {
+            addr: addr.to_string(),
+            retry_after,
+            down_until: Mutex::new(None),
+            up,
+            pending: metrics::gauge(
+                "server_replication_pending_bytes",
+                "Bytes of uploads received but not yet sent to a peer.",
+                &[("peer", addr)],
+            ),
+            lag: metrics::gauge(
+                "server_replication_lag_ms",
+                "Time from the end of the last upload until a peer acknowledged its copy.",
+                &[("peer", addr)],
+            ),
+            replicated: result("replicated"),
+            failed: result("failed"),
+            dropped: result("dropped"),
+            skipped: result("skipped"),
+            aborted: result("aborted"),
+        }
+    }
+
+    fn is_down(&self) -> bool {
+        let mut down_until = self.down_until.lock();
+        match *down_until {
+            Some(until) if until > Instant::now() => true,
+            Some(_) => {
+                *down_until = None;
+                self.up.set(1);
+                info!("replication: {}: retrying", self.addr);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn down(&self) {
+        let mut down_until = self.down_until.lock();
+        if down_until.is_none() {
+            warn!(
+                "replication: {}: no copies for {:?}",
+                self.addr, self.retry_after
+            );
+        }
+        *down_until = Some(Instant::now() + self.retry_after);
+        self.up.set(0);
+    }
+
+    fn finished(&self, path: &str, state: &State, result: Result<(), String>) {
+        if state.lagging.load(Ordering::Acquire) {
+            warn!("replication: {}: {}: peer fell behind", self.addr, path);
+            self.dropped.inc();
+            self.down();
+            return;
+        }
+
+        match result {
+            Ok(()) => {
+                self.replicated.inc();
+                if let Some(completed) = state.completed.get() {
+                    self.lag.set(completed.elapsed().as_millis() as i64);
+                }
+            }
+            // the upload failed, the peer rejects the copy as well
+            Err(_) if state.aborted.load(Ordering::Acquire) => self.aborted.inc(),
+            Err(e) => {
+                warn!("replication: {}: {}: {}", self.addr, path, e);
+                self.failed.inc();
+                self.down();
+            }
+        }
+    }
+}
+
+/// Body of a copy, fails unless the upload completed so that the peer discards it.
+pub struct Replica {
+    receiver: mpsc::Receiver<Bytes>,
+    state: Arc<State>,
+    pending: Gauge,
+}
+
+impl Body for Replica {
+    type Data = Bytes;
+    type Error = ServerError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match self.receiver.poll_recv(cx) {
+            Poll::Ready(Some(data)) => {
+                self.pending.sub(data.len() as i64);
+                Poll::Ready(Some(Ok(Frame::data(data))))
+            }
+            Poll::Ready(None) if self.state.completed.get().is_some() => Poll::Ready(None),
+            Poll::Ready(None) => Poll::Ready(Some(Err(ServerError::NetworkError(
+                "upload aborted".to_string(),
+            )))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for Replica {
+    fn drop(&mut self) {
+        while let Ok(data) = self.receiver.try_recv() {
+            self.pending.sub(data.len() as i64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Full};
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::Response;
+    use hyper_util::rt::TokioIo;
+    use tokio::net::TcpListener;
+
+    /// Local stand-in for the ingester of a peer that passes on the replica header and
+    /// the body of every upload, `Err` for uploads that broke off.
+    async fn peer() -> (
+        String,
+        mpsc::UnboundedReceiver<(bool, Result<Bytes, String>)>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let sender = sender.clone();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let sender = sender.clone();
+                    async move {
+                        let replica = req.headers().contains_key(REPLICA);
+                        let body = req.into_body().collect().await;
+                        let body = body.map(|b| b.to_bytes()).map_err(|e| e.to_string());
+                        sender.send((replica, body)).unwrap();
+                        Ok::<_, hyper::Error>(Response::new(Full::new(Bytes::new())))
+                    }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        (addr, receiver)
+    }
+
+    fn replication(peers: Vec<String>) -> Replication {
+        Replication::new(&config::Replication {
+            peers,
+            buffer: 4,
+            connect_timeout: 1,
+            retry_after: 60,
+        })
+    }
+
+    fn upload(method: Method) -> Request<()> {
+        Request::builder()
+            .method(method)
+            .uri("/s1/0/1.m4s")
+            .header(header::HOST, "t.example.com")
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn replica_only_from_peers() {
+        let replication = replication(vec!["127.0.0.1:8445".to_string()]);
+        let mut replicated = upload(Method::PUT);
+        replicated
+            .headers_mut()
+            .insert(REPLICA, "1".parse().unwrap());
+        let peer = Some("127.0.0.1".parse().unwrap());
+        assert!(replication.is_replica(&replicated, peer));
+        assert!(!replication.is_replica(&replicated, Some("10.1.2.3".parse().unwrap())));
+        assert!(!replication.is_replica(&replicated, None));
+        assert!(!replication.is_replica(&upload(Method::PUT), peer));
+    }
+
+    #[tokio::test]
+    async fn stream_upload_to_peer() {
+        let (addr, mut uploads) = peer().await;
+        let replication = replication(vec![addr]);
+        assert!(replication.tee(&upload(Method::GET)).is_none());
+        let mut replicated = upload(Method::PUT);
+        replicated
+            .headers_mut()
+            .insert(REPLICA, "1".parse().unwrap());
+        assert!(replication.tee(&replicated).is_none());
+
+        let mut copies = replication
+            .tee(&upload(Method::PUT))
+            .unwrap()
+            .take()
+            .unwrap();
+        copies.send(&Bytes::from_static(b"moof"));
+        copies.send(&Bytes::from_static(b"mdat"));
+        copies.complete();
+        let (replica, body) = uploads.recv().await.unwrap();
+        assert!(replica);
+        assert_eq!(body.unwrap(), Bytes::from_static(b"moofmdat"));
+
+        // a failed upload breaks off the copy, the peer gets no complete body
+        let mut copies = replication
+            .tee(&upload(Method::PUT))
+            .unwrap()
+            .take()
+            .unwrap();
+        copies.send(&Bytes::from_static(b"moof"));
+        drop(copies);
+        let mut copies = replication
+            .tee(&upload(Method::PUT))
+            .unwrap()
+            .take()
+            .unwrap();
+        copies.send(&Bytes::from_static(b"next"));
+        copies.complete();
+        loop {
+            if let (_, Ok(body)) = uploads.recv().await.unwrap() {
+                assert_eq!(body, Bytes::from_static(b"next"));
+                break;
+            }
+        }
+        assert!(!replication.peers[0].is_down());
+    }
+
+    #[tokio::test]
+    async fn skip_failed_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let replication = replication(vec![addr]);
+
+        let tee = replication.tee(&upload(Method::PUT)).unwrap();
+        tee.take().unwrap().complete();
+        while !replication.peers[0].is_down() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(replication.tee(&upload(Method::PUT)).is_none());
+    }
+}
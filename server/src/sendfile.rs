@@ -0,0 +1,262 @@
+use bytes::Bytes;
+use common::socket::Stream;
+use parking_lot::RwLock;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+/// Memory range of a loaded region.
+type Range = (usize, usize, Weak<FileRegion>);
+
+/// Memory ranges of the loaded regions, dropped regions are removed once the next one is
+/// loaded.
+static REGIONS: RwLock<Vec<Range>> = RwLock::new(Vec::new());
+
+/// Changes with every loaded region, connections copy the ranges again when it did.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Contents of a file loaded into memory. Writes of that memory to a [`Sendfile`]
+/// connection are sent from the file instead, the file must not change while it is
+/// served.
+#[derive(Debug)]
+pub struct FileRegion {
+    file: File,
+    data: Bytes,
+}
+
+impl FileRegion {
+    pub async fn load(path: impl AsRef<Path>) -> io::Result<Arc<FileRegion>> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).await?;
+        let region = Arc::new(FileRegion {
+            file: file.into_std().await,
+            data: Bytes::from(data),
+        });
+
+        let mut regions = REGIONS.write();
+        regions.retain(|(_, _, region)| region.strong_count() > 0);
+        let start = region.data.as_ptr() as usize;
+        regions.push((start, start + region.data.len(), Arc::downgrade(&region)));
+        GENERATION.fetch_add(1, Ordering::Release);
+        Ok(region)
+    }
+
+    pub fn data(&self) -> Bytes {
+        self.data.clone()
+    }
+}
+
+/// Copy of the memory ranges of the loaded regions kept by a connection, so that its
+/// writes are looked up without the lock.
+struct Regions {
+    generation: usize,
+    ranges: Vec<Range>,
+}
+
+impl Regions {
+    fn new() -> Self {
+        Regions {
+            generation: GENERATION.load(Ordering::Acquire),
+            ranges: REGIONS.read().clone(),
+        }
+    }
+
+    /// Returns the region holding the memory of `buf` and the offset of `buf` in it.
+    fn find(&mut self, buf: &[u8]) -> Option<(Arc<FileRegion>, u64)> {
+        if buf.is_empty() {
+            return None;
+        }
+        let generation = GENERATION.load(Ordering::Acquire);
+        if generation != self.generation {
+            *self = Regions::new();
+        }
+        let start = buf.as_ptr() as usize;
+        let end = start + buf.len();
+        self.ranges
+            .iter()
+            .filter(|(region_start, region_end, _)| *region_start <= start && end <= *region_end)
+            // the memory of a dropped region may belong to anything else by now
+            .find_map(|(region_start, _, region)| {
+                Some((region.upgrade()?, (start - region_start) as u64))
+            })
+    }
+}
+
+/// Connection sending the memory of file regions with `sendfile` when enabled. Other
+/// writes, and all of them when disabled, go to the stream as they are.
+pub struct Sendfile {
+    inner: Stream,
+    /// `None` when disabled.
+    regions: Option<Regions>,
+}
+
+impl Sendfile {
+    pub fn new(inner: Stream, enabled: bool) -> Self {
+        Sendfile {
+            inner,
+            regions: enabled.then(Regions::new),
+        }
+    }
+}
+
+impl AsyncRead for Sendfile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Sendfile {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_write_vectored(cx, &[io::IoSlice::new(buf)])
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if let Some(regions) = &mut this.regions {
+            for (i, buf) in bufs.iter().enumerate() {
+                let (region, offset) = match regions.find(buf) {
+                    Some(found) => found,
+                    None => continue,
+                };
+                // the buffers before the region first, the caller comes back for the rest
+                if i > 0 {
+                    return Pin::new(&mut this.inner).poll_write_vectored(cx, &bufs[..i]);
+                }
+                match this
+                    .inner
+                    .poll_sendfile(cx, &region.file, offset, buf.len())
+                {
+                    // the file got shorter than its memory
+                    Poll::Ready(Ok(0)) => break,
+                    result => return result,
+                }
+            }
+        }
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::http::service::TransmitterService;
+    use crate::tenant::{Router, Tenant};
+    use crate::websocket::Connection;
+    use hyper::server::conn::http1;
+    use hyper_util::rt::TokioIo;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream, UnixStream};
+    use tokio::sync::watch;
+
+    #[tokio::test]
+    async fn send_region_from_file() {
+        let path = std::env::temp_dir().join(format!("sendfile-{}", std::process::id()));
+        std::fs::write(&path, b"0123456789").unwrap();
+        let region = FileRegion::load(&path).await.unwrap();
+        let data = region.data();
+
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let mut client = Sendfile::new(Stream::Unix(client), true);
+        // the file changed on disk, so the bytes read back show where they were sent from
+        std::fs::write(&path, b"abcdefghij").unwrap();
+        let parts: [&[u8]; 3] = [b"head:", &data[2..6], b":tail"];
+        let mut written = 0;
+        while written < 14 {
+            let bufs = unwritten(&parts, written);
+            written += client.write_vectored(&bufs).await.unwrap();
+        }
+        drop(client);
+
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"head:cdef:tail");
+
+        drop(region);
+        assert!(Regions::new().find(&data[2..6]).is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn transmitter_sends_static_segment_from_file() {
+        let path = Tenant::test_file();
+        std::fs::write(&path, b"0123456789").unwrap();
+        let tenant = Tenant::for_test("static:file").await;
+        let mut setting = Tenant::test_setting("static:file");
+        setting.transmitter.sendfile = true;
+        let router = Arc::new(Router::new(vec![Arc::new(tenant)]));
+        let (_setting, setting) = watch::channel(Arc::new(setting));
+        let service = TransmitterService::new(router, setting, None, None);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let io = TokioIo::new(Sendfile::new(Stream::Tcp(stream), true));
+            let (connection, _released) = Connection::new(watch::channel(false).1);
+            let service = service.with_connection(None, connection);
+            http1::Builder::new()
+                .serve_connection(io, service)
+                .await
+                .unwrap();
+        });
+
+        // the file changed on disk, so the body shows that it was sent from the file
+        std::fs::write(&path, b"abcdefghij").unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let request = "GET /stream-0/0/0.m4s HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\n";
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(
+            response.ends_with("\r\nA\r\nabcdefghij\r\n0\r\n\r\n"),
+            "{}",
+            response
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn unwritten<'a>(parts: &[&'a [u8]], mut written: usize) -> Vec<io::IoSlice<'a>> {
+        let mut bufs = Vec::new();
+        for part in parts {
+            if written >= part.len() {
+                written -= part.len();
+                continue;
+            }
+            bufs.push(io::IoSlice::new(&part[written..]));
+            written = 0;
+        }
+        bufs
+    }
+}
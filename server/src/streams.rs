@@ -0,0 +1,415 @@
+use crate::cache::manifest::is_manifest;
+use crate::events::{is_init, Events};
+use crate::metrics::{self, Counter};
+use crate::tenant::split_stream;
+use parking_lot::Mutex;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+use tokio::time::Instant;
+use tracing::info;
+
+/// Announced keys kept for subscribers that read slower than uploads start.
+const ANNOUNCED_KEYS: usize = 1024;
+
+/// Streams of a tenant with their representations, uploads and viewers. A stream is the
+/// first path segment after the tenant prefix, a representation the segment after it.
+#[derive(Debug)]
+pub struct StreamRegistry {
+    tenant: String,
+    prefix: String,
+    /// Time without uploads after which a stream is idle.
+    idle_timeout: Duration,
+    /// Time without uploads after which all keys of a stream are removed.
+    purge_after: Option<Duration>,
+    streams: Mutex<HashMap<String, Arc<Stream>>>,
+    /// Keys of segments whose upload started, once their cell takes data.
+    announced: broadcast::Sender<Arc<str>>,
+    events: Option<Arc<Events>>,
+    purged: Counter,
+}
+
+#[derive(Debug)]
+struct Stream {
+    name: String,
+    representations: Mutex<BTreeSet<String>>,
+    edges: Mutex<HashMap<String, Edge>>,
+    last_ingest: Mutex<Instant>,
+    /// Set by the first upload and cleared once the stream is idle.
+    active: AtomicBool,
+    /// Uploads in progress.
+    uploads: AtomicUsize,
+    /// Keys in the cache, until their retention ends or the stream is purged.
+    keys: AtomicUsize,
+    viewers: AtomicUsize,
+    purge: watch::Sender<bool>,
+}
+
+impl StreamRegistry {
+    pub fn new(
+        tenant: &str,
+        prefix: &str,
+        idle_timeout: Duration,
+        purge_after: Option<Duration>,
+        events: Option<Arc<Events>>,
+    ) -> Self {
+        StreamRegistry {
+            tenant: tenant.to_string(),
+            prefix: prefix.to_string(),
+            idle_timeout,
+            purge_after,
+            streams: Mutex::new(HashMap::new()),
+            announced: broadcast::channel(ANNOUNCED_KEYS).0,
+            events,
+            purged: metrics::counter(
+                "server_streams_purged_total",
+                "Streams whose keys were removed because they got no uploads.",
+                &[("tenant", tenant)],
+            ),
+        }
+    }
+
+    /// Records the start of an upload of the key, the stream is registered on its first
+    /// upload.
+    pub fn ingest(&self, key: &str) -> Ingest {
+        let (name, path) = split_stream(&self.prefix, key);
+        let stream = {
+            let mut streams = self.streams.lock();
+            let stream = streams
+                .entry(name.to_string())
+                .or_insert_with(|| Arc::new(Stream::new(name)));
+            // counted under the lock so that the sweep does not drop the stream
+            stream.uploads.fetch_add(1, Ordering::AcqRel);
+            stream.keys.fetch_add(1, Ordering::AcqRel);
+            Arc::clone(stream)
+        };
+
+        let representation = path
+            .rsplit_once('/')
+            .map(|(representation, _)| representation);
+        if let Some(representation) = representation {
+            let mut representations = stream.representations.lock();
+            if !representations.contains(representation) {
+                representations.insert(representation.to_string());
+            }
+        }
+        *stream.last_ingest.lock() = Instant::now();
+        let events = self.events.as_ref().map(|events| {
+            let started = Started {
+                tenant: self.tenant.clone(),
+                path: path.to_string(),
+            };
+            (Arc::clone(events), started)
+        });
+        Ingest {
+            stream,
+            key: key.into(),
+            representation: representation
+                .filter(|_| !is_manifest(key))
+                .map(|representation| representation.to_string()),
+            announced: self.announced.clone(),
+            events,
+        }
+    }
+
+    /// Subscribes to the keys of the segments of the tenant as their uploads start.
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<str>> {
+        self.announced.subscribe()
+    }
+
+    /// Returns true if the stream had uploads within the idle timeout.
+    pub fn is_active(&self, stream: &str) -> bool {
+        self.streams
+            .lock()
+            .get(stream)
+            .is_some_and(|stream| stream.active.load(Ordering::Acquire))
+    }
+
+    /// Returns the latest init segment and segment of a representation of the stream.
+    pub fn edge(&self, stream: &str, representation: &str) -> Edge {
+        let stream = match self.streams.lock().get(stream) {
+            Some(stream) => Arc::clone(stream),
+            None => return Edge::default(),
+        };
+        let edges = stream.edges.lock();
+        edges.get(representation).cloned().unwrap_or_default()
+    }
+
+    /// Counts a viewer of the stream of the key until the returned guard is dropped,
+    /// `None` for streams without uploads.
+    pub fn view(&self, key: &str) -> Option<Viewer> {
+        let name = split_stream(&self.prefix, key).0;
+        let stream = Arc::clone(self.streams.lock().get(name)?);
+        stream.viewers.fetch_add(1, Ordering::AcqRel);
+        Some(Viewer { stream })
+    }
+
+    /// Reports idle streams, purges them and forgets streams without keys, forever.
+    pub async fn watch_idle(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            ticker.tick().await;
+            self.sweep(Instant::now());
+        }
+    }
+
+    fn sweep(&self, now: Instant) {
+        let mut idle = Vec::new();
+        let mut purged = Vec::new();
+        self.streams.lock().retain(|_, stream| {
+            if stream.uploads.load(Ordering::Acquire) > 0 {
+                return true;
+            }
+            let quiet = now.duration_since(*stream.last_ingest.lock());
+            if quiet >= self.idle_timeout && stream.active.swap(false, Ordering::AcqRel) {
+                idle.push(stream.name.clone());
+            }
+            if stream.keys.load(Ordering::Acquire) == 0 {
+                // kept until it is idle, so that the next upload does not start it again
+                return stream.active.load(Ordering::Acquire);
+            }
+            match self.purge_after {
+                Some(purge_after) if quiet >= purge_after => {
+                    if stream.active.swap(false, Ordering::AcqRel) {
+                        idle.push(stream.name.clone());
+                    }
+                    purged.push(Arc::clone(stream));
+                    false
+                }
+                _ => true,
+            }
+        });
+
+        if let Some(events) = &self.events {
+            for stream in idle {
+                events.stream_idle(&self.tenant, &stream);
+            }
+        }
+
+        for stream in purged {
+            info!(
+                "tenant {}: stream {}: no uploads for {:?}, purging {} keys of representations \
+                 {:?} with {} viewers",
+                self.tenant,
+                stream.name,
+                self.purge_after.unwrap_or_default(),
+                stream.keys.load(Ordering::Acquire),
+                stream.representations.lock(),
+                stream.viewers.load(Ordering::Acquire)
+            );
+            stream.purge.send_replace(true);
+            self.purged.inc();
+        }
+    }
+}
+
+impl Stream {
+    fn new(name: &str) -> Self {
+        Stream {
+            name: name.to_string(),
+            representations: Mutex::new(BTreeSet::new()),
+            edges: Mutex::new(HashMap::new()),
+            last_ingest: Mutex::new(Instant::now()),
+            active: AtomicBool::new(false),
+            uploads: AtomicUsize::new(0),
+            keys: AtomicUsize::new(0),
+            viewers: AtomicUsize::new(0),
+            purge: watch::channel(false).0,
+        }
+    }
+}
+
+/// Keys of the latest uploads of a representation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Edge {
+    pub init: Option<Arc<str>>,
+    pub segment: Option<Arc<str>>,
+}
+
+/// Upload of a key in progress.
+pub struct Ingest {
+    stream: Arc<Stream>,
+    key: Arc<str>,
+    /// Representation of a segment, `None` for manifests and keys outside of one.
+    representation: Option<String>,
+    announced: broadcast::Sender<Arc<str>>,
+    events: Option<(Arc<Events>, Started)>,
+}
+
+/// Tenant and path within the stream of an upload, for its events.
+struct Started {
+    tenant: String,
+    path: String,
+}
+
+impl Ingest {
+    /// Publishes the start of the upload, and of the stream on its first upload, once
+    /// its cell is in the cache, so viewers reacting to the event find the key.
+    pub fn started(&self) {
+        let first = !self.stream.active.swap(true, Ordering::AcqRel);
+        if let Some((events, started)) = &self.events {
+            if first {
+                events.stream_started(&started.tenant, &self.stream.name);
+            }
+            events.ingest_started(&started.tenant, &self.stream.name, &started.path, &self.key);
+        }
+    }
+
+    /// Announces the segment to the subscribers once its cell is in the cache.
+    pub fn announce(&self) {
+        let representation = match &self.representation {
+            Some(representation) => representation,
+            None => return,
+        };
+        {
+            let mut edges = self.stream.edges.lock();
+            let edge = edges.entry(representation.clone()).or_default();
+            if is_init(&self.key) {
+                edge.init = Some(Arc::clone(&self.key));
+            } else {
+                edge.segment = Some(Arc::clone(&self.key));
+            }
+        }
+        // fails only without subscribers
+        let _ = self.announced.send(Arc::clone(&self.key));
+    }
+
+    /// Ends the upload, the key stays in the cache until the returned expiry is dropped.
+    pub fn expiry(self) -> Expiry {
+        Expiry {
+            stream: Arc::clone(&self.stream),
+            purge: self.stream.purge.subscribe(),
+        }
+    }
+}
+
+impl Drop for Ingest {
+    fn drop(&mut self) {
+        *self.stream.last_ingest.lock() = Instant::now();
+        self.stream.uploads.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Key of a stream kept in the cache.
+pub struct Expiry {
+    stream: Arc<Stream>,
+    purge: watch::Receiver<bool>,
+}
+
+impl Expiry {
+    /// Completes when the stream is purged.
+    pub async fn purged(&mut self) {
+        // fails only without the sender, which lives as long as the stream held here
+        let _ = self.purge.wait_for(|purged| *purged).await;
+    }
+}
+
+impl Drop for Expiry {
+    fn drop(&mut self) {
+        self.stream.keys.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Viewer of a stream, counted until dropped.
+pub struct Viewer {
+    stream: Arc<Stream>,
+}
+
+impl Drop for Viewer {
+    fn drop(&mut self) {
+        self.stream.viewers.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Kind;
+
+    const IDLE: Duration = Duration::from_secs(10);
+
+    #[tokio::test(start_paused = true)]
+    async fn purge_idle_stream() {
+        let registry = StreamRegistry::new("t", "", IDLE, Some(Duration::from_secs(10)), None);
+        let mut expiry = registry.ingest("/s1/0/1.m4s").expiry();
+        let upload = registry.ingest("/s1/1/1.m4s");
+        let viewer = registry.view("/s1/1/1.m4s").unwrap();
+        assert!(registry.view("/s2/0/1.m4s").is_none());
+        {
+            let stream = &registry.streams.lock()["s1"];
+            assert_eq!(
+                stream.representations.lock().iter().collect::<Vec<_>>(),
+                ["0", "1"]
+            );
+            assert_eq!(stream.viewers.load(Ordering::Acquire), 1);
+        }
+
+        // not purged while an upload is in progress
+        tokio::time::advance(Duration::from_secs(10)).await;
+        registry.sweep(Instant::now());
+        assert_eq!(registry.streams.lock().len(), 1);
+
+        let upload = upload.expiry();
+        tokio::time::advance(Duration::from_secs(5)).await;
+        registry.sweep(Instant::now());
+        assert_eq!(registry.streams.lock().len(), 1);
+        tokio::time::advance(Duration::from_secs(5)).await;
+        registry.sweep(Instant::now());
+        assert_eq!(registry.streams.lock().len(), 0);
+        expiry.purged().await;
+        drop((expiry, upload, viewer));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forget_stream_without_keys() {
+        let registry = StreamRegistry::new("t", "/a/", IDLE, None, None);
+        let expiry = registry.ingest("/a/s1/index.mpd").expiry();
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        registry.sweep(Instant::now());
+        assert_eq!(registry.streams.lock().len(), 1);
+
+        drop(expiry);
+        registry.sweep(Instant::now());
+        assert_eq!(registry.streams.lock().len(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn start_and_idle_stream() {
+        let events = Arc::new(Events::new(16));
+        let mut receiver = events.subscribe();
+        let mut next = || {
+            let event = receiver.try_recv().ok()?;
+            Some((event.event, event.stream.clone()))
+        };
+        let registry = StreamRegistry::new("t", "", IDLE, None, Some(events));
+        let upload = registry.ingest("/s1/0/1.m4s");
+        assert!(!registry.is_active("s1"));
+        upload.started();
+        let second = registry.ingest("/s1/0/2.m4s");
+        second.started();
+        drop(second.expiry());
+        assert!(registry.is_active("s1"));
+        assert_eq!(next(), Some((Kind::StreamStarted, "s1".to_string())));
+        assert_eq!(next(), Some((Kind::SegmentStarted, "s1".to_string())));
+        assert_eq!(next(), Some((Kind::SegmentStarted, "s1".to_string())));
+
+        // idle once the last upload ended the idle timeout ago
+        tokio::time::advance(IDLE).await;
+        registry.sweep(Instant::now());
+        assert_eq!(next(), None);
+        drop(upload.expiry());
+        tokio::time::advance(IDLE / 2).await;
+        registry.sweep(Instant::now());
+        assert_eq!(next(), None);
+        tokio::time::advance(IDLE / 2).await;
+        registry.sweep(Instant::now());
+        assert_eq!(next(), Some((Kind::StreamIdle, "s1".to_string())));
+        assert!(!registry.is_active("s1"));
+        assert_eq!(registry.streams.lock().len(), 0);
+
+        registry.ingest("/s1/0/3.m4s").started();
+        assert_eq!(next(), Some((Kind::StreamStarted, "s1".to_string())));
+    }
+}
@@ -0,0 +1,533 @@
+use crate::cache::list_cache::ListCache;
+use crate::cache::map_cache::MapCache;
+use crate::cache::static_cache::ShardedStaticCache;
+use crate::cache::Cache;
+use crate::config::{self, CacheConfig, Setting};
+use crate::errors::ServerError;
+use crate::events::Events;
+use crate::ingester::list_ingester::ListIngester;
+use crate::ingester::map_ingester::MapIngester;
+use crate::ingester::quota::Quota;
+use crate::ingester::simple_ingester::SimpleIngester;
+use crate::ingester::Ingester;
+use crate::metrics::{self, Counter, Gauge};
+use crate::sendfile::FileRegion;
+use crate::streams::StreamRegistry;
+use hyper::{header, Request};
+use rustc_hash::FxHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::info;
+
+pub struct Tenant {
+    pub name: String,
+    hosts: Vec<String>,
+    path_prefix: Option<String>,
+    shards: Vec<Shard>,
+    pub streams: Arc<StreamRegistry>,
+    pub metrics: TenantMetrics,
+    /// Retention of the keys completed from now on, changed by reloads of the setting.
+    retention: watch::Sender<Duration>,
+}
+
+/// Part of the cache of a tenant together with the ingester writing into it.
+pub struct Shard {
+    pub cache: Arc<dyn Cache + Send + Sync>,
+    pub ingester: Arc<dyn Ingester + Send + Sync>,
+}
+
+pub struct TenantMetrics {
+    pub ingest_requests: Counter,
+    pub ingest_errors: Counter,
+    /// Uploads aborted because their body stalled or the upload took too long.
+    pub ingest_stalled: Counter,
+    pub ingest_too_long: Counter,
+    pub delivery_requests: Counter,
+    pub delivery_not_found: Counter,
+    pub viewers: Gauge,
+    pub delivered_bytes: Counter,
+}
+
+impl TenantMetrics {
+    fn new(tenant: &str) -> Self {
+        let labels = [("tenant", tenant)];
+        TenantMetrics {
+            ingest_requests: metrics::counter(
+                "server_ingest_requests_total",
+                "Requests received by the ingester.",
+                &labels,
+            ),
+            ingest_errors: metrics::counter(
+                "server_ingest_errors_total",
+                "Ingester requests completed with an error.",
+                &labels,
+            ),
+            ingest_stalled: aborted(tenant, "stalled"),
+            ingest_too_long: aborted(tenant, "too_long"),
+            delivery_requests: metrics::counter(
+                "server_delivery_requests_total",
+                "Requests received by the transmitter.",
+                &labels,
+            ),
+            delivery_not_found: metrics::counter(
+                "server_delivery_not_found_total",
+                "Transmitter requests for keys missing in the cache.",
+                &labels,
+            ),
+            viewers: metrics::gauge(
+                "server_viewers",
+                "Responses being delivered by the transmitter.",
+                &labels,
+            ),
+            delivered_bytes: metrics::counter(
+                "server_delivered_bytes_total",
+                "Bytes of response bodies sent by the transmitter.",
+                &labels,
+            ),
+        }
+    }
+}
+
+fn aborted(tenant: &str, reason: &str) -> Counter {
+    metrics::counter(
+        "server_ingest_aborted_total",
+        "Uploads aborted because they stalled or took too long.",
+        &[("tenant", tenant), ("reason", reason)],
+    )
+}
+
+impl Tenant {
+    /// Creates the tenant with its list or map cache split into `shards` shards, a static
+    /// cache is never split.
+    pub async fn new(
+        config: &config::Tenant,
+        cache_config: CacheConfig,
+        ingest: &config::Ingester,
+        streams: &config::Streams,
+        shards: usize,
+        events: Option<Arc<Events>>,
+    ) -> Result<Tenant, ServerError> {
+        let prefix = config.path_prefix.clone().unwrap_or_default();
+        let streams = Arc::new(StreamRegistry::new(
+            &config.name,
+            &prefix,
+            streams.idle_timeout(),
+            streams.purge_after(),
+            events,
+        ));
+        let quota = Arc::new(Quota::new(
+            &config.name,
+            &prefix,
+            config.max_bytes,
+            config.max_streams,
+            ingest.limits.clone(),
+        ));
+        let timeouts = ingest.timeouts();
+        let (retention, retained) = watch::channel(Duration::from_secs(config.retention));
+
+        let shards = match cache_config {
+            CacheConfig::Static(config) => {
+                info!("cache: {:?}", config);
+                let region = FileRegion::load(&config.file_path).await.map_err(|e| {
+                    ServerError::StorageError(format!("Failed to read file: {}", e))
+                })?;
+                let cache = Arc::new(ShardedStaticCache::new(
+                    config.shards,
+                    config.streams,
+                    config.tracks,
+                    config.segments,
+                    region,
+                )) as Arc<dyn Cache + Send + Sync>;
+                let ingester = Arc::new(SimpleIngester::new()) as Arc<dyn Ingester + Send + Sync>;
+                vec![Shard { cache, ingester }]
+            }
+            CacheConfig::List(config) => {
+                info!("cache: {:?}, shards: {}", config, shards);
+                (0..shards)
+                    .map(|_| {
+                        let cache = Arc::new(ListCache::new(config.copy));
+                        let ingester = Arc::new(ListIngester::new(
+                            Arc::clone(&cache),
+                            Arc::clone(&quota),
+                            Arc::clone(&streams),
+                            retained.clone(),
+                            timeouts,
+                        ))
+                            as Arc<dyn Ingester + Send + Sync>;
+                        Shard { cache, ingester }
+                    })
+                    .collect()
+            }
+            CacheConfig::Map(config) => {
+                info!("cache: {:?}, shards: {}", config, shards);
+                (0..shards)
+                    .map(|_| {
+                        let cache = Arc::new(MapCache::new(config.preallocate));
+                        let ingester = Arc::new(MapIngester::new(
+                            Arc::clone(&cache),
+                            Arc::clone(&quota),
+                            Arc::clone(&streams),
+                            retained.clone(),
+                            timeouts,
+                        ))
+                            as Arc<dyn Ingester + Send + Sync>;
+                        Shard { cache, ingester }
+                    })
+                    .collect()
+            }
+        };
+
+        Ok(Tenant {
+            name: config.name.clone(),
+            hosts: config.hosts.iter().map(|h| h.to_lowercase()).collect(),
+            path_prefix: config.path_prefix.clone(),
+            shards,
+            streams,
+            metrics: TenantMetrics::new(&config.name),
+            retention,
+        })
+    }
+
+    /// Applies the retention of the tenant of the same name in each reloaded setting,
+    /// keys already completed keep the retention they were stored with.
+    pub async fn follow(self: Arc<Self>, mut setting: watch::Receiver<Arc<Setting>>) {
+        while setting.changed().await.is_ok() {
+            let retention = setting
+                .borrow_and_update()
+                .tenant
+                .iter()
+                .find(|tenant| tenant.name == self.name)
+                .map(|tenant| Duration::from_secs(tenant.retention));
+            if let Some(retention) = retention {
+                self.retention.send_if_modified(|current| {
+                    let changed = *current != retention;
+                    *current = retention;
+                    changed
+                });
+            }
+        }
+    }
+
+    /// Returns the shard holding the stream of the key.
+    pub fn shard(&self, key: &str) -> &Shard {
+        if self.shards.len() == 1 {
+            return &self.shards[0];
+        }
+
+        let mut hasher = FxHasher::default();
+        self.stream(key).hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// Returns the stream of a key, the first path segment after the tenant prefix.
+    pub fn stream<'a>(&self, key: &'a str) -> &'a str {
+        stream_name(self.path_prefix.as_deref().unwrap_or_default(), key)
+    }
+
+    /// Returns the stream of a key and the path within the stream.
+    pub fn split<'a>(&self, key: &'a str) -> (&'a str, &'a str) {
+        split_stream(self.path_prefix.as_deref().unwrap_or_default(), key)
+    }
+
+    fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        if !self.hosts.is_empty() {
+            match host {
+                Some(host) => {
+                    if !self.hosts.iter().any(|h| h.eq_ignore_ascii_case(host)) {
+                        return false;
+                    }
+                }
+                None => return false,
+            }
+        }
+
+        match &self.path_prefix {
+            Some(prefix) => match path.strip_prefix(prefix.trim_end_matches('/')) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/'),
+                None => false,
+            },
+            None => true,
+        }
+    }
+}
+
+/// Routes requests to tenants by the `Host` header and the path prefix.
+pub struct Router {
+    tenants: Vec<Arc<Tenant>>,
+}
+
+impl Router {
+    pub fn new(tenants: Vec<Arc<Tenant>>) -> Self {
+        Router { tenants }
+    }
+
+    pub fn route<B>(&self, req: &Request<B>) -> Option<&Arc<Tenant>> {
+        let host = req
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| req.uri().host())
+            .map(strip_port);
+        let path = req.uri().path();
+        self.tenants.iter().find(|t| t.matches(host, path))
+    }
+}
+
+pub fn stream_name<'a>(prefix: &str, key: &'a str) -> &'a str {
+    split_stream(prefix, key).0
+}
+
+/// Splits a key into its stream and the path within the stream.
+pub fn split_stream<'a>(prefix: &str, key: &'a str) -> (&'a str, &'a str) {
+    let path = key
+        .strip_prefix(prefix.trim_end_matches('/'))
+        .unwrap_or(key);
+    let path = path.trim_start_matches('/');
+    path.split_once('/').unwrap_or((path, ""))
+}
+
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        // IPv6 literal
+        return match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        };
+    }
+
+    match host.rsplit_once(':') {
+        Some((host, _)) => host,
+        None => host,
+    }
+}
+
+#[cfg(test)]
+impl Tenant {
+    /// File served by the `static:file` cache of the tenants made for tests.
+    pub fn test_file() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("tenant-static-{}", std::process::id()))
+    }
+
+    /// Setting of the tenants made for tests.
+    pub fn test_setting(cache: &str) -> Setting {
+        Setting::parse(&format!(
+            r#"
+            [ingester]
+            addr = ":8445"
+
+            [transmitter]
+            addr = ":8446"
+
+            [[cache.list]]
+            name = "copy"
+            copy = true
+
+            [[cache.map]]
+            name = "m"
+            preallocate = 0
+
+            [[cache.static]]
+            name = "file"
+            file_path = "{}"
+            shards = 1
+            streams = 1
+            tracks = 1
+            segments = 1
+
+            [[tenant]]
+            name = "t"
+            cache = "{}"
+            retention = 30
+            "#,
+            Tenant::test_file().display(),
+            cache
+        ))
+        .unwrap()
+    }
+
+    /// Tenant `t` keeping keys for 30 seconds in one of the caches `list:copy`, `map:m`
+    /// or `static:file`.
+    pub async fn for_test(cache: &str) -> Tenant {
+        Tenant::for_test_with(&Tenant::test_setting(cache), 1, None).await
+    }
+
+    pub async fn for_test_with(
+        setting: &Setting,
+        shards: usize,
+        events: Option<Arc<Events>>,
+    ) -> Tenant {
+        let tenant = &setting.tenant[0];
+        let cache_config = setting.cache.config(&tenant.cache).unwrap();
+        Tenant::new(
+            tenant,
+            cache_config,
+            &setting.ingester,
+            &setting.streams,
+            shards,
+            events,
+        )
+        .await
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Kind;
+    use bytes::Bytes;
+    use futures_util::stream;
+    use http_body_util::{BodyExt, StreamBody};
+    use hyper::body::Frame;
+
+    async fn router() -> Router {
+        let setting = config::Setting::parse(
+            r#"
+            [ingester]
+            addr = ":8445"
+
+            [transmitter]
+            addr = ":8446"
+
+            [[cache.list]]
+            name = "copy"
+            copy = true
+
+            [[cache.map]]
+            name = "100KB"
+            preallocate = 100000
+
+            [[tenant]]
+            name = "a"
+            hosts = ["a.example.com"]
+            cache = "list:copy"
+            retention = 30
+
+            [[tenant]]
+            name = "b"
+            path_prefix = "/b"
+            cache = "map:100KB"
+
+            [[tenant]]
+            name = "fallback"
+            cache = "list:copy"
+            "#,
+        )
+        .unwrap();
+
+        let mut tenants = Vec::new();
+        for tenant in setting.tenants(None).unwrap() {
+            let cache_config = setting.cache.config(&tenant.cache).unwrap();
+            let tenant = Tenant::new(
+                &tenant,
+                cache_config,
+                &setting.ingester,
+                &setting.streams,
+                1,
+                None,
+            );
+            tenants.push(Arc::new(tenant.await.unwrap()));
+        }
+        Router::new(tenants)
+    }
+
+    fn request(host: &str, path: &str) -> Request<()> {
+        Request::builder()
+            .uri(path)
+            .header(header::HOST, host)
+            .body(())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn route_by_host_and_prefix() {
+        let router = router().await;
+        let route = |host, path| router.route(&request(host, path)).unwrap().name.clone();
+
+        assert_eq!(route("a.example.com:8446", "/b/s1/0/1.m4s"), "a");
+        assert_eq!(route("A.EXAMPLE.COM", "/s1/0/1.m4s"), "a");
+        assert_eq!(route("localhost:8446", "/b/s1/0/1.m4s"), "b");
+        assert_eq!(route("localhost:8446", "/bb/s1/0/1.m4s"), "fallback");
+        assert_eq!(route("[::1]:8446", "/s1/0/1.m4s"), "fallback");
+    }
+
+    #[tokio::test]
+    async fn retention_follows_reloads() {
+        let tenant = Arc::new(Tenant::for_test("list:copy").await);
+        let mut setting = Tenant::test_setting("list:copy");
+        let (sender, receiver) = watch::channel(Arc::new(setting.clone()));
+        let mut retention = tenant.retention.subscribe();
+        tokio::spawn(Arc::clone(&tenant).follow(receiver));
+
+        setting.tenant[0].retention = 60;
+        sender.send_replace(Arc::new(setting));
+        retention.changed().await.unwrap();
+        assert_eq!(*retention.borrow(), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn stream_stays_on_its_shard() {
+        let tenant = Tenant::for_test_with(&Tenant::test_setting("list:copy"), 4, None).await;
+
+        let shard = |key: &str| tenant.shard(key) as *const Shard;
+        assert_eq!(shard("/s1/index.mpd"), shard("/s1/0/1.m4s"));
+        let used: std::collections::HashSet<_> = (0..32)
+            .map(|i| shard(&format!("/s{}/0/1.m4s", i)))
+            .collect();
+        assert!(used.len() > 1);
+    }
+
+    #[test]
+    fn stream_name_after_prefix() {
+        assert_eq!(stream_name("/b/", "/b/s1/0/1.m4s"), "s1");
+        assert_eq!(stream_name("", "/s1/index.mpd"), "s1");
+        assert_eq!(stream_name("/b", "/other/s1"), "other");
+        assert_eq!(split_stream("/b", "/b/s1/0/1.m4s"), ("s1", "0/1.m4s"));
+        assert_eq!(split_stream("", "/_events"), ("_events", ""));
+    }
+
+    #[tokio::test]
+    async fn started_upload_is_in_the_cache() {
+        for cache in ["list:copy", "map:m"] {
+            let events = Arc::new(Events::new(16));
+            let mut started = events.subscribe();
+            let tenant = Arc::new(
+                Tenant::for_test_with(&Tenant::test_setting(cache), 1, Some(events)).await,
+            );
+
+            // the body never arrives
+            let body = StreamBody::new(stream::pending::<Result<Frame<Bytes>, ServerError>>());
+            let req = Request::put("/s1/0/1.m4s").body(body.boxed()).unwrap();
+            let upload = tokio::spawn({
+                let tenant = Arc::clone(&tenant);
+                async move { tenant.shard("/s1/0/1.m4s").ingester.ingest(req).await }
+            });
+
+            assert_eq!(started.recv().await.unwrap().event, Kind::StreamStarted);
+            assert_eq!(started.recv().await.unwrap().event, Kind::SegmentStarted);
+            let cached = tenant
+                .shard("/s1/0/1.m4s")
+                .cache
+                .get("/s1/0/1.m4s", &Default::default())
+                .await;
+            assert!(cached.unwrap().is_some(), "{}", cache);
+            upload.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn map_upload_cached_whole() {
+        let tenant = Tenant::for_test("map:m").await;
+
+        let chunks = ["ab", "cd", "ef"].map(|chunk| Ok(Frame::data(Bytes::from(chunk))));
+        let body = StreamBody::new(stream::iter(chunks));
+        let req = Request::put("/s1/0/1.m4s").body(body.boxed()).unwrap();
+        let shard = tenant.shard("/s1/0/1.m4s");
+        assert_eq!(shard.ingester.ingest(req).await.unwrap(), 6);
+        let cached = shard.cache.get("/s1/0/1.m4s", &Default::default()).await;
+        let body = cached.unwrap().unwrap().collect().await.unwrap();
+        assert_eq!(body.to_bytes(), "abcdef");
+    }
+}
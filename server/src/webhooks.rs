@@ -0,0 +1,367 @@
+use crate::config;
+use crate::events::{Event, Kind};
+use crate::metrics::{self, Counter};
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use http_body_util::Full;
+use hyper::header::{self, HeaderValue};
+use hyper::{Method, Request, StatusCode};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use parking_lot::Mutex;
+use serde_json::json;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, OnceCell, Semaphore};
+use tracing::{error, warn};
+
+/// Header with the HMAC-SHA256 of the body, `sha256=<hex>`.
+pub const SIGNATURE: &str = "X-Signature-256";
+
+/// Answer of the `on_publish` endpoint for a stream, set once.
+type Decision = OnceCell<Result<(), String>>;
+
+/// Sends stream events to the configured endpoints and asks the `on_publish` endpoint
+/// before a stream starts.
+pub struct Webhooks {
+    config: config::Webhooks,
+    client: Client<HttpConnector, Full<Bytes>>,
+    pending: Arc<Semaphore>,
+    /// Decisions of `on_publish` by tenant and stream, shared by concurrent first uploads
+    /// and kept until the stream is idle.
+    publishing: Mutex<HashMap<(String, String), Arc<Decision>>>,
+    delivered: Counter,
+    failed: Counter,
+    dropped: Counter,
+}
+
+impl Webhooks {
+    pub fn new(config: config::Webhooks) -> Self {
+        let pending = Arc::new(Semaphore::new(config.max_pending));
+        let result = |result| {
+            metrics::counter(
+                "server_webhooks_total",
+                "Webhook notifications by result.",
+                &[("result", result)],
+            )
+        };
+        Webhooks {
+            config,
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            pending,
+            publishing: Mutex::new(HashMap::new()),
+            delivered: result("delivered"),
+            failed: result("failed"),
+            dropped: result("dropped"),
+        }
+    }
+
+    /// Forwards the configured events to the endpoints until the events are closed.
+    pub async fn run(self: Arc<Self>, mut events: broadcast::Receiver<Arc<Event>>) {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    if event.event == Kind::StreamIdle {
+                        // the next upload starts the stream again
+                        let stream = (event.tenant.clone(), event.stream.clone());
+                        self.publishing.lock().remove(&stream);
+                    }
+                    if self.config.events.contains(&event.event) {
+                        self.notify(&event);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("webhooks: {} events missed", missed);
+                    self.dropped.add(missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+
+    fn notify(self: &Arc<Self>, event: &Event) {
+        let body = match serde_json::to_vec(event) {
+            Ok(body) => Bytes::from(body),
+            Err(e) => {
+                error!("webhooks: serialize event: {}", e);
+                return;
+            }
+        };
+
+        for url in self.config.urls.iter() {
+            let permit = match Arc::clone(&self.pending).try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    warn!("webhooks: {}: too many pending notifications, dropped", url);
+                    self.dropped.inc();
+                    continue;
+                }
+            };
+
+            let this = Arc::clone(self);
+            let url = url.clone();
+            let body = body.clone();
+            tokio::spawn(async move {
+                this.deliver(&url, body).await;
+                drop(permit);
+            });
+        }
+    }
+
+    /// Posts the body, retrying with a doubling backoff on errors, 5xx and 429 replies.
+    async fn deliver(&self, url: &str, body: Bytes) {
+        let mut backoff = Duration::from_secs(self.config.backoff);
+        let mut attempt = 0;
+        loop {
+            let error = match self.post(url, body.clone()).await {
+                Ok(status) if status.is_success() => {
+                    self.delivered.inc();
+                    return;
+                }
+                Ok(status)
+                    if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS =>
+                {
+                    warn!("webhooks: {}: rejected with {}", url, status);
+                    self.failed.inc();
+                    return;
+                }
+                Ok(status) => format!("replied {}", status),
+                Err(e) => e,
+            };
+
+            if attempt >= self.config.max_retries {
+                warn!(
+                    "webhooks: {}: {}, giving up after {} retries",
+                    url, error, attempt
+                );
+                self.failed.inc();
+                return;
+            }
+
+            warn!("webhooks: {}: {}, retry in {:?}", url, error, backoff);
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+
+    /// Asks the `on_publish` endpoint whether the stream may start, allowed without one.
+    /// Concurrent first uploads of a stream wait for the same decision, a rejected stream
+    /// is asked for again on its next upload.
+    pub async fn allow_publish(
+        &self,
+        tenant: &str,
+        stream: &str,
+        key: &str,
+        remote: Option<IpAddr>,
+    ) -> Result<(), String> {
+        let url = match &self.config.on_publish {
+            Some(url) => url,
+            None => return Ok(()),
+        };
+
+        let id = (tenant.to_string(), stream.to_string());
+        let decision = Arc::clone(self.publishing.lock().entry(id.clone()).or_default());
+        let result = decision
+            .get_or_init(|| self.ask_publish(url, tenant, stream, key, remote))
+            .await
+            .clone();
+        if result.is_err() {
+            let mut publishing = self.publishing.lock();
+            if publishing
+                .get(&id)
+                .is_some_and(|current| Arc::ptr_eq(current, &decision))
+            {
+                publishing.remove(&id);
+            }
+        }
+        result
+    }
+
+    async fn ask_publish(
+        &self,
+        url: &str,
+        tenant: &str,
+        stream: &str,
+        key: &str,
+        remote: Option<IpAddr>,
+    ) -> Result<(), String> {
+        let body = json!({
+            "event": "publish",
+            "tenant": tenant,
+            "stream": stream,
+            "key": key,
+            "remote": remote,
+        });
+        match self.post(url, Bytes::from(body.to_string())).await {
+            Ok(status) if status.is_success() => Ok(()),
+            Ok(status) => Err(format!("on_publish replied {}", status)),
+            Err(e) => Err(format!("on_publish: {}", e)),
+        }
+    }
+
+    async fn post(&self, url: &str, body: Bytes) -> Result<StatusCode, String> {
+        let mut req = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(secret) = &self.config.secret {
+            let signature = format!("sha256={}", sign(secret, &body));
+            req = req.header(SIGNATURE, HeaderValue::from_str(&signature).unwrap());
+        }
+        let req = req.body(Full::new(body)).map_err(|e| e.to_string())?;
+
+        let timeout = Duration::from_secs(self.config.timeout);
+        match tokio::time::timeout(timeout, self.client.request(req)).await {
+            Ok(Ok(response)) => Ok(response.status()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!("no reply after {:?}", timeout)),
+        }
+    }
+}
+
+/// Returns the hex encoded HMAC-SHA256 of the body.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Events;
+    use http_body_util::BodyExt;
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::Response;
+    use hyper_util::rt::TokioIo;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    /// Local stand-in for an endpoint that replies with the given statuses in turn and
+    /// passes on the signature and body of every request.
+    async fn endpoint(statuses: Vec<u16>) -> (String, mpsc::UnboundedReceiver<(String, Bytes)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hooks", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let calls = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (sender, calls, statuses) = (sender.clone(), calls.clone(), statuses.clone());
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let sender = sender.clone();
+                    let call = calls.fetch_add(1, Ordering::SeqCst);
+                    let status = statuses[call.min(statuses.len() - 1)];
+                    async move {
+                        let signature = req
+                            .headers()
+                            .get(SIGNATURE)
+                            .map(|v| v.to_str().unwrap().to_string())
+                            .unwrap_or_default();
+                        let body = req.into_body().collect().await.unwrap().to_bytes();
+                        sender.send((signature, body)).unwrap();
+                        let response = Response::builder().status(status);
+                        Ok::<_, hyper::Error>(response.body(Full::new(Bytes::new())).unwrap())
+                    }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        (url, receiver)
+    }
+
+    fn config(urls: Vec<String>, on_publish: Option<String>) -> config::Webhooks {
+        config::Webhooks {
+            urls,
+            events: vec![Kind::StreamStarted, Kind::StreamIdle, Kind::IngestFailed],
+            on_publish,
+            secret: Some("secret".to_string()),
+            timeout: 5,
+            max_retries: 2,
+            backoff: 0,
+            max_pending: 16,
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_signed_notification() {
+        let (url, mut requests) = endpoint(vec![500, 200]).await;
+        let webhooks = Arc::new(Webhooks::new(config(vec![url], None)));
+        let events = Events::new(16);
+        tokio::spawn(Arc::clone(&webhooks).run(events.subscribe()));
+
+        events.stream_started("t", "s1");
+        events.ingest_started("t", "s1", "0/1.m4s", "/s1/0/1.m4s");
+        let (signature, body) = requests.recv().await.unwrap();
+        let event: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(event["event"], "stream_started");
+        assert_eq!(event["stream"], "s1");
+        assert_eq!(signature, format!("sha256={}", sign("secret", &body)));
+
+        // the segment_started event is not sent, the retry of stream_started is
+        let (_, retried) = requests.recv().await.unwrap();
+        assert_eq!(retried, body);
+    }
+
+    #[tokio::test]
+    async fn publish_rejected_by_endpoint() {
+        let (url, _requests) = endpoint(vec![204, 403]).await;
+        let webhooks = Webhooks::new(config(Vec::new(), Some(url)));
+
+        assert!(webhooks
+            .allow_publish("t", "s1", "/s1/0/1.m4s", None)
+            .await
+            .is_ok());
+        let rejected = webhooks.allow_publish("t", "s2", "/s2/0/1.m4s", None).await;
+        assert_eq!(
+            rejected,
+            Err("on_publish replied 403 Forbidden".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn publish_asked_once_per_start() {
+        let (url, mut requests) = endpoint(vec![204]).await;
+        let webhooks = Arc::new(Webhooks::new(config(Vec::new(), Some(url))));
+        let events = Events::new(16);
+        tokio::spawn(Arc::clone(&webhooks).run(events.subscribe()));
+
+        let first = |key: &'static str| webhooks.allow_publish("t", "s1", key, None);
+        let (a, b) = tokio::join!(first("/s1/0/1.m4s"), first("/s1/1/1.m4s"));
+        assert!(a.is_ok() && b.is_ok());
+        requests.recv().await.unwrap();
+        assert!(requests.try_recv().is_err());
+
+        // asked again once the stream went idle
+        events.stream_idle("t", "s1");
+        while !webhooks.publishing.lock().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert!(first("/s1/0/2.m4s").await.is_ok());
+        requests.recv().await.unwrap();
+    }
+
+    #[test]
+    fn sign_body() {
+        assert_eq!(
+            sign("key", b"The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+}
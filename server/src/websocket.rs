@@ -0,0 +1,406 @@
+use crate::cache::list_cache::viewers_cut;
+use crate::cache::LagPolicy;
+use crate::errors::ServerError;
+use crate::tenant::Tenant;
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::body::{Frame, Incoming};
+use hyper::header::{self, HeaderValue};
+use hyper::{Method, Request};
+use hyper_util::rt::TokioIo;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::{mpsc, watch};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+use tracing::debug;
+
+/// Last path segment of the WebSocket endpoint of a representation,
+/// `/<stream>/<representation>/_ws`.
+pub const PATH: &str = "_ws";
+
+/// Returns the `Sec-WebSocket-Accept` value answering the WebSocket handshake of the
+/// request, `None` if the request is no handshake.
+pub fn accept_key<B>(req: &Request<B>) -> Option<HeaderValue> {
+    let headers = req.headers();
+    let upgrade = headers
+        .get(header::UPGRADE)
+        .is_some_and(|upgrade| upgrade.as_bytes().eq_ignore_ascii_case(b"websocket"));
+    let version = headers
+        .get(header::SEC_WEBSOCKET_VERSION)
+        .is_some_and(|version| version == "13");
+    if req.method() != Method::GET || !upgrade || !version {
+        return None;
+    }
+    let key = headers.get(header::SEC_WEBSOCKET_KEY)?;
+    HeaderValue::from_str(&derive_accept_key(key.as_bytes())).ok()
+}
+
+/// Subscriber pushed the chunks of a representation of a stream. Each segment starts
+/// with a text message naming its key, followed by a binary message per chunk.
+///
+/// A text message from the client with the name of another representation switches to
+/// it once the segment being sent is complete: its init segment comes first, then the
+/// segment in progress.
+///
+/// A subscriber falling behind is closed as lagging. With the `skip` lag policy it jumps
+/// to the newest chunk of a segment it falls behind within instead.
+pub struct Subscriber {
+    tenant: Arc<Tenant>,
+    stream: String,
+    representation: String,
+    lag: LagPolicy,
+    /// Segments waiting to be sent before the subscriber is disconnected as lagging.
+    max_lag_segments: usize,
+}
+
+enum Event {
+    Chunk(Option<Result<Frame<Bytes>, ServerError>>),
+    Announced(Result<Arc<str>, RecvError>),
+    Message(Option<Result<Message, tungstenite::Error>>),
+    Stopping,
+}
+
+/// Connection of the transmitter a subscriber may take over. It stays counted against
+/// the connection limits, and is waited for on shutdown, until every clone is dropped.
+#[derive(Clone)]
+pub struct Connection {
+    served: mpsc::Sender<()>,
+    /// Set once the server shuts down.
+    stopping: watch::Receiver<bool>,
+}
+
+impl Connection {
+    /// Returns the connection and the receiver that completes once it is released.
+    pub fn new(stopping: watch::Receiver<bool>) -> (Self, mpsc::Receiver<()>) {
+        let (served, released) = mpsc::channel(1);
+        (Connection { served, stopping }, released)
+    }
+}
+
+impl Subscriber {
+    pub fn new(
+        tenant: Arc<Tenant>,
+        stream: &str,
+        representation: &str,
+        lag: LagPolicy,
+        max_lag_segments: usize,
+    ) -> Self {
+        Subscriber {
+            tenant,
+            stream: stream.to_string(),
+            representation: representation.to_string(),
+            lag,
+            max_lag_segments,
+        }
+    }
+
+    /// Takes over the connection once the handshake response is sent, the guard and the
+    /// connection are held until the subscriber leaves.
+    pub fn subscribe<G: Send + 'static>(
+        self,
+        req: Request<Incoming>,
+        guard: G,
+        connection: Connection,
+    ) {
+        tokio::spawn(async move {
+            let upgraded = match hyper::upgrade::on(req).await {
+                Ok(upgraded) => upgraded,
+                Err(e) => {
+                    debug!("websocket: upgrade: {}", e);
+                    return;
+                }
+            };
+            let socket =
+                WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None).await;
+            if let Err(e) = self.run(socket, connection.stopping).await {
+                debug!("websocket: {}", e);
+            }
+            drop((guard, connection.served));
+        });
+    }
+
+    async fn run<S>(
+        mut self,
+        socket: WebSocketStream<S>,
+        mut stopping: watch::Receiver<bool>,
+    ) -> Result<(), tungstenite::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (mut sink, mut messages) = socket.split();
+        // subscribed before the edge is taken, so no segment in between is missed
+        let mut announced = self.tenant.streams.subscribe();
+        let mut queue = self.edge(None);
+        let mut body: Option<BoxBody<Bytes, ServerError>> = None;
+        let mut last: Option<Arc<str>> = None;
+        let mut switch: Option<String> = None;
+
+        loop {
+            while body.is_none() {
+                if let Some(representation) = switch.take() {
+                    // keys announced so far are covered by the edge
+                    while !matches!(
+                        announced.try_recv(),
+                        Err(TryRecvError::Empty | TryRecvError::Closed)
+                    ) {}
+                    self.representation = representation;
+                    queue = self.edge(last.as_deref());
+                }
+                let key = match queue.pop_front() {
+                    Some(key) => key,
+                    None => break,
+                };
+                let shard = self.tenant.shard(&key);
+                if let Ok(Some(segment)) = shard.cache.get(&key, &self.lag).await {
+                    let start = serde_json::json!({
+                        "key": &*key,
+                        "representation": &self.representation,
+                    });
+                    sink.send(Message::text(start.to_string())).await?;
+                    body = Some(segment);
+                    last = Some(key);
+                }
+            }
+
+            let event = tokio::select! {
+                chunk = next_chunk(&mut body) => Event::Chunk(chunk),
+                key = announced.recv() => Event::Announced(key),
+                message = messages.next() => Event::Message(message),
+                Ok(_) = stopping.wait_for(|stop| *stop) => Event::Stopping,
+            };
+            match event {
+                Event::Chunk(Some(Ok(frame))) => {
+                    if let Ok(data) = frame.into_data() {
+                        self.tenant.metrics.delivered_bytes.add(data.len() as u64);
+                        sink.send(Message::Binary(data)).await?;
+                    }
+                }
+                Event::Chunk(None) => body = None,
+                // cut by the lag policy
+                Event::Chunk(Some(Err(ServerError::LagError(_)))) => {
+                    return close(&mut sink, CloseCode::Policy, "lagging").await;
+                }
+                // aborted upload, the subscriber goes on with the next segment
+                Event::Chunk(Some(Err(_))) => body = None,
+                Event::Announced(Ok(key)) => {
+                    // announced again or in between subscribing and taking the edge
+                    let queued = last.as_ref() == Some(&key) || queue.contains(&key);
+                    if self.owns(&key) && !queued {
+                        queue.push_back(key);
+                        if queue.len() > self.max_lag_segments {
+                            viewers_cut("lag_segments").inc();
+                            return close(&mut sink, CloseCode::Policy, "lagging").await;
+                        }
+                    }
+                }
+                // keys were missed, the subscriber can't keep up with the uploads
+                Event::Announced(Err(RecvError::Lagged(_))) => {
+                    viewers_cut("lag_segments").inc();
+                    return close(&mut sink, CloseCode::Policy, "lagging").await;
+                }
+                Event::Announced(Err(RecvError::Closed)) => return Ok(()),
+                Event::Message(Some(Ok(Message::Text(representation)))) => {
+                    let representation = representation.trim();
+                    if !representation.is_empty() && representation != self.representation {
+                        switch = Some(representation.to_string());
+                    }
+                }
+                Event::Message(Some(Ok(Message::Close(_)))) | Event::Message(None) => {
+                    return Ok(());
+                }
+                Event::Message(Some(Ok(_))) => {}
+                Event::Message(Some(Err(e))) => return Err(e),
+                Event::Stopping => {
+                    return close(&mut sink, CloseCode::Away, "server shutting down").await;
+                }
+            }
+        }
+    }
+
+    /// Returns the init segment and the segment in progress of the representation,
+    /// without the segment of the same name as `last`, which was sent already.
+    fn edge(&self, last: Option<&str>) -> VecDeque<Arc<str>> {
+        let edge = self.tenant.streams.edge(&self.stream, &self.representation);
+        let sent = last
+            .and_then(|last| last.rsplit_once('/'))
+            .map(|(_, name)| name);
+        let segment = edge
+            .segment
+            .filter(|segment| segment.rsplit_once('/').map(|(_, name)| name) != sent);
+        edge.init.into_iter().chain(segment).collect()
+    }
+
+    fn owns(&self, key: &str) -> bool {
+        let (stream, path) = self.tenant.split(key);
+        stream == self.stream
+            && path
+                .rsplit_once('/')
+                .is_some_and(|(representation, _)| representation == self.representation)
+    }
+}
+
+async fn next_chunk(
+    body: &mut Option<BoxBody<Bytes, ServerError>>,
+) -> Option<Result<Frame<Bytes>, ServerError>> {
+    match body {
+        Some(body) => body.frame().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn close<S>(
+    sink: &mut S,
+    code: CloseCode,
+    reason: &'static str,
+) -> Result<(), tungstenite::Error>
+where
+    S: SinkExt<Message, Error = tungstenite::Error> + Unpin,
+{
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    sink.send(Message::Close(Some(frame))).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Full;
+    use std::time::Duration;
+
+    async fn tenant(cache: &str) -> Arc<Tenant> {
+        Arc::new(Tenant::for_test(cache).await)
+    }
+
+    async fn upload(tenant: &Tenant, key: &str, data: &'static str) {
+        let body = Full::new(Bytes::from(data))
+            .map_err(|never| match never {})
+            .boxed();
+        let req = Request::put(key).body(body).unwrap();
+        tenant.shard(key).ingester.ingest(req).await.unwrap();
+    }
+
+    async fn next<S>(client: &mut WebSocketStream<S>) -> Message
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let next = tokio::time::timeout(Duration::from_secs(5), client.next());
+        next.await.unwrap().unwrap().unwrap()
+    }
+
+    /// Returns the key announced by the next text message and the data of the binary
+    /// message after it.
+    async fn segment<S>(client: &mut WebSocketStream<S>) -> (String, String)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let start: serde_json::Value = match next(client).await {
+            Message::Text(start) => serde_json::from_str(&start).unwrap(),
+            message => panic!("unexpected message {:?}", message),
+        };
+        let data = next(client).await.into_data();
+        (
+            start["key"].as_str().unwrap().to_string(),
+            String::from_utf8(data.to_vec()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn push_segments_and_switch_representation() {
+        let tenant = tenant("list:copy").await;
+        upload(&tenant, "/s1/0/init.m4s", "i0").await;
+        upload(&tenant, "/s1/0/1.m4s", "a").await;
+        upload(&tenant, "/s1/1/init.m4s", "i1").await;
+        upload(&tenant, "/s1/1/1.m4s", "b").await;
+
+        let (server, client) = tokio::io::duplex(64 * 1024);
+        let subscriber = Subscriber::new(Arc::clone(&tenant), "s1", "0", LagPolicy::default(), 2);
+        let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        tokio::spawn(subscriber.run(server, watch::channel(false).1));
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+
+        // the subscriber starts with the init segment and the segment in progress
+        assert_eq!(
+            segment(&mut client).await,
+            ("/s1/0/init.m4s".into(), "i0".into())
+        );
+        assert_eq!(
+            segment(&mut client).await,
+            ("/s1/0/1.m4s".into(), "a".into())
+        );
+        upload(&tenant, "/s1/0/2.m4s", "c").await;
+        upload(&tenant, "/s2/0/2.m4s", "other stream").await;
+        assert_eq!(
+            segment(&mut client).await,
+            ("/s1/0/2.m4s".into(), "c".into())
+        );
+
+        // segment 2 of the new representation was sent in the old one already
+        upload(&tenant, "/s1/1/2.m4s", "d").await;
+        client.send(Message::text("1")).await.unwrap();
+        assert_eq!(
+            segment(&mut client).await,
+            ("/s1/1/init.m4s".into(), "i1".into())
+        );
+        upload(&tenant, "/s1/0/3.m4s", "old representation").await;
+        upload(&tenant, "/s1/1/3.m4s", "e").await;
+        assert_eq!(
+            segment(&mut client).await,
+            ("/s1/1/3.m4s".into(), "e".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn push_segments_of_map_cache() {
+        let tenant = tenant("map:m").await;
+        upload(&tenant, "/s1/0/init.m4s", "i0").await;
+        upload(&tenant, "/s1/0/1.m4s", "a").await;
+
+        let (server, client) = tokio::io::duplex(64 * 1024);
+        let subscriber = Subscriber::new(Arc::clone(&tenant), "s1", "0", LagPolicy::default(), 2);
+        let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        tokio::spawn(subscriber.run(server, watch::channel(false).1));
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+
+        assert_eq!(
+            segment(&mut client).await,
+            ("/s1/0/init.m4s".into(), "i0".into())
+        );
+        assert_eq!(
+            segment(&mut client).await,
+            ("/s1/0/1.m4s".into(), "a".into())
+        );
+        upload(&tenant, "/s1/0/2.m4s", "b").await;
+        assert_eq!(
+            segment(&mut client).await,
+            ("/s1/0/2.m4s".into(), "b".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn close_on_shutdown() {
+        let tenant = tenant("list:copy").await;
+        let (server, client) = tokio::io::duplex(64 * 1024);
+        let subscriber = Subscriber::new(Arc::clone(&tenant), "s1", "0", LagPolicy::default(), 2);
+        let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let (shutdown, stopping) = watch::channel(false);
+        let served = tokio::spawn(subscriber.run(server, stopping));
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+
+        shutdown.send_replace(true);
+        match next(&mut client).await {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Away),
+            message => panic!("unexpected message {:?}", message),
+        }
+        served.await.unwrap().unwrap();
+    }
+}
@@ -3,9 +3,11 @@ mod transcoder;
 
 use crate::transcoder::Transcoder;
 use clap::Parser as ClapParser;
+use common::logging::LogArgs;
 use std::io::{self, Read, Write};
 use std::process::{exit, Command, Stdio};
 use std::thread;
+use tracing::{error, info};
 
 #[derive(ClapParser, Debug)]
 #[command(version)]
@@ -20,39 +22,47 @@ struct Cli {
     print: bool,
 
     output: String,
+
+    #[command(flatten)]
+    log: LogArgs,
 }
 
 fn main() {
     let cli = Cli::parse();
+    // stdout carries the output of ffmpeg
+    let log = common::logging::init_stderr("transcoder", &cli.log.apply(&Default::default()));
     let transcoder = match build_transcoder(cli.config.as_str()) {
         Ok(transcoder) => transcoder,
         Err(e) => {
-            eprintln!("{}", e);
+            error!("{}", e);
             exit(1);
         }
     };
 
     run_transcoder(transcoder, cli);
+    log.flush();
 }
 
 fn run_transcoder(transcoder: Transcoder, cli: Cli) {
     let mut cmd = match transcoder.build_ffmpeg_command(cli.input, cli.output) {
         Ok(cmd) => cmd,
         Err(e) => {
-            eprintln!("build ffmpeg command: {:?}", e);
+            error!("build ffmpeg command: {:?}", e);
             exit(1);
         }
     };
 
-    print_command(&cmd);
+    let command = format_command(&cmd);
     if cli.print {
+        println!("{}", command);
         exit(0);
     }
+    info!("{}", command);
 
     let mut child = match cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn() {
         Ok(child) => child,
         Err(e) => {
-            eprintln!("run command: {:?}", e);
+            error!("run command: {:?}", e);
             exit(1);
         }
     };
@@ -60,7 +70,7 @@ fn run_transcoder(transcoder: Transcoder, cli: Cli) {
     let stdout = match child.stdout.take() {
         Some(stdout) => stdout,
         None => {
-            eprintln!("Failed to get stdout");
+            error!("Failed to get stdout");
             exit(1);
         }
     };
@@ -68,7 +78,7 @@ fn run_transcoder(transcoder: Transcoder, cli: Cli) {
     let stderr = match child.stderr.take() {
         Some(stderr) => stderr,
         None => {
-            eprintln!("Failed to get stderr");
+            error!("Failed to get stderr");
             exit(1);
         }
     };
@@ -79,24 +89,24 @@ fn run_transcoder(transcoder: Transcoder, cli: Cli) {
     let status = match child.wait() {
         Ok(status) => status,
         Err(e) => {
-            eprintln!("command status: {:?}", e);
+            error!("command status: {:?}", e);
             exit(1);
         }
     };
 
     if let Err(e) = stdout_thread.join() {
-        eprintln!("Waiting for stdout thread: {:?}", e);
+        error!("Waiting for stdout thread: {:?}", e);
         exit(1);
     }
     if let Err(e) = stderr_thread.join() {
-        eprintln!("Waiting for stderr thread: {:?}", e);
+        error!("Waiting for stderr thread: {:?}", e);
         exit(1);
     }
 
     if status.success() {
-        println!("Success");
+        info!("Success");
     } else {
-        eprintln!("{}", status);
+        error!("{}", status);
     }
 }
 
@@ -114,7 +124,7 @@ where
                     writer.write_all(&buffer[..n]).expect("Failed to write");
                 }
                 Err(e) => {
-                    eprintln!("Failed to read: {}", e);
+                    error!("Failed to read: {}", e);
                     break;
                 }
             }
@@ -128,11 +138,11 @@ fn build_transcoder(path: &str) -> Result<Transcoder, Box<dyn std::error::Error>
     Ok(config)
 }
 
-fn print_command(cmd: &Command) {
+fn format_command(cmd: &Command) -> String {
     let args: Vec<String> = cmd
         .get_args()
         .map(|arg| arg.to_string_lossy().into_owned())
         .collect();
 
-    println!("ffmpeg {}", args.join(" "));
+    format!("ffmpeg {}", args.join(" "))
 }